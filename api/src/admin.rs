@@ -0,0 +1,63 @@
+use serde::Deserialize;
+
+use payment_core::CurrentProvider;
+
+use crate::ProviderHandler;
+
+/// Totals a processor reports from `/admin/payments-summary`.
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct PaymentSummaryResponse {
+    #[serde(rename = "totalRequests")]
+    pub total_requests: f64,
+    #[serde(rename = "totalAmount")]
+    pub total_amount: f64,
+    #[serde(rename = "feePerTransaction")]
+    pub fee_per_transaction: f64,
+}
+
+/// Fetch a processor's admin summary for payments requested between `from` and `to`.
+pub async fn fetch_summary(
+    handler: &ProviderHandler,
+    provider: CurrentProvider,
+    from: &str,
+    to: &str,
+) -> anyhow::Result<PaymentSummaryResponse> {
+    if let Some(mock) = &handler.mock {
+        return Ok(mock.get(provider).summary(from, to));
+    }
+    let endpoint = handler.registry.get(provider);
+    Ok(handler
+        .client
+        .get(&endpoint.admin_summary_url)
+        .header("X-Rinha-Token", &endpoint.token)
+        .query(&[("from", from), ("to", to)])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?)
+}
+
+/// Whether a processor knows about a payment, from `GET /payments/{id}`.
+pub async fn has_payment(
+    handler: &ProviderHandler,
+    provider: CurrentProvider,
+    correlation_id: &uuid::Uuid,
+) -> anyhow::Result<bool> {
+    if let Some(mock) = &handler.mock {
+        return Ok(mock.get(provider).has_payment(correlation_id));
+    }
+    let res = handler
+        .client
+        .get(format!(
+            "{}/{correlation_id}",
+            handler.registry.get(provider).payments_url
+        ))
+        .send()
+        .await?;
+    if res.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+    res.error_for_status()?;
+    Ok(true)
+}
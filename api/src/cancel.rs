@@ -0,0 +1,208 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    env,
+    sync::Mutex,
+};
+
+use shared_types::{CancelOutcome, DBWrite, void_key};
+use uuid::Uuid;
+
+#[derive(Default)]
+struct Inner {
+    /// Accepted but not processed yet.
+    unsettled: HashSet<Uuid>,
+    /// Unsettled payments that were cancelled.
+    cancelled: HashSet<Uuid>,
+    /// Records written for the most recently settled payments, oldest first in `order`, one
+    /// per provider that processed it. Emptied once the payment was voided.
+    settled: HashMap<Uuid, Vec<DBWrite>>,
+    order: VecDeque<Uuid>,
+}
+
+/// Tracks cancellations of accepted payments. Only the last `capacity` settled payments
+/// can be voided, since their records are kept in memory.
+pub struct Cancellations {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+impl Cancellations {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            capacity: env::var("CANCEL_WINDOW")
+                .unwrap_or("10000".to_string())
+                .parse()?,
+            inner: Mutex::new(Inner::default()),
+        })
+    }
+
+    /// A payment was queued.
+    pub fn accept(&self, id: Uuid) {
+        self.inner.lock().unwrap().unsettled.insert(id);
+    }
+
+    /// A payment was given up on without being processed.
+    pub fn forget(&self, id: &Uuid) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.unsettled.remove(id);
+        inner.cancelled.remove(id);
+    }
+
+    /// Cancel a payment, returning the compensating records to write when it was already
+    /// processed, one for each record written for it.
+    pub fn cancel(&self, id: Uuid) -> (CancelOutcome, Vec<DBWrite>) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(writes) = inner.settled.get_mut(&id) {
+            let voids = writes.drain(..).map(|write| void(&write)).collect();
+            return (CancelOutcome::Voided, voids);
+        }
+        if inner.unsettled.contains(&id) {
+            inner.cancelled.insert(id);
+            return (CancelOutcome::Dropped, Vec::new());
+        }
+        (CancelOutcome::Unknown, Vec::new())
+    }
+
+    /// Whether a payment about to be processed was cancelled. Cancelled payments are
+    /// forgotten.
+    pub fn is_cancelled(&self, id: &Uuid) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.cancelled.remove(id) {
+            inner.unsettled.remove(id);
+            return true;
+        }
+        false
+    }
+
+    /// Remember the records written for a processed payment, one per provider that processed
+    /// it, all at once so a cancellation voids every one. A payment cancelled while its
+    /// provider calls were in flight, or voided already, is voided right away, returning the
+    /// records to write.
+    pub fn settle(&self, id: Uuid, writes: &[DBWrite]) -> Vec<DBWrite> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.unsettled.remove(&id);
+        let cancelled = inner.cancelled.remove(&id);
+        if let Some(settled) = inner.settled.get_mut(&id) {
+            if settled.is_empty() {
+                return writes.iter().map(void).collect();
+            }
+            settled.extend_from_slice(writes);
+            return Vec::new();
+        }
+        let voids = if cancelled {
+            writes.iter().map(void).collect()
+        } else {
+            Vec::new()
+        };
+        if self.capacity == 0 {
+            return voids;
+        }
+
+        if inner.order.len() == self.capacity {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.settled.remove(&oldest);
+            }
+        }
+        inner.order.push_back(id);
+        let records = if cancelled {
+            Vec::new()
+        } else {
+            writes.to_vec()
+        };
+        inner.settled.insert(id, records);
+        voids
+    }
+
+    pub fn clear(&self) {
+        *self.inner.lock().unwrap() = Inner::default();
+    }
+}
+
+fn void(write: &DBWrite) -> DBWrite {
+    DBWrite {
+        key: void_key(&write.key),
+        value: write.value,
+        tree: write.tree.clone(),
+        ack: write.ack,
+        trace_id: write.trace_id.clone(),
+        traceparent: write.traceparent.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared_types::{Ack, SledTree, payment_key};
+
+    use super::*;
+
+    fn cancellations(capacity: usize) -> Cancellations {
+        Cancellations {
+            capacity,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    fn write(id: Uuid, tree: SledTree) -> DBWrite {
+        DBWrite {
+            key: payment_key("2025-07-15T12:00:00.000Z", &id),
+            value: 10.0,
+            tree,
+            ack: Ack::default(),
+            trace_id: None,
+            traceparent: None,
+        }
+    }
+
+    fn voided(voids: &[DBWrite]) -> Vec<(String, SledTree)> {
+        voids
+            .iter()
+            .map(|void| (void.key.clone(), void.tree.clone()))
+            .collect()
+    }
+
+    fn hedged(id: Uuid) -> Vec<DBWrite> {
+        vec![write(id, SledTree::DEFAULT), write(id, SledTree::FALLBACK)]
+    }
+
+    #[test]
+    fn cancelling_a_hedged_payment_voids_both_records() {
+        let cancels = cancellations(10);
+        let id = Uuid::from_u128(1);
+        cancels.accept(id);
+        assert!(cancels.settle(id, &hedged(id)).is_empty());
+
+        let (outcome, voids) = cancels.cancel(id);
+        assert_eq!(outcome, CancelOutcome::Voided);
+        let expected = hedged(id).iter().map(void).collect::<Vec<_>>();
+        assert_eq!(voided(&voids), voided(&expected));
+        // Voided once only.
+        assert!(cancels.cancel(id).1.is_empty());
+    }
+
+    #[test]
+    fn a_cancel_in_flight_voids_every_leg() {
+        let cancels = cancellations(10);
+        let id = Uuid::from_u128(1);
+        cancels.accept(id);
+        assert_eq!(cancels.cancel(id).0, CancelOutcome::Dropped);
+
+        assert_eq!(cancels.settle(id, &hedged(id)).len(), 2);
+        // A record settled later for the voided payment is voided as well.
+        assert_eq!(cancels.settle(id, &[write(id, SledTree::DEFAULT)]).len(), 1);
+        assert!(cancels.cancel(id).1.is_empty());
+    }
+
+    #[test]
+    fn a_payment_settled_again_is_evicted_once() {
+        let cancels = cancellations(2);
+        let (first, second, third) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        cancels.settle(first, &[write(first, SledTree::DEFAULT)]);
+        cancels.settle(first, &[write(first, SledTree::FALLBACK)]);
+        cancels.settle(second, &[write(second, SledTree::DEFAULT)]);
+        assert_eq!(cancels.inner.lock().unwrap().order.len(), 2);
+
+        cancels.settle(third, &[write(third, SledTree::DEFAULT)]);
+        assert_eq!(cancels.cancel(first).0, CancelOutcome::Unknown);
+        assert_eq!(cancels.cancel(second).1.len(), 1);
+    }
+}
@@ -0,0 +1,60 @@
+use std::{env, time::Duration};
+
+use tracing::info;
+
+/// Random faults injected in front of one kind of call.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Fault {
+    /// Probability that a call fails without being made.
+    pub fail_rate: f64,
+    /// Probability that a call is held back by `delay` first.
+    pub delay_rate: f64,
+    pub delay: Duration,
+}
+
+impl Fault {
+    fn from_env(prefix: &str) -> anyhow::Result<Self> {
+        let var = |name: &str, default: &str| {
+            env::var(format!("CHAOS_{prefix}_{name}")).unwrap_or(default.to_string())
+        };
+
+        Ok(Self {
+            fail_rate: var("FAIL_RATE", "0.1").parse()?,
+            delay_rate: var("DELAY_RATE", "0.1").parse()?,
+            delay: Duration::from_millis(var("DELAY_MS", "200").parse()?),
+        })
+    }
+
+    /// Maybe sleep, then return whether the call should fail.
+    pub async fn inject(&self) -> bool {
+        if self.delay_rate > 0.0 && fastrand::f64() < self.delay_rate {
+            tokio::time::sleep(self.delay).await;
+        }
+        self.fail_rate > 0.0 && fastrand::f64() < self.fail_rate
+    }
+}
+
+/// Failure injection for exercising retries, the circuit breakers and the DB stash locally.
+/// Everything is off unless `CHAOS_ENABLED=true`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChaosConfig {
+    pub provider: Fault,
+    pub db: Fault,
+}
+
+impl ChaosConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let enabled: bool = env::var("CHAOS_ENABLED")
+            .unwrap_or("false".to_string())
+            .parse()?;
+        if !enabled {
+            return Ok(Self::default());
+        }
+
+        info!("Chaos mode enabled, provider calls and DB writes will randomly fail");
+        Ok(Self {
+            provider: Fault::from_env("PROVIDER")?,
+            db: Fault::from_env("DB")?,
+        })
+    }
+}
@@ -0,0 +1,224 @@
+use std::{env, fmt, sync::Arc, time::Duration};
+
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use shared_types::{
+    AggregatePush, DBRead, DBWrite, DBWriteBatch, DbRequest, DbResponse, GlobalSummary,
+    PaymentRecord, UnixConnectionPool, codec,
+};
+
+/// rinha-db has too many writes in flight and asks for this long a pause before retrying.
+#[derive(Debug)]
+pub struct Busy(pub Duration);
+
+impl fmt::Display for Busy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rinha-db is busy, retry in {:?}", self.0)
+    }
+}
+
+impl std::error::Error for Busy {}
+
+/// Writes accounting records to rinha-db, over its binary unix socket by default or over HTTP
+/// with `DB_TRANSPORT=http`.
+#[derive(Clone)]
+pub enum DbClient {
+    Socket(Arc<UnixConnectionPool>),
+    Http {
+        client: Client,
+        url: String,
+        /// Sent as `X-Rinha-Token` when rinha-db requires one.
+        token: Option<String>,
+    },
+}
+
+impl DbClient {
+    pub fn from_env(client: Client) -> anyhow::Result<Self> {
+        match env::var("DB_TRANSPORT").as_deref().unwrap_or("unix") {
+            "unix" => {
+                let path = env::var("DB_SOCKET_PATH").unwrap_or("/tmp/rinha-db.sock".to_string());
+                let pool_size: usize = env::var("DB_POOL_SIZE")
+                    .unwrap_or("32".to_string())
+                    .parse()?;
+                // Lazy so the worker can start before rinha-db is listening.
+                Ok(DbClient::Socket(Arc::new(UnixConnectionPool::new_lazy(
+                    path, pool_size,
+                ))))
+            }
+            "http" => Ok(DbClient::Http {
+                client,
+                url: env::var("DB_URL").unwrap_or("http://rinha-db:8888".to_string()),
+                token: env::var("DB_TOKEN").ok(),
+            }),
+            other => anyhow::bail!("unknown DB_TRANSPORT {other:?}, expected unix or http"),
+        }
+    }
+
+    /// Write the records, draining `writes` on success. On failure the records that weren't
+    /// written are left in `writes` so the caller can retry them.
+    pub async fn write_batch(&self, writes: &mut Vec<DBWrite>) -> anyhow::Result<()> {
+        match self {
+            DbClient::Socket(pool) => {
+                let batch = DbRequest::WriteBatch(DBWriteBatch {
+                    writes: std::mem::take(writes),
+                });
+                let res = match request(pool, &batch).await {
+                    Ok(DbResponse::Ok) => return Ok(()),
+                    Ok(DbResponse::Error(e)) => {
+                        Err(anyhow::anyhow!("rinha-db rejected batch: {e}"))
+                    }
+                    Ok(DbResponse::Busy(ms)) => Err(Busy(Duration::from_millis(ms)).into()),
+                    Ok(other) => Err(anyhow::anyhow!("unexpected rinha-db response: {other:?}")),
+                    Err(e) => Err(e),
+                };
+                if let DbRequest::WriteBatch(batch) = batch {
+                    *writes = batch.writes;
+                }
+                res
+            }
+            DbClient::Http { client, url, token } => {
+                let res = authorized(client.post(format!("{url}/payments-batch")), token)
+                    .json(&writes)
+                    .send()
+                    .await?;
+                accepted(res).await?;
+                writes.clear();
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn write(&self, write: &DBWrite) -> anyhow::Result<()> {
+        match self {
+            DbClient::Socket(pool) => {
+                match request(pool, &DbRequest::Write(write.clone())).await? {
+                    DbResponse::Ok => Ok(()),
+                    DbResponse::Error(e) => anyhow::bail!("rinha-db rejected write: {e}"),
+                    DbResponse::Busy(ms) => Err(Busy(Duration::from_millis(ms)).into()),
+                    other => anyhow::bail!("unexpected rinha-db response: {other:?}"),
+                }
+            }
+            DbClient::Http { client, url, token } => {
+                let res = authorized(client.post(format!("{url}/payment")), token)
+                    .body(serde_json::to_string(write)?)
+                    .send()
+                    .await?;
+                accepted(res).await
+            }
+        }
+    }
+
+    /// Add per-second totals to rinha-db's aggregate buckets.
+    pub async fn aggregate(&self, push: AggregatePush) -> anyhow::Result<()> {
+        match self {
+            DbClient::Socket(pool) => match request(pool, &DbRequest::Aggregate(push)).await? {
+                DbResponse::Ok => Ok(()),
+                DbResponse::Error(e) => anyhow::bail!("rinha-db rejected aggregates: {e}"),
+                other => anyhow::bail!("unexpected rinha-db response: {other:?}"),
+            },
+            DbClient::Http { client, url, token } => {
+                authorized(client.post(format!("{url}/aggregate")), token)
+                    .json(&push)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Local totals per tree for payments requested between `from` and `to`.
+    pub async fn summary(&self, from: &str, to: &str) -> anyhow::Result<GlobalSummary> {
+        match self {
+            DbClient::Socket(pool) => {
+                let read = DBRead {
+                    from: from.to_string(),
+                    to: to.to_string(),
+                };
+                match request(pool, &DbRequest::Summary(read)).await? {
+                    DbResponse::Summary(summary) => Ok(summary),
+                    DbResponse::Error(e) => anyhow::bail!("rinha-db rejected summary: {e}"),
+                    other => anyhow::bail!("unexpected rinha-db response: {other:?}"),
+                }
+            }
+            DbClient::Http { client, url, token } => {
+                Ok(authorized(client.get(format!("{url}/summary")), token)
+                    .query(&[("from", from), ("to", to)])
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?)
+            }
+        }
+    }
+
+    /// Every record stored for payments requested between `from` and `to`.
+    pub async fn records(&self, from: &str, to: &str) -> anyhow::Result<Vec<PaymentRecord>> {
+        match self {
+            DbClient::Socket(pool) => {
+                let read = DBRead {
+                    from: from.to_string(),
+                    to: to.to_string(),
+                };
+                match request(pool, &DbRequest::Records(read)).await? {
+                    DbResponse::Records(records) => Ok(records),
+                    DbResponse::Error(e) => anyhow::bail!("rinha-db rejected export: {e}"),
+                    other => anyhow::bail!("unexpected rinha-db response: {other:?}"),
+                }
+            }
+            DbClient::Http { client, url, token } => {
+                let body = authorized(client.get(format!("{url}/export")), token)
+                    .query(&[("from", from), ("to", to)])
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .text()
+                    .await?;
+                body.lines()
+                    .map(|line| Ok(serde_json::from_str(line)?))
+                    .collect()
+            }
+        }
+    }
+}
+
+/// A 429 becomes [`Busy`] with the delay rinha-db suggested, any other error status an error.
+async fn accepted(res: Response) -> anyhow::Result<()> {
+    if res.status() != StatusCode::TOO_MANY_REQUESTS {
+        res.error_for_status()?;
+        return Ok(());
+    }
+    let retry_after = res
+        .json::<serde_json::Value>()
+        .await
+        .ok()
+        .and_then(|body| body["retryAfterMs"].as_u64())
+        .unwrap_or(100);
+    Err(Busy(Duration::from_millis(retry_after)).into())
+}
+
+fn authorized(request: RequestBuilder, token: &Option<String>) -> RequestBuilder {
+    match token {
+        Some(token) => request.header("X-Rinha-Token", token),
+        None => request,
+    }
+}
+
+/// Send one request and wait for its response. Connections that fail mid-exchange are dropped
+/// instead of going back to the pool.
+async fn request(pool: &UnixConnectionPool, request: &DbRequest) -> anyhow::Result<DbResponse> {
+    let mut conn = pool.acquire().await?;
+
+    let res = async {
+        codec::write_frame(&mut *conn, request).await?;
+        codec::read_frame::<_, DbResponse>(&mut *conn)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("rinha-db closed the connection"))
+    }
+    .await;
+
+    if res.is_err() {
+        drop(conn.take());
+    }
+    res
+}
@@ -0,0 +1,125 @@
+use std::{
+    env,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use chrono::{SecondsFormat, Utc};
+use tracing::{info, warn};
+
+use payment_core::CurrentProvider;
+
+use crate::{ProviderHandler, admin};
+
+#[derive(Clone, Copy, Debug)]
+pub struct FeeConfig {
+    /// How often the processors' `feePerTransaction` is read. `None` keeps the configured fee
+    /// hints and always prefers the default.
+    pub interval: Option<Duration>,
+    /// How much cheaper the standby has to be before payments move to it. Moving back takes
+    /// the same margin the other way, so fees hovering around each other don't cause flapping.
+    pub margin: f64,
+}
+
+impl FeeConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let interval: u64 = env::var("FEE_POLL_INTERVAL_MS")
+            .unwrap_or("0".to_string())
+            .parse()?;
+
+        Ok(Self {
+            interval: (interval > 0).then(|| Duration::from_millis(interval)),
+            margin: env::var("FEE_SWITCH_MARGIN")
+                .unwrap_or("0.02".to_string())
+                .parse()?,
+        })
+    }
+}
+
+/// Fees last announced by the processors and the provider payments start on.
+pub struct FeeWatch {
+    config: FeeConfig,
+    announced: Mutex<Option<(f64, f64)>>,
+    prefer_fallback: AtomicBool,
+}
+
+impl FeeWatch {
+    pub fn new(config: FeeConfig) -> Self {
+        Self {
+            config,
+            announced: Mutex::new(None),
+            prefer_fallback: AtomicBool::new(false),
+        }
+    }
+
+    /// The provider's announced fee, or `hint` until one was read.
+    pub fn fee(&self, provider: CurrentProvider, hint: f64) -> f64 {
+        match (*self.announced.lock().unwrap(), provider) {
+            (Some((default, _)), CurrentProvider::Default) => default,
+            (Some((_, fallback)), CurrentProvider::Fallback) => fallback,
+            (None, _) => hint,
+        }
+    }
+
+    pub fn preferred(&self) -> CurrentProvider {
+        if self.prefer_fallback.load(Ordering::Relaxed) {
+            CurrentProvider::Fallback
+        } else {
+            CurrentProvider::Default
+        }
+    }
+
+    /// Record freshly announced fees, returning the newly preferred provider on a switch.
+    fn update(&self, default: f64, fallback: f64) -> Option<CurrentProvider> {
+        *self.announced.lock().unwrap() = Some((default, fallback));
+
+        let prefer_fallback = match self.preferred() {
+            CurrentProvider::Default => fallback + self.config.margin < default,
+            CurrentProvider::Fallback => default + self.config.margin >= fallback,
+        };
+        let was = self
+            .prefer_fallback
+            .swap(prefer_fallback, Ordering::Relaxed);
+        (was != prefer_fallback).then(|| self.preferred())
+    }
+}
+
+/// Start polling the processors' fees if `FEE_POLL_INTERVAL_MS` is set.
+pub fn spawn(handler: Arc<ProviderHandler>) {
+    let Some(interval) = handler.fees.config.interval else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+
+            // The fee comes with every summary, a window with no payments in it is the cheapest
+            // one to ask for.
+            let now = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+            let (default, fallback) = tokio::join!(
+                admin::fetch_summary(&handler, CurrentProvider::Default, &now, &now),
+                admin::fetch_summary(&handler, CurrentProvider::Fallback, &now, &now),
+            );
+            let (default, fallback) = match (default, fallback) {
+                (Ok(default), Ok(fallback)) => (default, fallback),
+                (Err(e), _) | (_, Err(e)) => {
+                    warn!("Failed to read the processors' fees: {e}");
+                    continue;
+                }
+            };
+
+            let (default, fallback) = (default.fee_per_transaction, fallback.fee_per_transaction);
+            if let Some(preferred) = handler.fees.update(default, fallback) {
+                info!(
+                    "Switching to {preferred:?} as the preferred provider, fees are {default} (default) and {fallback} (fallback)"
+                );
+            }
+        }
+    });
+}
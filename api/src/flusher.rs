@@ -0,0 +1,443 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    env,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use shared_types::{
+    Ack, AggregateDelta, AggregatePush, DBWrite, PushToken, SledTree, aggregate_bucket, is_void_key,
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{Instant, MissedTickBehavior},
+};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    chaos::Fault,
+    db::{Busy, DbClient},
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct FlusherConfig {
+    pub max_batch: usize,
+    pub window: Duration,
+    /// Records allowed to wait for the flusher before `push` starts blocking workers.
+    pub backlog: usize,
+    /// Records kept in memory while rinha-db is unreachable. The oldest ones are dropped past
+    /// this.
+    pub stash_capacity: usize,
+    /// How often stashed records are retried.
+    pub retry_interval: Duration,
+    /// With `DB_WRITE_MODE=aggregate`, how often per-second totals are pushed instead of
+    /// writing one record per payment.
+    pub aggregate: Option<Duration>,
+    /// When rinha-db acknowledges the records written, from `DB_ACK`.
+    pub ack: Ack,
+}
+
+impl FlusherConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            max_batch: env::var("DB_BATCH_SIZE")
+                .unwrap_or("100".to_string())
+                .parse()?,
+            window: Duration::from_millis(
+                env::var("DB_BATCH_WINDOW_MS")
+                    .unwrap_or("5".to_string())
+                    .parse()?,
+            ),
+            backlog: env::var("DB_BATCH_BACKLOG")
+                .unwrap_or("10000".to_string())
+                .parse()?,
+            stash_capacity: env::var("DB_STASH_CAPACITY")
+                .unwrap_or("100000".to_string())
+                .parse()?,
+            retry_interval: Duration::from_millis(
+                env::var("DB_STASH_RETRY_MS")
+                    .unwrap_or("500".to_string())
+                    .parse()?,
+            ),
+            aggregate: match env::var("DB_WRITE_MODE").as_deref().unwrap_or("records") {
+                "records" => None,
+                "aggregate" => Some(Duration::from_millis(
+                    env::var("DB_AGGREGATE_MS")
+                        .unwrap_or("100".to_string())
+                        .parse()?,
+                )),
+                other => {
+                    anyhow::bail!("unknown DB_WRITE_MODE {other:?}, expected records or aggregate")
+                }
+            },
+            ack: match env::var("DB_ACK").as_deref().unwrap_or("received") {
+                "none" => Ack::None,
+                "received" => Ack::Received,
+                "flushed" => Ack::Flushed,
+                other => {
+                    anyhow::bail!("unknown DB_ACK {other:?}, expected none, received or flushed")
+                }
+            },
+        })
+    }
+}
+
+enum Message {
+    Write(DBWrite),
+    /// Write whatever is batched or stashed right away, replying with the number of records
+    /// that still couldn't be written.
+    Flush(oneshot::Sender<usize>),
+}
+
+/// Collects accounting records from the workers and writes them to rinha-db in batches of up
+/// to `max_batch` records or every `window`, whichever comes first. Batches that fail are
+/// stashed and retried in the background, so a rinha-db outage doesn't lose records for
+/// payments the providers already processed.
+#[derive(Clone)]
+pub struct DbFlusher {
+    tx: mpsc::Sender<Message>,
+    progress: Arc<Progress>,
+    ack: Ack,
+}
+
+impl DbFlusher {
+    pub fn spawn(db: DbClient, config: FlusherConfig, chaos: Fault) -> Self {
+        let (tx, rx) = mpsc::channel(config.backlog);
+        let progress = Arc::new(Progress::new());
+        let writer = Writer {
+            db,
+            chaos,
+            progress: Arc::clone(&progress),
+        };
+        match config.aggregate {
+            Some(interval) => tokio::spawn(run_aggregate(writer, interval, rx)),
+            None => tokio::spawn(run(writer, config, rx)),
+        };
+        Self {
+            tx,
+            progress,
+            ack: config.ack,
+        }
+    }
+
+    /// Records pushed but not written yet, and how long the flusher has gone without
+    /// writing any of them.
+    pub fn lag(&self) -> (usize, Duration) {
+        self.progress.lag()
+    }
+
+    /// Queue a record, waiting when the flusher has fallen `backlog` records behind.
+    pub async fn push(&self, mut write: DBWrite) -> anyhow::Result<()> {
+        write.ack = self.ack;
+        self.progress.pushed();
+        self.tx
+            .send(Message::Write(write))
+            .await
+            .map_err(|_| anyhow::anyhow!("db flusher stopped"))
+    }
+
+    /// Wait until every record pushed so far has been written, failing if some are still
+    /// stashed.
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        let (done_tx, done_rx) = oneshot::channel();
+        self.tx
+            .send(Message::Flush(done_tx))
+            .await
+            .map_err(|_| anyhow::anyhow!("db flusher stopped"))?;
+        match done_rx.await? {
+            0 => Ok(()),
+            stashed => anyhow::bail!("{stashed} records still waiting for rinha-db"),
+        }
+    }
+}
+
+/// Records waiting for rinha-db, shared with the status reports.
+struct Progress {
+    unwritten: AtomicUsize,
+    /// Last write, or when the flusher last caught up if that's more recent.
+    last_progress: Mutex<Instant>,
+}
+
+impl Progress {
+    fn new() -> Self {
+        Self {
+            unwritten: AtomicUsize::new(0),
+            last_progress: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn pushed(&self) {
+        if self.unwritten.fetch_add(1, Ordering::Relaxed) == 0 {
+            *self.last_progress.lock().unwrap() = Instant::now();
+        }
+    }
+
+    /// Records written, or dropped for good.
+    fn settled(&self, records: usize) {
+        if records > 0 {
+            self.unwritten.fetch_sub(records, Ordering::Relaxed);
+            *self.last_progress.lock().unwrap() = Instant::now();
+        }
+    }
+
+    fn lag(&self) -> (usize, Duration) {
+        match self.unwritten.load(Ordering::Relaxed) {
+            0 => (0, Duration::ZERO),
+            unwritten => (unwritten, self.last_progress.lock().unwrap().elapsed()),
+        }
+    }
+}
+
+/// The DB client behind the chaos mode's fault injection.
+struct Writer {
+    db: DbClient,
+    chaos: Fault,
+    progress: Arc<Progress>,
+}
+
+impl Writer {
+    async fn write_batch(&self, writes: &mut Vec<DBWrite>) -> anyhow::Result<()> {
+        if self.chaos.inject().await {
+            anyhow::bail!("injected failure");
+        }
+        let len = writes.len();
+        let res = self.db.write_batch(writes).await;
+        self.progress.settled(len - writes.len());
+        // Hold off as long as rinha-db asked before anything else is sent its way.
+        if let Some(Busy(retry_after)) = res.as_ref().err().and_then(|e| e.downcast_ref()) {
+            tokio::time::sleep(*retry_after).await;
+        }
+        res
+    }
+
+    /// Push deltas that sum up `records` records.
+    async fn aggregate(&self, push: AggregatePush, records: usize) -> anyhow::Result<()> {
+        if self.chaos.inject().await {
+            anyhow::bail!("injected failure");
+        }
+        self.db.aggregate(push).await?;
+        self.progress.settled(records);
+        Ok(())
+    }
+}
+
+async fn run(db: Writer, config: FlusherConfig, mut rx: mpsc::Receiver<Message>) {
+    let mut batch = Vec::with_capacity(config.max_batch);
+    let mut stash = Stash::new(config.stash_capacity);
+    let mut retry = tokio::time::interval(config.retry_interval);
+    retry.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        let first = tokio::select! {
+            message = rx.recv() => match message {
+                Some(message) => message,
+                None => return,
+            },
+            _ = retry.tick(), if !stash.is_empty() => {
+                stash.retry(&db, config.max_batch).await;
+                continue;
+            }
+        };
+
+        let mut flushed = None;
+        match first {
+            Message::Write(write) => batch.push(write),
+            Message::Flush(done) => flushed = Some(done),
+        }
+        let deadline = Instant::now() + config.window;
+
+        while flushed.is_none() && batch.len() < config.max_batch {
+            match tokio::time::timeout_at(deadline, rx.recv()).await {
+                Ok(Some(Message::Write(write))) => batch.push(write),
+                Ok(Some(Message::Flush(done))) => flushed = Some(done),
+                _ => break,
+            }
+        }
+
+        if !batch.is_empty() {
+            let len = batch.len();
+            if let Err(e) = db.write_batch(&mut batch).await {
+                warn!("Failed to flush {len} records to rinha-db, stashing them: {e}");
+                let dropped = stash.extend(batch.drain(..));
+                db.progress.settled(dropped);
+            }
+        }
+        if let Some(done) = flushed {
+            if !stash.is_empty() {
+                stash.retry(&db, config.max_batch).await;
+            }
+            let _ = done.send(stash.len());
+        }
+    }
+}
+
+/// Aggregate mode: sum records per tree and second, and push the deltas every `interval`. A
+/// push that fails is sent again as it was, token included, before anything newer, since
+/// rinha-db may have merged it and only the reply was lost.
+async fn run_aggregate(db: Writer, interval: Duration, mut rx: mpsc::Receiver<Message>) {
+    let mut pending = Aggregates::new(Uuid::new_v4());
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            message = rx.recv() => match message {
+                Some(Message::Write(write)) => pending.add(&write),
+                Some(Message::Flush(done)) => {
+                    pending.push(&db).await;
+                    let _ = done.send(pending.records());
+                }
+                None => return,
+            },
+            _ = ticker.tick(), if !pending.is_empty() => pending.push(&db).await,
+        }
+    }
+}
+
+/// Totals not yet pushed to rinha-db, keyed by tree and bucket.
+struct Aggregates {
+    buckets: HashMap<(SledTree, String), (i64, f64)>,
+    /// Records summed into `buckets`.
+    records: usize,
+    /// The last push, until rinha-db confirms it, with the records it sums up.
+    unconfirmed: Option<(AggregatePush, usize)>,
+    worker: Uuid,
+    /// Sequence of the last push.
+    sequence: u64,
+}
+
+impl Aggregates {
+    fn new(worker: Uuid) -> Self {
+        Self {
+            buckets: HashMap::new(),
+            records: 0,
+            unconfirmed: None,
+            worker,
+            sequence: 0,
+        }
+    }
+
+    fn add(&mut self, write: &DBWrite) {
+        let bucket = aggregate_bucket(&write.key).to_string();
+        let (requests, amount) = self
+            .buckets
+            .entry((write.tree.clone(), bucket))
+            .or_default();
+        self.records += 1;
+        if is_void_key(write.key.as_bytes()) {
+            *requests -= 1;
+            *amount -= write.value;
+        } else {
+            *requests += 1;
+            *amount += write.value;
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buckets.is_empty() && self.unconfirmed.is_none()
+    }
+
+    /// Records not confirmed by rinha-db yet.
+    fn records(&self) -> usize {
+        self.records + self.unconfirmed.as_ref().map_or(0, |(_, records)| *records)
+    }
+
+    /// Send the unconfirmed push again, then the totals summed since, until one fails.
+    async fn push(&mut self, db: &Writer) {
+        loop {
+            if self.unconfirmed.is_none() {
+                if self.buckets.is_empty() {
+                    return;
+                }
+                self.sequence += 1;
+                let deltas = self
+                    .buckets
+                    .drain()
+                    .map(|((tree, bucket), (requests, amount))| AggregateDelta {
+                        tree,
+                        bucket,
+                        requests,
+                        amount,
+                    })
+                    .collect();
+                let push = AggregatePush {
+                    token: Some(PushToken {
+                        worker: self.worker,
+                        sequence: self.sequence,
+                    }),
+                    deltas,
+                };
+                self.unconfirmed = Some((push, std::mem::take(&mut self.records)));
+            }
+
+            let (push, records) = self.unconfirmed.as_ref().unwrap();
+            match db.aggregate(push.clone(), *records).await {
+                Ok(()) => self.unconfirmed = None,
+                Err(e) => {
+                    warn!(
+                        "Failed to push {} aggregates to rinha-db, sending them again later: {e}",
+                        push.deltas.len()
+                    );
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Records that failed to reach rinha-db, oldest first.
+struct Stash {
+    capacity: usize,
+    records: VecDeque<DBWrite>,
+}
+
+impl Stash {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            records: VecDeque::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Stash records, returning how many of the oldest were dropped to make room.
+    fn extend(&mut self, writes: impl IntoIterator<Item = DBWrite>) -> usize {
+        self.records.extend(writes);
+        let overflow = self.records.len().saturating_sub(self.capacity);
+        if overflow > 0 {
+            warn!("Stash full, dropping {overflow} records");
+            self.records.drain(..overflow);
+        }
+        overflow
+    }
+
+    /// Write stashed records in batches until rinha-db fails again.
+    async fn retry(&mut self, db: &Writer, max_batch: usize) {
+        let mut batch = Vec::with_capacity(max_batch);
+        while !self.records.is_empty() {
+            let n = self.records.len().min(max_batch);
+            batch.extend(self.records.drain(..n));
+            if let Err(e) = db.write_batch(&mut batch).await {
+                warn!(
+                    "rinha-db still unreachable, {} records stashed: {e}",
+                    self.len() + batch.len()
+                );
+                for write in batch.drain(..).rev() {
+                    self.records.push_front(write);
+                }
+                return;
+            }
+        }
+    }
+}
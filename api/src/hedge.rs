@@ -0,0 +1,77 @@
+use std::{collections::VecDeque, env, sync::Mutex, time::Duration};
+
+#[derive(Clone, Copy, Debug)]
+pub struct HedgeConfig {
+    /// p99 latency of the primary provider above which payments are hedged. `None` disables
+    /// hedging. A lower budget trades more fallback fees for lower latency.
+    pub budget: Option<Duration>,
+    /// How long the primary gets before the secondary is also called. A longer delay lets
+    /// the cheaper primary win more often.
+    pub delay: Duration,
+    /// Number of recent calls the p99 is computed over.
+    pub window: usize,
+}
+
+impl HedgeConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let budget: u64 = env::var("HEDGE_P99_BUDGET_MS")
+            .unwrap_or("0".to_string())
+            .parse()?;
+
+        Ok(Self {
+            budget: (budget > 0).then(|| Duration::from_millis(budget)),
+            delay: Duration::from_millis(
+                env::var("PROVIDER_HEDGE_DELAY_MS")
+                    .unwrap_or("20".to_string())
+                    .parse()?,
+            ),
+            window: env::var("LATENCY_WINDOW")
+                .unwrap_or("256".to_string())
+                .parse()?,
+        })
+    }
+}
+
+/// Sliding window of the latest call latencies to a single provider.
+pub struct LatencyTracker {
+    window: usize,
+    samples: Mutex<VecDeque<Duration>>,
+}
+
+impl LatencyTracker {
+    /// Samples needed before a percentile is reported.
+    const MIN_SAMPLES: usize = 20;
+
+    pub fn new(window: usize) -> Self {
+        Self {
+            window: window.max(1),
+            samples: Mutex::new(VecDeque::with_capacity(window)),
+        }
+    }
+
+    pub fn record(&self, latency: Duration) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == self.window {
+            samples.pop_front();
+        }
+        samples.push_back(latency);
+    }
+
+    pub fn last(&self) -> Option<Duration> {
+        self.samples.lock().unwrap().back().copied()
+    }
+
+    /// p99 over the window, or `None` until enough calls were observed.
+    pub fn p99(&self) -> Option<Duration> {
+        let mut sorted: Vec<Duration> = {
+            let samples = self.samples.lock().unwrap();
+            if samples.len() < Self::MIN_SAMPLES {
+                return None;
+            }
+            samples.iter().copied().collect()
+        };
+        sorted.sort_unstable();
+        let idx = (sorted.len() * 99).div_ceil(100) - 1;
+        Some(sorted[idx])
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    collections::{HashMap, VecDeque, hash_map::Entry},
+    hash::{BuildHasher, RandomState},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use uuid::Uuid;
+
+const SHARDS: usize = 16;
+
+/// Correlation ids this worker has already accepted, so a payment retried by the gateway
+/// isn't charged twice. Sharded to keep socket readers from contending on one lock. Ids are
+/// forgotten `ttl` after they were claimed, so the set only holds the recent ones.
+#[derive(Clone)]
+pub struct IdempotencyGuard {
+    shards: Arc<[Mutex<Claims>; SHARDS]>,
+    hasher: RandomState,
+    /// `None` keeps every id until a purge.
+    ttl: Option<Duration>,
+}
+
+#[derive(Default)]
+struct Claims {
+    /// When each id was claimed.
+    ids: HashMap<Uuid, Instant>,
+    /// Ids oldest claim first, expired from the front. Ids released or claimed again since
+    /// are left for their later claim to expire.
+    order: VecDeque<(Instant, Uuid)>,
+}
+
+impl IdempotencyGuard {
+    pub fn new(ttl: Option<Duration>) -> Self {
+        Self {
+            shards: Arc::new(Default::default()),
+            hasher: RandomState::new(),
+            ttl,
+        }
+    }
+
+    fn shard(&self, id: &Uuid) -> &Mutex<Claims> {
+        &self.shards[self.hasher.hash_one(id) as usize % SHARDS]
+    }
+
+    /// Claim an id, returning false if it was already claimed within the ttl.
+    pub fn try_claim(&self, id: Uuid) -> bool {
+        let mut claims = self.shard(&id).lock().unwrap();
+        claims.claim(id, Instant::now(), self.ttl)
+    }
+
+    /// Release an id whose payment was never settled, so a later retry can go through.
+    pub fn release(&self, id: &Uuid) {
+        self.shard(id).lock().unwrap().ids.remove(id);
+    }
+
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            *shard.lock().unwrap() = Claims::default();
+        }
+    }
+}
+
+impl Claims {
+    fn claim(&mut self, id: Uuid, now: Instant, ttl: Option<Duration>) -> bool {
+        if let Some(ttl) = ttl {
+            self.expire(now, ttl);
+        }
+        match self.ids.entry(id) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(now);
+                if ttl.is_some() {
+                    self.order.push_back((now, id));
+                }
+                true
+            }
+        }
+    }
+
+    fn expire(&mut self, now: Instant, ttl: Duration) {
+        while let Some(&(claimed, id)) = self.order.front() {
+            if now.duration_since(claimed) < ttl {
+                break;
+            }
+            self.order.pop_front();
+            if self.ids.get(&id) == Some(&claimed) {
+                self.ids.remove(&id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Option<Duration> = Some(Duration::from_secs(10));
+
+    fn at(secs: u64, start: Instant) -> Instant {
+        start + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn ids_are_claimed_once() {
+        let guard = IdempotencyGuard::new(TTL);
+        let id = Uuid::from_u128(1);
+        assert!(guard.try_claim(id));
+        assert!(!guard.try_claim(id));
+        guard.release(&id);
+        assert!(guard.try_claim(id));
+    }
+
+    #[test]
+    fn ids_are_forgotten_after_the_ttl() {
+        let (start, mut claims) = (Instant::now(), Claims::default());
+        let ids: Vec<_> = (0..100).map(Uuid::from_u128).collect();
+        assert!(ids.iter().all(|id| claims.claim(*id, start, TTL)));
+        assert!(!claims.claim(ids[0], at(9, start), TTL));
+
+        assert!(claims.claim(ids[0], at(10, start), TTL));
+        assert_eq!((claims.ids.len(), claims.order.len()), (1, 1));
+    }
+
+    #[test]
+    fn an_id_claimed_again_expires_with_its_later_claim() {
+        let (start, mut claims) = (Instant::now(), Claims::default());
+        let id = Uuid::from_u128(1);
+        assert!(claims.claim(id, start, TTL));
+        claims.ids.remove(&id);
+        assert!(claims.claim(id, at(5, start), TTL));
+        // The first claim expired, the second one still holds.
+        assert!(!claims.claim(id, at(12, start), TTL));
+        assert!(claims.claim(id, at(15, start), TTL));
+    }
+
+    #[test]
+    fn without_a_ttl_ids_are_kept() {
+        let (start, mut claims) = (Instant::now(), Claims::default());
+        let id = Uuid::from_u128(1);
+        assert!(claims.claim(id, start, None));
+        assert!(!claims.claim(id, at(3600, start), None));
+        assert!(claims.order.is_empty());
+    }
+}
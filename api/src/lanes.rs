@@ -0,0 +1,158 @@
+use std::env;
+
+use async_channel::{Receiver, Sender, TrySendError, bounded};
+use shared_types::PaymentDTO;
+
+use crate::QueuedPayment;
+
+#[derive(Clone, Copy, Debug)]
+pub struct PriorityConfig {
+    /// Payments of at least this amount go through the priority lane. `None` leaves it to the
+    /// gateway's `X-Payment-Priority` header.
+    pub amount: Option<f64>,
+    /// Priority payments a worker takes before it has to serve one from the normal lane.
+    pub weight: usize,
+}
+
+impl PriorityConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            amount: env::var("PRIORITY_AMOUNT")
+                .ok()
+                .map(|v| v.parse())
+                .transpose()?,
+            weight: env::var("PRIORITY_WEIGHT")
+                .unwrap_or("4".to_string())
+                .parse::<usize>()?
+                .max(1),
+        })
+    }
+
+    pub fn is_priority(&self, payment: &PaymentDTO) -> bool {
+        payment.priority || self.amount.is_some_and(|amount| payment.amount >= amount)
+    }
+}
+
+/// Sending half of a shard's two lanes.
+#[derive(Clone)]
+pub struct LaneSender {
+    high: Sender<QueuedPayment>,
+    normal: Sender<QueuedPayment>,
+}
+
+impl LaneSender {
+    fn lane(&self, payment: &QueuedPayment) -> &Sender<QueuedPayment> {
+        if payment.priority {
+            &self.high
+        } else {
+            &self.normal
+        }
+    }
+
+    /// The payment is dropped when it can't be queued.
+    pub fn try_send(&self, payment: QueuedPayment) -> Result<(), TrySendError<()>> {
+        self.lane(&payment).try_send(payment).map_err(|e| match e {
+            TrySendError::Full(_) => TrySendError::Full(()),
+            TrySendError::Closed(_) => TrySendError::Closed(()),
+        })
+    }
+
+    pub async fn send(&self, payment: QueuedPayment) -> Result<(), QueuedPayment> {
+        self.lane(&payment).send(payment).await.map_err(|e| e.0)
+    }
+
+    pub fn close(&self) {
+        self.high.close();
+        self.normal.close();
+    }
+}
+
+/// A priority and a normal channel drained by the same workers. Under a backlog priority
+/// payments jump ahead, while `weight` keeps the normal lane from starving.
+pub struct Lanes {
+    pub tx: LaneSender,
+    high: Receiver<QueuedPayment>,
+    normal: Receiver<QueuedPayment>,
+    weight: usize,
+}
+
+impl Lanes {
+    /// Each lane gets the full `capacity`, so a flood of one kind can't crowd out the other.
+    pub fn new(capacity: usize, weight: usize) -> Self {
+        let (high_tx, high) = bounded(capacity);
+        let (normal_tx, normal) = bounded(capacity);
+        Self {
+            tx: LaneSender {
+                high: high_tx,
+                normal: normal_tx,
+            },
+            high,
+            normal,
+            weight,
+        }
+    }
+
+    pub fn receiver(&self) -> LaneReceiver {
+        LaneReceiver {
+            high: self.high.clone(),
+            normal: self.normal.clone(),
+            weight: self.weight,
+            streak: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.high.len() + self.normal.len()
+    }
+
+    /// Drop everything waiting in both lanes, returning how many payments were dropped.
+    pub fn clear(&self) -> u64 {
+        let mut dropped = 0;
+        while self.high.try_recv().is_ok() || self.normal.try_recv().is_ok() {
+            dropped += 1;
+        }
+        dropped
+    }
+}
+
+/// One worker's view of the lanes, tracking how many priority payments it took in a row.
+pub struct LaneReceiver {
+    high: Receiver<QueuedPayment>,
+    normal: Receiver<QueuedPayment>,
+    weight: usize,
+    streak: usize,
+}
+
+impl LaneReceiver {
+    /// Next payment to process, or `None` once both lanes are closed and empty.
+    pub async fn recv(&mut self) -> Option<QueuedPayment> {
+        if self.streak < self.weight {
+            if let Ok(payment) = self.high.try_recv() {
+                self.streak += 1;
+                return Some(payment);
+            }
+        }
+        if let Ok(payment) = self.normal.try_recv() {
+            self.streak = 0;
+            return Some(payment);
+        }
+        if let Ok(payment) = self.high.try_recv() {
+            self.streak += 1;
+            return Some(payment);
+        }
+
+        // Both lanes are empty, wait for whichever gets a payment first.
+        tokio::select! {
+            biased;
+            Ok(payment) = self.high.recv() => {
+                self.streak += 1;
+                Some(payment)
+            }
+            Ok(payment) = self.normal.recv() => {
+                self.streak = 0;
+                Some(payment)
+            }
+            else => None,
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use std::{
+    env,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct LimiterConfig {
+    /// Calls per second allowed while the provider isn't throttling.
+    pub max_rate: f64,
+    /// Floor the rate never drops below, however many 429s come back.
+    pub min_rate: f64,
+    /// Calls that may go out back to back after an idle period.
+    pub burst: f64,
+}
+
+impl LimiterConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            max_rate: env::var("PROVIDER_RATE_LIMIT")
+                .unwrap_or("1000".to_string())
+                .parse()?,
+            min_rate: env::var("PROVIDER_RATE_LIMIT_MIN")
+                .unwrap_or("10".to_string())
+                .parse()?,
+            burst: env::var("PROVIDER_RATE_BURST")
+                .unwrap_or("50".to_string())
+                .parse()?,
+        })
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    rate: f64,
+    refilled_at: Instant,
+}
+
+/// Token bucket in front of a single provider. The rate is halved on every 429 and creeps
+/// back up on successes, so bursts get smoothed before the provider starts throttling.
+pub struct RateLimiter {
+    config: LimiterConfig,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(config: LimiterConfig) -> Self {
+        Self {
+            config,
+            bucket: Mutex::new(Bucket {
+                tokens: config.burst,
+                rate: config.max_rate,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Wait for a token.
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                self.refill(&mut bucket);
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / bucket.rate)
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// The provider answered 429: halve the rate and stop the current burst.
+    pub fn on_throttled(&self) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.rate = (bucket.rate / 2.0).max(self.config.min_rate);
+        bucket.tokens = bucket.tokens.min(0.0);
+    }
+
+    /// Recover towards `max_rate` by a small step per accepted call.
+    pub fn on_success(&self) {
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.rate < self.config.max_rate {
+            bucket.rate = (bucket.rate + self.config.min_rate / 10.0).min(self.config.max_rate);
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * bucket.rate).min(self.config.burst.max(1.0));
+        bucket.refilled_at = now;
+    }
+}
@@ -1,28 +1,101 @@
-use async_channel::Receiver;
-use async_channel::Sender;
-use async_channel::unbounded;
+mod admin;
+mod cancel;
+mod chaos;
+mod db;
+mod fees;
+mod flusher;
+mod hedge;
+mod idempotency;
+mod lanes;
+mod limiter;
+mod metrics;
+mod mock;
+mod reconcile;
+mod replay;
+mod retry;
+mod spill;
+
+use arc_swap::ArcSwap;
+use async_channel::TrySendError;
 use axum::http::HeaderMap;
+use bytes::Bytes;
+use cancel::Cancellations;
+use chaos::ChaosConfig;
 use chrono::Utc;
+use db::DbClient;
+use fees::FeeConfig;
+use fees::FeeWatch;
+use flusher::DbFlusher;
+use flusher::FlusherConfig;
+use hedge::HedgeConfig;
+use hedge::LatencyTracker;
+use idempotency::IdempotencyGuard;
+use lanes::Lanes;
+use lanes::PriorityConfig;
+use limiter::LimiterConfig;
+use limiter::RateLimiter;
+use metrics::Metrics;
+use metrics::ProviderCounters;
+use mock::MockProviders;
+use payment_core::CurrentProvider;
+use payment_core::PaymentServiceDTO;
+use payment_core::breaker::BreakerConfig;
+use payment_core::breaker::CircuitBreaker;
+use payment_core::breaker::CircuitState;
+use payment_core::client::ClientConfig;
+use payment_core::error::ProviderError;
+use payment_core::health;
+use payment_core::health::ProviderHealth;
+use payment_core::health::ProviderState;
+use payment_core::registry::ProviderRegistry;
+use payment_core::retry::RetryPolicy;
+use payment_core::strategy::ProviderView;
+use payment_core::strategy::RoutingContext;
+use payment_core::strategy::RoutingStrategy;
+use payment_core::strategy::StrategyKind;
+use payment_core::timeout::TimeoutConfig;
+use reconcile::ReconcileConfig;
+use reconcile::UncertainJournal;
 use reqwest::Client;
-use serde::Deserialize;
-use serde::Serialize;
+use reqwest::StatusCode;
+use retry::RetryQueue;
+use shared_types::Ack;
+use shared_types::ApiFrame;
+use shared_types::ApiReply;
+use shared_types::CancelOutcome;
 use shared_types::DBWrite;
+use shared_types::NackReason;
 use shared_types::PaymentDTO;
-use shared_types::UnixConnectionPool;
-use std::collections::HashMap;
+use shared_types::ProviderStatus;
+use shared_types::WorkerStatus;
+use shared_types::logging;
+use shared_types::payment_key;
+use spill::SpillQueue;
 use std::env;
 use std::path::Path;
-use std::sync::{Arc, LazyLock};
-use std::time::Duration;
-use tokio;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use tokio::io::AsyncBufReadExt;
 use tokio::io::AsyncWriteExt;
 use tokio::io::BufReader;
 use tokio::net::UnixListener;
+use tokio::net::UnixStream;
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::task::JoinSet;
+use tracing::{Instrument, Span, debug_span, error, info, warn};
 use uuid::Uuid;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    shared_types::logging::init("api")?;
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("--replay") {
+        let range = args.get(2).map(String::as_str).unwrap_or_default();
+        return replay::run(&ProviderHandler::new().await?, range).await;
+    }
+
     let num_workers: usize = env::var("NUM_WORKERS")
         .unwrap_or("5".to_string())
         .parse()
@@ -33,192 +106,829 @@ async fn main() -> anyhow::Result<()> {
         std::fs::remove_file(api_path.as_str())?;
     }
 
-    let listener = UnixListener::bind(api_path.as_str())?;
-    println!("API listening on {}", api_path.as_str());
+    let listener = Arc::new(UnixListener::bind(api_path.as_str())?);
+    info!("API listening on {}", api_path.as_str());
+
+    let queue_capacity: usize = env::var("QUEUE_CAPACITY")
+        .unwrap_or("10000".to_string())
+        .parse()?;
+    // Each shard gets its own accept loop, channel and slice of the workers, so readers on
+    // different cores don't contend on a single queue.
+    let num_shards: usize = env::var("READER_SHARDS")
+        .unwrap_or("1".to_string())
+        .parse::<usize>()?
+        .clamp(1, num_workers.max(1));
+    let retry_policy = RetryPolicy::from_env(
+        "RETRY_QUEUE",
+        RetryPolicy {
+            max_attempts: 8,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            jitter: 0.2,
+        },
+    )?;
+    let priority = PriorityConfig::from_env()?;
+    // Payments waiting longer than this skip the retry ladder, 0 disables it.
+    let deadline = match env::var("PAYMENT_DEADLINE_MS")
+        .unwrap_or("0".to_string())
+        .parse()?
+    {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    };
+    // Correlation ids are remembered this long after they were accepted, 0 keeps them all.
+    let idempotency_ttl = match env::var("IDEMPOTENCY_TTL_SECS")
+        .unwrap_or("600".to_string())
+        .parse()?
+    {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    let shards: Arc<[Shard]> = (0..num_shards)
+        .map(|_| {
+            let lanes = Lanes::new(queue_capacity.div_ceil(num_shards), priority.weight);
+            Shard::new(lanes, retry_policy)
+        })
+        .collect();
+    let spill = match env::var("QUEUE_PATH") {
+        Ok(path) => SpillQueue::open(path)?,
+        Err(_) => SpillQueue::disabled(),
+    };
+
+    let ctx = Context {
+        shards,
+        shard: 0,
+        priority,
+        deadline,
+        handler: Arc::new(ProviderHandler::new().await?),
+        seen: IdempotencyGuard::new(idempotency_ttl),
+        spill,
+    };
 
-    let (tx, rx): (Sender<PaymentDTO>, Receiver<PaymentDTO>) = unbounded();
-    let handler = Arc::new(ProviderHandler::new().await?);
+    let shutdown_deadline = Duration::from_millis(
+        env::var("SHUTDOWN_TIMEOUT_MS")
+            .unwrap_or("5000".to_string())
+            .parse()?,
+    );
+    let mut sigterm = signal(SignalKind::terminate())?;
+    reconcile::spawn(Arc::clone(&ctx.handler))?;
+    fees::spawn(Arc::clone(&ctx.handler));
 
+    let mut workers = JoinSet::new();
     for i in 0..num_workers {
-        let handler = Arc::clone(&handler);
-        let rx = rx.clone();
+        workers.spawn(run_worker(i, ctx.with_shard(i % num_shards)));
+    }
+
+    // Replay after the workers are up so a backlog larger than the channel can't block startup.
+    let pending = ctx.spill.pending();
+    if !pending.is_empty() {
+        info!("Replaying {} payments from the spill queue", pending.len());
+        let ctx = ctx.clone();
         tokio::spawn(async move {
-            while let Ok(payment) = rx.recv().await {
-                if let Err(e) = handler.process_payment(payment).await {
-                    eprintln!("[worker-{i}] Failed to process payment: {e}");
+            for (i, payment) in pending.into_iter().enumerate() {
+                ctx.seen.try_claim(payment.correlation_id);
+                ctx.handler.cancels.accept(payment.correlation_id);
+                let shard = &ctx.shards[i % ctx.shards.len()];
+                let queued = QueuedPayment::new(payment, &ctx.priority);
+                if shard.lanes.tx.send(queued).await.is_err() {
+                    return;
                 }
             }
         });
     }
 
+    let mut accept_loops = JoinSet::new();
+    for shard in 0..num_shards {
+        accept_loops.spawn(accept_loop(Arc::clone(&listener), ctx.with_shard(shard)));
+    }
+
+    tokio::select! {
+        // Accept loops only return on error.
+        Some(res) = accept_loops.join_next() => res??,
+        _ = sigterm.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+
+    accept_loops.shutdown().await;
+    drop(listener);
+    let _ = std::fs::remove_file(api_path.as_str());
+    drain(ctx, workers, shutdown_deadline).await
+}
+
+/// Let the workers finish the payments already accepted, up to `deadline`, then flush the
+/// pending DB writes. Fails when payments had to be abandoned so the exit status shows it.
+async fn drain(ctx: Context, mut workers: JoinSet<()>, deadline: Duration) -> anyhow::Result<()> {
+    info!("Shutting down, draining {} queued payments", ctx.queued());
+    for shard in ctx.shards.iter() {
+        shard.retry_queue.shutdown().await;
+        // Connected readers answer with Nacks from now on, and workers exit once the channel
+        // is empty.
+        shard.lanes.tx.close();
+    }
+
+    let _ = tokio::time::timeout(deadline, async {
+        while workers.join_next().await.is_some() {}
+    })
+    .await;
+    let in_flight = workers.len();
+    workers.shutdown().await;
+
+    let flushed = ctx.handler.db.flush().await;
+    if let Err(e) = &flushed {
+        error!("Failed to flush pending DB writes: {e}");
+    }
+
+    let parked: usize = ctx.shards.iter().map(|s| s.retry_queue.abandoned()).sum();
+    let abandoned = parked + ctx.queued() + in_flight;
+    if abandoned > 0 {
+        anyhow::bail!("Abandoned {abandoned} payments on shutdown");
+    }
+    flushed
+}
+
+/// The worker lanes along with the retry queue feeding back into them.
+struct Shard {
+    lanes: Lanes,
+    retry_queue: RetryQueue,
+}
+
+impl Shard {
+    fn new(lanes: Lanes, retry_policy: RetryPolicy) -> Self {
+        Self {
+            retry_queue: RetryQueue::spawn(retry_policy, lanes.tx.clone()),
+            lanes,
+        }
+    }
+}
+
+/// State shared by the socket readers and the workers.
+#[derive(Clone)]
+struct Context {
+    shards: Arc<[Shard]>,
+    /// Shard this reader or worker belongs to.
+    shard: usize,
+    priority: PriorityConfig,
+    /// How long a payment may wait before workers take the fast path for it.
+    deadline: Option<Duration>,
+    handler: Arc<ProviderHandler>,
+    spill: SpillQueue,
+    seen: IdempotencyGuard,
+}
+
+impl Context {
+    fn with_shard(&self, shard: usize) -> Self {
+        Self {
+            shard,
+            ..self.clone()
+        }
+    }
+
+    fn shard(&self) -> &Shard {
+        &self.shards[self.shard]
+    }
+
+    /// Payments waiting across every shard.
+    fn queued(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lanes.len()).sum()
+    }
+}
+
+async fn accept_loop(listener: Arc<UnixListener>, ctx: Context) -> anyhow::Result<()> {
     loop {
         let (stream, _) = listener.accept().await?;
-        let (reader, _) = stream.into_split();
-        let mut reader = BufReader::new(reader).lines();
+        tokio::spawn(handle_connection(stream, ctx.clone()));
+    }
+}
 
-        let tx = tx.clone();
-        tokio::spawn(async move {
-            while let Ok(Some(line)) = reader.next_line().await {
-                if line.trim().is_empty() {
-                    continue;
+async fn run_worker(i: usize, ctx: Context) {
+    let shard = ctx.shard();
+    let mut lanes = shard.lanes.receiver();
+    while let Some(queued) = lanes.recv().await {
+        let correlation_id = queued.payment.correlation_id;
+        if ctx.handler.cancels.is_cancelled(&correlation_id) {
+            ctx.spill.remove(&correlation_id);
+            continue;
+        }
+        let span = debug_span!(
+            "payment",
+            worker = i,
+            %correlation_id,
+            trace_id = queued.payment.trace_id.as_deref(),
+        );
+        logging::follow(&span, queued.payment.traceparent.as_deref());
+        let processed = match ctx.deadline {
+            Some(deadline) if queued.enqueued_at.elapsed() > deadline => {
+                let payment = queued.payment.clone();
+                ctx.handler.process_stale(payment).instrument(span).await
+            }
+            _ => {
+                let payment = queued.payment.clone();
+                ctx.handler.process_payment(payment).instrument(span).await
+            }
+        };
+        match processed {
+            Ok(true) => ctx.spill.remove(&correlation_id),
+            Ok(false) => {
+                if !shard.retry_queue.schedule(queued) {
+                    ctx.handler.metrics.record_dropped();
+                    ctx.handler.cancels.forget(&correlation_id);
+                    ctx.spill.remove(&correlation_id);
+                    ctx.seen.release(&correlation_id);
                 }
+            }
+            Err(e) => {
+                error!("[worker-{i}] Failed to process payment: {e}");
+                ctx.handler.metrics.record_dropped();
+                ctx.handler.cancels.forget(&correlation_id);
+                ctx.spill.remove(&correlation_id);
+            }
+        }
+    }
+}
 
-                match serde_json::from_str::<PaymentDTO>(&line) {
-                    Ok(payment) => {
-                        if let Err(e) = tx.send(payment).await {
-                            eprintln!("Channel send failed: {e}");
-                        }
-                    }
+async fn handle_connection(stream: UnixStream, ctx: Context) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader).lines();
+
+    while let Ok(Some(line)) = reader.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match serde_json::from_str::<ApiFrame>(&line) {
+            Ok(ApiFrame::Payment(payment)) => {
+                let correlation_id = payment.correlation_id;
+                let reply = enqueue(&ctx, payment);
+                if let Err(e) = write_reply(&mut writer, &reply).await {
+                    error!("Failed to reply for payment {correlation_id}: {e}");
+                }
+            }
+            Ok(ApiFrame::Purge) => {
+                for shard in ctx.shards.iter() {
+                    shard.retry_queue.clear();
+                }
+                ctx.spill.clear();
+                ctx.seen.clear();
+                ctx.handler.cancels.clear();
+                let dropped = ctx.shards.iter().map(|shard| shard.lanes.clear()).sum();
+
+                if let Err(e) = write_reply(&mut writer, &ApiReply::Purged { dropped }).await {
+                    error!("Failed to acknowledge purge: {e}");
+                }
+            }
+            Ok(ApiFrame::Metrics) => {
+                let reply = ApiReply::Metrics(ctx.handler.metrics.snapshot());
+                if let Err(e) = write_reply(&mut writer, &reply).await {
+                    error!("Failed to report metrics: {e}");
+                }
+            }
+            Ok(ApiFrame::Cancel { correlation_id }) => {
+                let outcome = cancel(&ctx, correlation_id).await;
+                let reply = ApiReply::Cancelled {
+                    correlation_id,
+                    outcome,
+                };
+                if let Err(e) = write_reply(&mut writer, &reply).await {
+                    error!("Failed to reply to cancel of {correlation_id}: {e}");
+                }
+            }
+            Ok(ApiFrame::Status) => {
+                let reply = ApiReply::Status(status(&ctx));
+                if let Err(e) = write_reply(&mut writer, &reply).await {
+                    error!("Failed to report status: {e}");
+                }
+            }
+            Ok(ApiFrame::Flush) => {
+                let unwritten = match ctx.handler.db.flush().await {
+                    Ok(()) => 0,
                     Err(e) => {
-                        eprintln!("Invalid payment payload: {e}");
+                        error!("Failed to flush DB writes on request: {e}");
+                        ctx.handler.db.lag().0 as u64
                     }
+                };
+                if let Err(e) = write_reply(&mut writer, &ApiReply::Flushed { unwritten }).await {
+                    error!("Failed to acknowledge flush: {e}");
                 }
             }
-        });
+            Ok(ApiFrame::Depth) => {
+                let queued = ctx.queued() as u64;
+                if let Err(e) = write_reply(&mut writer, &ApiReply::Depth { queued }).await {
+                    error!("Failed to report queue depth: {e}");
+                }
+            }
+            Err(e) => {
+                error!("Invalid frame: {e}");
+            }
+        }
+    }
+}
+
+/// Hand a payment to the workers, answering with the reply the gateway is waiting for.
+fn enqueue(ctx: &Context, payment: PaymentDTO) -> ApiReply {
+    let correlation_id = payment.correlation_id;
+    if !ctx.seen.try_claim(correlation_id) {
+        warn!("Skipping duplicate payment {correlation_id}");
+        return ApiReply::Ack { correlation_id };
+    }
+    if let Err(e) = ctx.spill.push(&payment) {
+        error!("Failed to spill payment: {e}");
+    }
+    // Before queueing, so a worker can't settle the payment first.
+    ctx.handler.cancels.accept(correlation_id);
+
+    let queued = QueuedPayment::new(payment, &ctx.priority);
+    let reason = match ctx.shard().lanes.tx.try_send(queued) {
+        Ok(()) => return ApiReply::Ack { correlation_id },
+        Err(TrySendError::Full(_)) => NackReason::QueueFull,
+        Err(TrySendError::Closed(_)) => {
+            error!("Channel send failed: channel closed");
+            NackReason::Unavailable
+        }
+    };
+    ctx.handler.metrics.record_dropped();
+    ctx.handler.cancels.forget(&correlation_id);
+    ctx.spill.remove(&correlation_id);
+    ctx.seen.release(&correlation_id);
+    ApiReply::Nack {
+        correlation_id,
+        reason,
+    }
+}
+
+fn status(ctx: &Context) -> WorkerStatus {
+    let handler = &ctx.handler;
+    let health = handler.health.load();
+    let provider = |provider: CurrentProvider, health: &ProviderHealth| {
+        let latency = handler.latency(&provider);
+        ProviderStatus {
+            circuit: handler.breaker(&provider).state().into(),
+            failing: health.failing,
+            min_response_time: health.min_response_time,
+            last_latency_ms: latency.last().map(|d| d.as_millis() as u64),
+            p99_latency_ms: latency.p99().map(|d| d.as_millis() as u64),
+        }
+    };
+    let (db_backlog, db_lag) = handler.db.lag();
+
+    WorkerStatus {
+        queued: ctx.queued() as u64,
+        default: provider(CurrentProvider::Default, &health.default),
+        fallback: provider(CurrentProvider::Fallback, &health.fallback),
+        db_backlog: db_backlog as u64,
+        db_lag_ms: db_lag.as_millis() as u64,
+    }
+}
+
+/// Void a payment: queued ones are skipped by the workers, processed ones get a compensating
+/// record so the summaries no longer count them.
+async fn cancel(ctx: &Context, correlation_id: Uuid) -> CancelOutcome {
+    let (outcome, voids) = ctx.handler.cancels.cancel(correlation_id);
+    for void in voids {
+        if let Err(e) = ctx.handler.db.push(void).await {
+            error!("Failed to void payment {correlation_id}: {e}");
+            return CancelOutcome::Unknown;
+        }
     }
+    outcome
+}
+
+/// A payment waiting in the worker lanes.
+#[derive(Debug)]
+pub struct QueuedPayment {
+    pub payment: PaymentDTO,
+    /// Number of times every provider already rejected it.
+    pub attempt: u32,
+    /// Whether it goes through the priority lane, including on retries.
+    pub priority: bool,
+    /// When the worker accepted it. Kept across retries.
+    pub enqueued_at: Instant,
+}
+
+impl QueuedPayment {
+    pub fn new(payment: PaymentDTO, priority: &PriorityConfig) -> Self {
+        Self {
+            priority: priority.is_priority(&payment),
+            payment,
+            attempt: 0,
+            enqueued_at: Instant::now(),
+        }
+    }
+}
+
+async fn write_reply(writer: &mut OwnedWriteHalf, reply: &ApiReply) -> anyhow::Result<()> {
+    let mut buf = serde_json::to_vec(reply)?;
+    buf.push(b'\n');
+    writer.write_all(&buf).await?;
+    Ok(())
 }
 
 #[derive(Clone)]
 pub struct ProviderHandler {
     pub client: Client,
+    pub registry: Arc<ProviderRegistry>,
+    pub db: DbFlusher,
     pub current_provider: CurrentProvider,
+    pub health: Arc<ArcSwap<ProviderState>>,
+    pub default_breaker: Arc<CircuitBreaker>,
+    pub fallback_breaker: Arc<CircuitBreaker>,
+    pub strategy: Arc<dyn RoutingStrategy>,
+    pub retry_policy: RetryPolicy,
+    pub timeouts: TimeoutConfig,
+    pub hedge: HedgeConfig,
+    pub default_latency: Arc<LatencyTracker>,
+    pub fallback_latency: Arc<LatencyTracker>,
+    pub metrics: Arc<Metrics>,
+    pub chaos: ChaosConfig,
+    /// In-process processors replacing the HTTP calls with `PROVIDER_MODE=mock`.
+    pub mock: Option<Arc<MockProviders>>,
+    pub uncertain: Arc<UncertainJournal>,
+    pub cancels: Arc<Cancellations>,
+    pub fees: Arc<FeeWatch>,
+    pub reconcile: ReconcileConfig,
+    pub default_limiter: Arc<RateLimiter>,
+    pub fallback_limiter: Arc<RateLimiter>,
 }
 
 impl ProviderHandler {
     pub async fn new() -> anyhow::Result<Self> {
         let mut headers = HeaderMap::new();
         headers.insert("Content-Type", "application/json".parse()?);
-        // TODO: Tweak Client config
-        let client = Client::builder()
-            .no_gzip()
-            .no_zstd()
-            .default_headers(headers.clone())
-            .build()?;
+        let timeouts = TimeoutConfig::from_env()?;
+        let client_config = ClientConfig::from_env()?;
+        let client = client_config.build(&timeouts, headers)?;
+
+        let registry = Arc::new(ProviderRegistry::from_env()?);
+        let health = Arc::new(ArcSwap::from_pointee(ProviderState::default()));
+        let mock = MockProviders::from_env()?;
+        match &mock {
+            Some(mock) => Arc::clone(mock).spawn_health_poller(Arc::clone(&health)),
+            None => {
+                health::spawn_health_poller(
+                    client.clone(),
+                    Arc::clone(&registry),
+                    Arc::clone(&health),
+                );
+                client_config.spawn_prewarm(&client, &registry);
+            }
+        }
+
+        let breaker_config = BreakerConfig::from_env()?;
+        let hedge = HedgeConfig::from_env()?;
+        let chaos = ChaosConfig::from_env()?;
+        let reconcile = ReconcileConfig::from_env()?;
+        let limiter_config = LimiterConfig::from_env()?;
+        let strategy: StrategyKind = env::var("ROUTING_STRATEGY")
+            .unwrap_or("health-aware".to_string())
+            .parse()?;
 
         Ok(Self {
+            db: DbFlusher::spawn(
+                DbClient::from_env(client.clone())?,
+                FlusherConfig::from_env()?,
+                chaos.db,
+            ),
+            chaos,
+            mock,
+            cancels: Arc::new(Cancellations::from_env()?),
+            fees: Arc::new(FeeWatch::new(FeeConfig::from_env()?)),
+            uncertain: Arc::new(UncertainJournal::new(reconcile.journal_capacity())),
+            reconcile,
             client,
+            registry,
             current_provider: CurrentProvider::Default,
+            health,
+            default_breaker: Arc::new(CircuitBreaker::new(breaker_config)),
+            fallback_breaker: Arc::new(CircuitBreaker::new(breaker_config)),
+            strategy: strategy.build().into(),
+            retry_policy: RetryPolicy::from_env(
+                "PROVIDER_RETRY",
+                RetryPolicy {
+                    max_attempts: 5,
+                    base_delay: Duration::from_millis(100),
+                    max_delay: Duration::from_millis(500),
+                    jitter: 0.5,
+                },
+            )?,
+            timeouts,
+            hedge,
+            default_latency: Arc::new(LatencyTracker::new(hedge.window)),
+            fallback_latency: Arc::new(LatencyTracker::new(hedge.window)),
+            metrics: Arc::new(Metrics::default()),
+            default_limiter: Arc::new(RateLimiter::new(limiter_config)),
+            fallback_limiter: Arc::new(RateLimiter::new(limiter_config)),
         })
     }
 
-    /// Process a payment using a naive strategy. If default provider is down try fallback, if both fails drop the payment.
-    // TODO: Explore different strategies for handling payment processing failures.
-    pub async fn process_payment(&self, payload: PaymentDTO) -> anyhow::Result<()> {
-        let now = Utc::now().to_rfc3339();
-        let payload = PaymentServiceDTO::new(payload, now.clone());
-        for _ in 0..5 {
-            let res = self
-                .client
-                .post(URLS.get("default_payments").unwrap())
-                .body(serde_json::to_string(&payload)?)
-                .send()
-                .await?
-                .error_for_status();
-
-            if res.is_ok() {
-                let _ = self
-                    .client
-                    .post("http://rinha-db:8888/payment")
-                    .body(serde_json::to_string(&DBWrite {
-                        key: now,
-                        value: payload.amount,
-                        tree: shared_types::SledTree::Default,
-                    })?)
-                    .send()
-                    .await?;
-
-                return Ok(());
-            }
-            tokio::time::sleep(Duration::from_millis(500)).await;
-        }
-
-        let res = self
-            .client
-            .post(URLS.get("fallback_payments").unwrap())
-            .body(serde_json::to_string(&payload)?)
-            .send()
-            .await?
-            .error_for_status();
-
-        if res.is_ok() {
-            let _ = self
-                .client
-                .post("http://rinha-db:8888/payment")
-                .body(serde_json::to_string(&DBWrite {
-                    key: now,
-                    value: payload.amount,
-                    tree: shared_types::SledTree::Fallback,
-                })?)
-                .send()
-                .await?;
+    /// Process a payment on the providers picked by the routing strategy. The primary provider
+    /// is retried and the secondary gets a single attempt. Returns whether a provider accepted
+    /// the payment, so the caller can park it in the retry queue otherwise.
+    pub async fn process_payment(&self, payload: PaymentDTO) -> anyhow::Result<bool> {
+        let (payload, body) = Self::service_payload(payload)?;
+        let choice = self.strategy.choose(&self.routing_context());
 
-            return Ok(());
+        if let Some(secondary) = choice.secondary {
+            if self.should_hedge(choice.primary, secondary) {
+                let processed = self.hedge(choice.primary, secondary, &payload, &body).await;
+                if !processed.is_empty() {
+                    self.store_all(&processed, &payload).await?;
+                }
+                return Ok(!processed.is_empty());
+            }
         }
 
-        Ok(())
+        for attempt in 0..self.retry_policy.max_attempts {
+            match self.send(&choice.primary, &payload, &body).await {
+                // A duplicate was processed by an earlier attempt, it still counts.
+                Ok(()) | Err(ProviderError::Duplicate) => {
+                    self.store(choice.primary, &payload).await?;
+                    return Ok(true);
+                }
+                Err(ProviderError::InvalidPayload(reason)) => {
+                    anyhow::bail!("{:?} rejected the payment: {reason}", choice.primary)
+                }
+                Err(ProviderError::Down) => break,
+                Err(ProviderError::Retryable(_)) => {}
+            }
+            // Don't keep waiting on a provider the breaker has given up on.
+            if self.breaker(&choice.primary).is_open() {
+                break;
+            }
+            if attempt + 1 < self.retry_policy.max_attempts {
+                tokio::time::sleep(self.retry_policy.delay(attempt)).await;
+            }
+        }
+
+        if let Some(secondary) = choice.secondary {
+            match self.send(&secondary, &payload, &body).await {
+                Ok(()) | Err(ProviderError::Duplicate) => {
+                    self.store(secondary, &payload).await?;
+                    return Ok(true);
+                }
+                Err(ProviderError::InvalidPayload(reason)) => {
+                    anyhow::bail!("{secondary:?} rejected the payment: {reason}")
+                }
+                Err(_) => {}
+            }
+        }
+
+        Ok(false)
     }
-}
 
-#[derive(Deserialize)]
-struct PaymentSummaryResponse {
-    #[serde(rename = "totalRequests")]
-    total_requests: f64,
-    #[serde(rename = "totalAmount")]
-    total_amount: f64,
-    #[serde(rename = "totalFee")]
-    total_fee: f64,
-    #[serde(rename = "feePerTransaction")]
-    fee_per_transaction: f64,
-}
-
-#[derive(Deserialize)]
-struct ProviderHealthResponse {
-    failing: bool,
-    #[serde(rename = "minResponseTime")]
-    min_response_time: u64,
-}
-
-pub static URLS: LazyLock<HashMap<&'static str, String>> = LazyLock::new(|| {
-    let default_base = env::var("PAYMENT_PROCESSOR_URL_DEFAULT")
-        .unwrap_or_else(|_| "http://0.0.0.0:8001".to_string());
-    let fallback_base = env::var("PAYMENT_PROCESSOR_URL_FALLBACK")
-        .unwrap_or_else(|_| "http://0.0.0.0:8002".to_string());
-
-    HashMap::from([
-        ("default_payments", format!("{}/payments", default_base)),
-        ("fallback_payments", format!("{}/payments", fallback_base)),
-        (
-            "default_payments_health",
-            format!("{}/payments/service-health", default_base),
-        ),
-        (
-            "fallback_payments_health",
-            format!("{}/payments/service-health", fallback_base),
-        ),
-    ])
-});
+    /// A single call to whichever provider is healthy right now, for payments that already
+    /// waited past the deadline. There are no retries or hedging, a failure sends the payment
+    /// back to the retry queue.
+    pub async fn process_stale(&self, payload: PaymentDTO) -> anyhow::Result<bool> {
+        let Some(provider) = self.healthy_provider() else {
+            return Ok(false);
+        };
+        let (payload, body) = Self::service_payload(payload)?;
 
-#[derive(Clone)]
-pub enum CurrentProvider {
-    Default,
-    Fallback,
-}
+        match self.send(&provider, &payload, &body).await {
+            Ok(()) | Err(ProviderError::Duplicate) => {
+                self.store(provider, &payload).await?;
+                Ok(true)
+            }
+            Err(ProviderError::InvalidPayload(reason)) => {
+                anyhow::bail!("{provider:?} rejected the payment: {reason}")
+            }
+            Err(_) => Ok(false),
+        }
+    }
 
-#[derive(Serialize)]
-pub struct PaymentServiceDTO {
-    #[serde(rename = "correlationId")]
-    pub correlation_id: Uuid,
-    pub amount: f64,
-    #[serde(rename = "requestedAt")]
-    pub requested_at: String,
-}
+    fn service_payload(payload: PaymentDTO) -> anyhow::Result<(PaymentServiceDTO, Bytes)> {
+        let requested_at = payload
+            .requested_at
+            .clone()
+            .unwrap_or_else(|| Utc::now().to_rfc3339());
+        let payload = PaymentServiceDTO::new(payload, requested_at);
+        let body = payload.to_body()?;
+        Ok((payload, body))
+    }
 
-impl PaymentServiceDTO {
-    pub fn new(payment: PaymentDTO, requested_at: String) -> Self {
-        PaymentServiceDTO {
-            correlation_id: payment.correlation_id,
-            amount: payment.amount,
-            requested_at,
+    /// The preferred provider when it's available, the other one otherwise.
+    fn healthy_provider(&self) -> Option<CurrentProvider> {
+        let routing = self.routing_context();
+        let preferred = routing.preferred;
+        [preferred, preferred.other()]
+            .into_iter()
+            .find(|&provider| routing.view(provider).available())
+    }
+
+    fn routing_context(&self) -> RoutingContext {
+        let health = self.health.load();
+        let fee = |provider| self.fees.fee(provider, self.registry.get(provider).fee);
+        RoutingContext {
+            preferred: self.fees.preferred(),
+            default: ProviderView {
+                failing: health.default.failing,
+                circuit_open: self.default_breaker.is_open(),
+                fee: fee(CurrentProvider::Default),
+                min_response_time: health.default.min_response_time,
+            },
+            fallback: ProviderView {
+                failing: health.fallback.failing,
+                circuit_open: self.fallback_breaker.is_open(),
+                fee: fee(CurrentProvider::Fallback),
+                min_response_time: health.fallback.min_response_time,
+            },
         }
     }
+
+    /// Hedge once the primary's p99 is over budget. Both circuits must be closed, since
+    /// cancelling the losing call would otherwise leave a half-open probe unresolved.
+    fn should_hedge(&self, primary: CurrentProvider, secondary: CurrentProvider) -> bool {
+        let Some(budget) = self.hedge.budget else {
+            return false;
+        };
+        self.breaker(&primary).state() == CircuitState::Closed
+            && self.breaker(&secondary).state() == CircuitState::Closed
+            && self.latency(&primary).p99().is_some_and(|p99| p99 > budget)
+    }
+
+    /// Call the primary, then the secondary as well after the hedge delay, returning every
+    /// provider that processed the payment. The secondary is cancelled when the primary
+    /// accepts before it was called. Once both were called, the loser is still awaited, since
+    /// a provider that received the payment may process it anyway and it must be stored under
+    /// that provider too. Timeouts on either call land in the uncertain journal.
+    async fn hedge(
+        &self,
+        primary: CurrentProvider,
+        secondary: CurrentProvider,
+        payment: &PaymentServiceDTO,
+        body: &Bytes,
+    ) -> Vec<CurrentProvider> {
+        let hedged = AtomicBool::new(false);
+        let first = self.send(&primary, payment, body);
+        let second = async {
+            tokio::time::sleep(self.hedge.delay).await;
+            hedged.store(true, Ordering::Relaxed);
+            self.send(&secondary, payment, body).await
+        };
+        tokio::pin!(first, second);
+
+        let mut processed = Vec::with_capacity(2);
+        let (mut first_done, mut second_done) = (false, false);
+        while !(first_done && second_done) {
+            tokio::select! {
+                outcome = &mut first, if !first_done => {
+                    if ProviderError::processed(&outcome) {
+                        processed.push(primary);
+                    }
+                    first_done = true;
+                }
+                outcome = &mut second, if !second_done => {
+                    if ProviderError::processed(&outcome) {
+                        processed.push(secondary);
+                    }
+                    second_done = true;
+                }
+            }
+            if first_done && !processed.is_empty() && !hedged.load(Ordering::Relaxed) {
+                break;
+            }
+        }
+
+        if let Some(&winner) = processed.first() {
+            self.provider_metrics(&winner).record_hedge_win();
+        }
+        if processed.len() == 2 {
+            warn!(
+                "Payment {} was processed by both providers while hedging",
+                payment.correlation_id
+            );
+            self.metrics.record_hedged_twice();
+        }
+        processed
+    }
+
+    fn provider_metrics(&self, provider: &CurrentProvider) -> &ProviderCounters {
+        match provider {
+            CurrentProvider::Default => &self.metrics.default,
+            CurrentProvider::Fallback => &self.metrics.fallback,
+        }
+    }
+
+    fn limiter(&self, provider: &CurrentProvider) -> &RateLimiter {
+        match provider {
+            CurrentProvider::Default => &self.default_limiter,
+            CurrentProvider::Fallback => &self.fallback_limiter,
+        }
+    }
+
+    fn latency(&self, provider: &CurrentProvider) -> &LatencyTracker {
+        match provider {
+            CurrentProvider::Default => &self.default_latency,
+            CurrentProvider::Fallback => &self.fallback_latency,
+        }
+    }
+
+    fn breaker(&self, provider: &CurrentProvider) -> &CircuitBreaker {
+        match provider {
+            CurrentProvider::Default => &self.default_breaker,
+            CurrentProvider::Fallback => &self.fallback_breaker,
+        }
+    }
+
+    /// POST the payment to a provider with a timeout scaled from its reported
+    /// `minResponseTime`, paced by the provider's rate limiter. Calls are skipped while the
+    /// provider's circuit is open.
+    async fn send(
+        &self,
+        provider: &CurrentProvider,
+        payment: &PaymentServiceDTO,
+        body: &Bytes,
+    ) -> Result<(), ProviderError> {
+        let limiter = self.limiter(provider);
+        limiter.acquire().await;
+
+        let breaker = self.breaker(provider);
+        if !breaker.allow() {
+            return Err(ProviderError::Down);
+        }
+
+        let health = self.health.load();
+        let min_response_time = match provider {
+            CurrentProvider::Default => health.default.min_response_time,
+            CurrentProvider::Fallback => health.fallback.min_response_time,
+        };
+
+        let started = Instant::now();
+        let timeout = self.timeouts.for_call(min_response_time);
+        let outcome = if self.chaos.provider.inject().await {
+            Err(ProviderError::Retryable(StatusCode::INTERNAL_SERVER_ERROR))
+        } else if let Some(mock) = &self.mock {
+            match tokio::time::timeout(timeout, mock.get(*provider).pay(payment)).await {
+                Ok(outcome) => outcome,
+                Err(_) => {
+                    self.uncertain.push(*provider, payment);
+                    Err(ProviderError::Down)
+                }
+            }
+        } else {
+            let span = debug_span!("provider", ?provider);
+            let mut request = self
+                .client
+                .post(&self.registry.get(*provider).payments_url)
+                .timeout(timeout)
+                .body(body.clone());
+            if let Some(traceparent) = logging::traceparent(&span) {
+                request = request.header("traceparent", traceparent);
+            }
+            let res = request.send().instrument(span).await;
+            // The provider may still process a call we gave up on.
+            if matches!(&res, Err(e) if e.is_timeout()) {
+                self.uncertain.push(*provider, payment);
+            }
+            ProviderError::from_response(res).await
+        };
+        let elapsed = started.elapsed();
+
+        self.latency(provider).record(elapsed);
+        self.provider_metrics(provider).record(&outcome, elapsed);
+        match &outcome {
+            Ok(()) => limiter.on_success(),
+            Err(ProviderError::Retryable(StatusCode::TOO_MANY_REQUESTS)) => limiter.on_throttled(),
+            Err(_) => {}
+        }
+        // Rejections of a single payment say nothing about the provider's health.
+        match &outcome {
+            Err(e) if e.is_provider_failure() => breaker.record_failure(),
+            _ => breaker.record_success(),
+        }
+        outcome
+    }
+
+    /// Queue a processed payment for the next batch written to rinha-db.
+    async fn store(
+        &self,
+        provider: CurrentProvider,
+        payment: &PaymentServiceDTO,
+    ) -> anyhow::Result<()> {
+        self.store_all(&[provider], payment).await
+    }
+
+    /// Queue a payment every one of `providers` processed, as hedged ones can be, with a record
+    /// under each.
+    async fn store_all(
+        &self,
+        providers: &[CurrentProvider],
+        payment: &PaymentServiceDTO,
+    ) -> anyhow::Result<()> {
+        let writes: Vec<DBWrite> = providers
+            .iter()
+            .map(|provider| DBWrite {
+                key: payment_key(&payment.requested_at, &payment.correlation_id),
+                value: payment.amount,
+                tree: provider.tree(),
+                ack: Ack::default(),
+                trace_id: payment.trace_id.clone(),
+                traceparent: logging::traceparent(&Span::current()),
+            })
+            .collect();
+        let voids = self.cancels.settle(payment.correlation_id, &writes);
+        for write in writes.into_iter().chain(voids) {
+            self.db.push(write).await?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,107 @@
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use payment_core::error::ProviderError;
+use shared_types::{ProviderMetrics, SummaryDrift, WorkerMetrics};
+
+/// Upper bounds of the latency histogram buckets, in milliseconds.
+const BUCKETS_MS: [u64; 10] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+
+/// Counters for a single provider, updated by every call made to it.
+#[derive(Default)]
+pub struct ProviderCounters {
+    success: AtomicU64,
+    retryable_failure: AtomicU64,
+    permanent_failure: AtomicU64,
+    /// One slot per bucket plus the unbounded one.
+    latency: [AtomicU64; BUCKETS_MS.len() + 1],
+    latency_sum_ms: AtomicU64,
+    hedge_wins: AtomicU64,
+}
+
+impl ProviderCounters {
+    /// Count a finished call. Duplicates count as successes since the provider holds the
+    /// payment.
+    pub fn record(&self, outcome: &Result<(), ProviderError>, latency: Duration) {
+        let counter = match outcome {
+            Ok(()) | Err(ProviderError::Duplicate) => &self.success,
+            Err(ProviderError::InvalidPayload(_)) => &self.permanent_failure,
+            Err(_) => &self.retryable_failure,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        let ms = latency.as_millis() as u64;
+        let bucket = BUCKETS_MS
+            .iter()
+            .position(|&le| ms <= le)
+            .unwrap_or(BUCKETS_MS.len());
+        self.latency[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_ms.fetch_add(ms, Ordering::Relaxed);
+    }
+
+    /// Count a hedged payment this provider processed first.
+    pub fn record_hedge_win(&self) {
+        self.hedge_wins.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> ProviderMetrics {
+        let bounds = BUCKETS_MS.iter().copied().chain([u64::MAX]);
+        let mut cumulative = 0;
+        let latency_buckets = bounds
+            .zip(&self.latency)
+            .map(|(le, count)| {
+                cumulative += count.load(Ordering::Relaxed);
+                (le, cumulative)
+            })
+            .collect();
+
+        ProviderMetrics {
+            success: self.success.load(Ordering::Relaxed),
+            retryable_failure: self.retryable_failure.load(Ordering::Relaxed),
+            permanent_failure: self.permanent_failure.load(Ordering::Relaxed),
+            latency_buckets,
+            latency_sum_ms: self.latency_sum_ms.load(Ordering::Relaxed),
+            hedge_wins: self.hedge_wins.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counters for the whole worker, reported to the gateway over the control socket.
+#[derive(Default)]
+pub struct Metrics {
+    pub default: ProviderCounters,
+    pub fallback: ProviderCounters,
+    dropped: AtomicU64,
+    hedged_twice: AtomicU64,
+    drift: Mutex<Option<SummaryDrift>>,
+}
+
+impl Metrics {
+    pub fn record_drift(&self, drift: SummaryDrift) {
+        *self.drift.lock().unwrap() = Some(drift);
+    }
+
+    pub fn record_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a hedged payment both providers processed.
+    pub fn record_hedged_twice(&self) {
+        self.hedged_twice.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> WorkerMetrics {
+        WorkerMetrics {
+            default: self.default.snapshot(),
+            fallback: self.fallback.snapshot(),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            hedged_twice: self.hedged_twice.load(Ordering::Relaxed),
+            drift: *self.drift.lock().unwrap(),
+        }
+    }
+}
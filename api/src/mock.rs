@@ -0,0 +1,172 @@
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    env,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwap;
+use reqwest::StatusCode;
+use tracing::info;
+use uuid::Uuid;
+
+use payment_core::{
+    CurrentProvider, PaymentServiceDTO,
+    error::ProviderError,
+    health::{ProviderHealth, ProviderState},
+};
+
+use crate::admin::PaymentSummaryResponse;
+
+/// How a fake processor behaves over time.
+#[derive(Clone, Copy, Debug)]
+pub struct MockProfile {
+    /// Response time with the provider healthy, also reported as `minResponseTime`.
+    pub latency: Duration,
+    /// Up to this much is randomly added to every call.
+    pub jitter: Duration,
+    /// Probability that a call outside an outage answers 500.
+    pub fail_rate: f64,
+    /// Every `outage_every`, the provider fails every call for `outage`.
+    pub outage_every: Duration,
+    pub outage: Duration,
+    pub fee: f64,
+}
+
+impl MockProfile {
+    fn from_env(prefix: &str, latency: &str, fee: &str) -> anyhow::Result<Self> {
+        let var = |name: &str, default: &str| {
+            env::var(format!("MOCK_{prefix}_{name}")).unwrap_or(default.to_string())
+        };
+
+        Ok(Self {
+            latency: Duration::from_millis(var("LATENCY_MS", latency).parse()?),
+            jitter: Duration::from_millis(var("JITTER_MS", "5").parse()?),
+            fail_rate: var("FAIL_RATE", "0.0").parse()?,
+            outage_every: Duration::from_millis(var("OUTAGE_EVERY_MS", "0").parse()?),
+            outage: Duration::from_millis(var("OUTAGE_MS", "0").parse()?),
+            fee: var("FEE", fee).parse()?,
+        })
+    }
+}
+
+/// In-process stand-in for one payment processor, keeping every payment it accepted.
+pub struct MockProvider {
+    profile: MockProfile,
+    started: Instant,
+    payments: Mutex<HashMap<Uuid, (String, f64)>>,
+}
+
+impl MockProvider {
+    fn new(profile: MockProfile) -> Self {
+        Self {
+            profile,
+            started: Instant::now(),
+            payments: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn in_outage(&self) -> bool {
+        if self.profile.outage_every.is_zero() {
+            return false;
+        }
+        let period = self.profile.outage_every.as_millis();
+        let elapsed = self.started.elapsed().as_millis() % period;
+        elapsed >= period.saturating_sub(self.profile.outage.as_millis())
+    }
+
+    fn health(&self) -> ProviderHealth {
+        ProviderHealth {
+            failing: self.in_outage(),
+            min_response_time: self.profile.latency.as_millis() as u64,
+        }
+    }
+
+    /// Take a payment the way `POST /payments` would. Accepted payments are recorded before
+    /// the simulated latency, so a caller timing out leaves them processed like a real provider.
+    pub async fn pay(&self, payment: &PaymentServiceDTO) -> Result<(), ProviderError> {
+        let failed = self.in_outage() || fastrand::f64() < self.profile.fail_rate;
+        let outcome = if failed {
+            Err(ProviderError::Retryable(StatusCode::INTERNAL_SERVER_ERROR))
+        } else {
+            match self.payments.lock().unwrap().entry(payment.correlation_id) {
+                Entry::Occupied(_) => Err(ProviderError::Duplicate),
+                Entry::Vacant(entry) => {
+                    entry.insert((payment.requested_at.clone(), payment.amount));
+                    Ok(())
+                }
+            }
+        };
+
+        let jitter = self.profile.jitter.mul_f64(fastrand::f64());
+        tokio::time::sleep(self.profile.latency + jitter).await;
+        outcome
+    }
+
+    /// Totals for payments requested between `from` and `to`, like `/admin/payments-summary`.
+    pub fn summary(&self, from: &str, to: &str) -> PaymentSummaryResponse {
+        let payments = self.payments.lock().unwrap();
+        let (requests, amount) = payments
+            .values()
+            .filter(|(requested_at, _)| {
+                requested_at.as_str() >= from && requested_at.as_str() <= to
+            })
+            .fold((0.0, 0.0), |(n, total), (_, amount)| {
+                (n + 1.0, total + amount)
+            });
+
+        PaymentSummaryResponse {
+            total_requests: requests,
+            total_amount: amount,
+            fee_per_transaction: self.profile.fee,
+        }
+    }
+
+    pub fn has_payment(&self, correlation_id: &Uuid) -> bool {
+        self.payments.lock().unwrap().contains_key(correlation_id)
+    }
+}
+
+/// Fake processors used instead of HTTP with `PROVIDER_MODE=mock`, so the worker runs without
+/// the processor containers. Profiles are read from `MOCK_{DEFAULT|FALLBACK}_*`.
+pub struct MockProviders {
+    default: MockProvider,
+    fallback: MockProvider,
+}
+
+impl MockProviders {
+    pub fn from_env() -> anyhow::Result<Option<Arc<Self>>> {
+        match env::var("PROVIDER_MODE").as_deref().unwrap_or("http") {
+            "http" => Ok(None),
+            "mock" => {
+                info!("Using in-process mock payment processors");
+                Ok(Some(Arc::new(Self {
+                    default: MockProvider::new(MockProfile::from_env("DEFAULT", "10", "0.05")?),
+                    fallback: MockProvider::new(MockProfile::from_env("FALLBACK", "20", "0.15")?),
+                })))
+            }
+            other => anyhow::bail!("unknown PROVIDER_MODE {other:?}, expected http or mock"),
+        }
+    }
+
+    pub fn get(&self, provider: CurrentProvider) -> &MockProvider {
+        match provider {
+            CurrentProvider::Default => &self.default,
+            CurrentProvider::Fallback => &self.fallback,
+        }
+    }
+
+    /// Publish the fakes' health on the same 5 second cadence as the real poller.
+    pub fn spawn_health_poller(self: Arc<Self>, state: Arc<ArcSwap<ProviderState>>) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(5));
+            loop {
+                ticker.tick().await;
+                state.store(Arc::new(ProviderState {
+                    default: self.default.health(),
+                    fallback: self.fallback.health(),
+                }));
+            }
+        });
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    collections::VecDeque,
+    env,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::{SecondsFormat, Utc};
+use shared_types::{GlobalSummary, ProviderDrift, Summary, SummaryDrift};
+use tracing::{error, info, warn};
+
+use payment_core::{CurrentProvider, PaymentServiceDTO};
+
+use crate::{
+    ProviderHandler,
+    admin::{self, PaymentSummaryResponse},
+    db::DbClient,
+};
+
+#[derive(Clone, Debug)]
+pub struct ReconcileConfig {
+    /// How often local totals are compared with the processors. `None` disables the job,
+    /// which only needs to run on one api worker since both read the same totals.
+    pub interval: Option<Duration>,
+    /// How far behind now the compared window ends, so in-flight payments and batched DB
+    /// writes have settled.
+    pub lag: Duration,
+    /// Look up payments whose provider call timed out and record the ones the provider did
+    /// process.
+    pub repair: bool,
+    pub journal_size: usize,
+}
+
+impl ReconcileConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let interval: u64 = env::var("RECONCILE_INTERVAL_MS")
+            .unwrap_or("0".to_string())
+            .parse()?;
+
+        Ok(Self {
+            interval: (interval > 0).then(|| Duration::from_millis(interval)),
+            lag: Duration::from_millis(
+                env::var("RECONCILE_LAG_MS")
+                    .unwrap_or("2000".to_string())
+                    .parse()?,
+            ),
+            repair: env::var("RECONCILE_REPAIR")
+                .unwrap_or("false".to_string())
+                .parse()?,
+            journal_size: env::var("RECONCILE_JOURNAL_SIZE")
+                .unwrap_or("10000".to_string())
+                .parse()?,
+        })
+    }
+
+    /// Size of the uncertain payment journal, zero unless repairs will read it.
+    pub fn journal_capacity(&self) -> usize {
+        if self.interval.is_some() && self.repair {
+            self.journal_size
+        } else {
+            0
+        }
+    }
+}
+
+struct Uncertain {
+    provider: CurrentProvider,
+    payment: PaymentServiceDTO,
+    at: Instant,
+}
+
+/// Payments whose provider call timed out. The provider may have processed them anyway,
+/// which shows up as drift unless they are looked up and recorded.
+pub struct UncertainJournal {
+    capacity: usize,
+    entries: Mutex<VecDeque<Uncertain>>,
+}
+
+impl UncertainJournal {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn push(&self, provider: CurrentProvider, payment: &PaymentServiceDTO) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(Uncertain {
+            provider,
+            payment: payment.clone(),
+            at: Instant::now(),
+        });
+    }
+
+    /// Remove the entries recorded more than `age` ago.
+    fn take_older_than(&self, age: Duration) -> Vec<Uncertain> {
+        let mut entries = self.entries.lock().unwrap();
+        let n = entries
+            .iter()
+            .take_while(|entry| entry.at.elapsed() >= age)
+            .count();
+        entries.drain(..n).collect()
+    }
+
+    fn restore(&self, entry: Uncertain) {
+        self.entries.lock().unwrap().push_front(entry);
+    }
+}
+
+/// Start the reconciliation job if `RECONCILE_INTERVAL_MS` is set.
+pub fn spawn(handler: Arc<ProviderHandler>) -> anyhow::Result<()> {
+    let Some(interval) = handler.reconcile.interval else {
+        return Ok(());
+    };
+    let db = DbClient::from_env(handler.client.clone())?;
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+
+            if handler.reconcile.repair {
+                let repaired = repair(&handler).await;
+                if repaired > 0 {
+                    info!("Reconciliation recorded {repaired} payments after timeouts");
+                }
+            }
+
+            match compare(&handler, &db, interval).await {
+                Ok(drift) => {
+                    if drift.default.requests != 0 || drift.fallback.requests != 0 {
+                        warn!("Drift against the processors: {drift:?}");
+                    }
+                    handler.metrics.record_drift(drift);
+                }
+                Err(e) => error!("Reconciliation failed: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Compare the last `window` (ending `lag` ago) of local totals with the processors' own.
+async fn compare(
+    handler: &ProviderHandler,
+    db: &DbClient,
+    window: Duration,
+) -> anyhow::Result<SummaryDrift> {
+    let to = Utc::now() - handler.reconcile.lag;
+    let from = to - window;
+    let from = from.to_rfc3339_opts(SecondsFormat::Millis, true);
+    let to = to.to_rfc3339_opts(SecondsFormat::Millis, true);
+
+    let (local, default, fallback) = tokio::join!(
+        db.summary(&from, &to),
+        admin::fetch_summary(handler, CurrentProvider::Default, &from, &to),
+        admin::fetch_summary(handler, CurrentProvider::Fallback, &from, &to),
+    );
+    let local: GlobalSummary = local?;
+
+    Ok(SummaryDrift {
+        default: drift(&default?, &local.default),
+        fallback: drift(&fallback?, &local.fallback),
+    })
+}
+
+fn drift(remote: &PaymentSummaryResponse, local: &Summary) -> ProviderDrift {
+    ProviderDrift {
+        requests: remote.total_requests as i64 - local.total_requests as i64,
+        amount: remote.total_amount - local.total_amount,
+    }
+}
+
+/// Look up every settled uncertain payment and record the ones the provider has.
+async fn repair(handler: &ProviderHandler) -> usize {
+    let mut repaired = 0;
+    for entry in handler.uncertain.take_older_than(handler.reconcile.lag) {
+        let correlation_id = entry.payment.correlation_id;
+        match admin::has_payment(handler, entry.provider, &correlation_id).await {
+            Ok(true) => match handler.store(entry.provider, &entry.payment).await {
+                Ok(()) => repaired += 1,
+                Err(e) => error!("Failed to record repaired payment {correlation_id}: {e}"),
+            },
+            Ok(false) => {}
+            Err(e) => {
+                error!("Failed to look up payment {correlation_id}: {e}");
+                handler.uncertain.restore(entry);
+                break;
+            }
+        }
+    }
+    repaired
+}
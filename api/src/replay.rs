@@ -0,0 +1,82 @@
+use std::collections::HashSet;
+
+use shared_types::{PaymentRecord, SledTree};
+use tracing::{error, info};
+use uuid::Uuid;
+
+use payment_core::{CurrentProvider, PaymentServiceDTO, error::ProviderError};
+
+use crate::{ProviderHandler, db::DbClient};
+
+/// Resend every payment rinha-db recorded between `from` and `to` to the provider it was
+/// recorded against, for recovering a window the provider lost. Payments the provider already
+/// has come back as duplicates and are left alone, and nothing is written to rinha-db.
+///
+/// Run with `api --replay <from>..<to>`.
+pub async fn run(handler: &ProviderHandler, range: &str) -> anyhow::Result<()> {
+    let Some((from, to)) = range.split_once("..") else {
+        anyhow::bail!("expected --replay <from>..<to>, got {range:?}");
+    };
+
+    let db = DbClient::from_env(handler.client.clone())?;
+    let records = db.records(from, to).await?;
+    // Cancelled payments must not be charged again.
+    let voided: HashSet<Option<String>> = records
+        .iter()
+        .filter(|record| record.voided)
+        .map(|record| record.correlation_id.clone())
+        .collect();
+    let records: Vec<PaymentRecord> = records
+        .into_iter()
+        .filter(|record| !voided.contains(&record.correlation_id))
+        .collect();
+    info!("Replaying {} payments from {from} to {to}", records.len());
+
+    let (mut sent, mut duplicates, mut failed, mut skipped) = (0, 0, 0, 0);
+    for record in records {
+        let Some((provider, payment)) = payment(record) else {
+            skipped += 1;
+            continue;
+        };
+
+        match handler.send(&provider, &payment, &payment.to_body()?).await {
+            Ok(()) => sent += 1,
+            Err(ProviderError::Duplicate) => duplicates += 1,
+            Err(e) => {
+                error!("Failed to replay payment {}: {e:?}", payment.correlation_id);
+                failed += 1;
+            }
+        }
+    }
+
+    info!(
+        "Replay done: {sent} resent, {duplicates} already processed, {failed} failed, {skipped} \
+         without a correlation id or a provider to send them to"
+    );
+    if failed > 0 {
+        anyhow::bail!("{failed} payments could not be replayed");
+    }
+    Ok(())
+}
+
+/// Records stored before keys carried the correlation id, or under a provider the workers
+/// don't route to, can't be replayed.
+fn payment(record: PaymentRecord) -> Option<(CurrentProvider, PaymentServiceDTO)> {
+    let correlation_id = Uuid::parse_str(record.correlation_id.as_deref()?).ok()?;
+    let provider = if record.tree == SledTree::DEFAULT {
+        CurrentProvider::Default
+    } else if record.tree == SledTree::FALLBACK {
+        CurrentProvider::Fallback
+    } else {
+        return None;
+    };
+    Some((
+        provider,
+        PaymentServiceDTO {
+            correlation_id,
+            amount: record.amount,
+            requested_at: record.requested_at,
+            trace_id: None,
+        },
+    ))
+}
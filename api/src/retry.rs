@@ -0,0 +1,105 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use payment_core::retry::RetryPolicy;
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::time::DelayQueue;
+use tracing::warn;
+
+use crate::{QueuedPayment, lanes::LaneSender};
+
+enum Command {
+    Schedule(QueuedPayment),
+    Clear,
+    /// Stop the queue, replying with the number of payments it dropped.
+    Shutdown(oneshot::Sender<usize>),
+}
+
+/// Parks payments that every provider rejected and re-enqueues them for the workers after a
+/// capped exponential backoff.
+#[derive(Clone)]
+pub struct RetryQueue {
+    tx: mpsc::UnboundedSender<Command>,
+    config: RetryPolicy,
+    abandoned: Arc<AtomicUsize>,
+}
+
+impl RetryQueue {
+    pub fn spawn(config: RetryPolicy, work_tx: LaneSender) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run(config, rx, work_tx));
+        Self {
+            tx,
+            config,
+            abandoned: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Schedule another attempt, or count the payment as lost once it ran out of attempts.
+    /// Returns false only for lost payments.
+    pub fn schedule(&self, mut payment: QueuedPayment) -> bool {
+        payment.attempt += 1;
+        if payment.attempt >= self.config.max_attempts {
+            warn!(
+                "Payment {} lost after {} attempts",
+                payment.payment.correlation_id, payment.attempt
+            );
+            return false;
+        }
+
+        // Once stopped, payments are left in the spill queue for the next start.
+        if self.tx.send(Command::Schedule(payment)).is_err() {
+            self.abandoned.fetch_add(1, Ordering::Relaxed);
+        }
+        true
+    }
+
+    /// Drop every parked payment, used by purge.
+    pub fn clear(&self) {
+        let _ = self.tx.send(Command::Clear);
+    }
+
+    /// Stop re-enqueueing payments. Parked payments and any scheduled afterwards are counted
+    /// as abandoned.
+    pub async fn shutdown(&self) {
+        let (done_tx, done_rx) = oneshot::channel();
+        if self.tx.send(Command::Shutdown(done_tx)).is_ok() {
+            if let Ok(dropped) = done_rx.await {
+                self.abandoned.fetch_add(dropped, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Payments dropped since `shutdown`.
+    pub fn abandoned(&self) -> usize {
+        self.abandoned.load(Ordering::Relaxed)
+    }
+}
+
+async fn run(config: RetryPolicy, mut rx: mpsc::UnboundedReceiver<Command>, work_tx: LaneSender) {
+    let mut queue: DelayQueue<QueuedPayment> = DelayQueue::new();
+
+    loop {
+        tokio::select! {
+            command = rx.recv() => match command {
+                Some(Command::Schedule(payment)) => {
+                    let delay = config.delay(payment.attempt);
+                    queue.insert(payment, delay);
+                }
+                Some(Command::Clear) => queue.clear(),
+                Some(Command::Shutdown(done)) => {
+                    let _ = done.send(queue.len());
+                    return;
+                }
+                None => return,
+            },
+            Some(expired) = std::future::poll_fn(|cx| queue.poll_expired(cx)) => {
+                if work_tx.send(expired.into_inner()).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
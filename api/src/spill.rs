@@ -0,0 +1,65 @@
+use std::path::Path;
+
+use shared_types::PaymentDTO;
+use tracing::error;
+use uuid::Uuid;
+
+/// On-disk copy of every payment accepted from the gateway but not yet settled, so a crashed
+/// worker can replay them on startup. A no-op when `QUEUE_PATH` is unset.
+#[derive(Clone)]
+pub struct SpillQueue {
+    tree: Option<sled::Tree>,
+}
+
+impl SpillQueue {
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let db = sled::open(path)?;
+        Ok(Self {
+            tree: Some(db.open_tree("inflight")?),
+        })
+    }
+
+    pub fn disabled() -> Self {
+        Self { tree: None }
+    }
+
+    pub fn push(&self, payment: &PaymentDTO) -> anyhow::Result<()> {
+        if let Some(tree) = &self.tree {
+            tree.insert(
+                payment.correlation_id.as_bytes(),
+                serde_json::to_vec(payment)?,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Forget a payment once it was processed, lost or purged.
+    pub fn remove(&self, correlation_id: &Uuid) {
+        if let Some(tree) = &self.tree {
+            if let Err(e) = tree.remove(correlation_id.as_bytes()) {
+                error!("Failed to remove {correlation_id} from spill queue: {e}");
+            }
+        }
+    }
+
+    pub fn clear(&self) {
+        if let Some(tree) = &self.tree {
+            if let Err(e) = tree.clear() {
+                error!("Failed to clear spill queue: {e}");
+            }
+        }
+    }
+
+    /// Payments left over from a previous run.
+    pub fn pending(&self) -> Vec<PaymentDTO> {
+        let Some(tree) = &self.tree else {
+            return Vec::new();
+        };
+
+        tree.iter()
+            .values()
+            .filter_map(Result::ok)
+            .filter_map(|value| serde_json::from_slice(&value).ok())
+            .collect()
+    }
+}
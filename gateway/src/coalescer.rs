@@ -0,0 +1,183 @@
+use std::{io::IoSlice, sync::Arc, time::Duration};
+
+use shared_types::{ApiReply, UnixConnectionPool};
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
+
+use crate::{control, delivery::Delivery};
+
+type Pending = (
+    Vec<u8>,
+    Arc<Delivery>,
+    oneshot::Sender<anyhow::Result<ApiReply>>,
+);
+
+/// Buffers frames for a single backend and writes them together with one vectored write.
+#[derive(Clone)]
+pub struct Coalescer {
+    tx: mpsc::Sender<Pending>,
+}
+
+impl Coalescer {
+    /// Spawn the writer task for `pool`. A batch is written once it holds `max_frames`
+    /// frames or `window` has elapsed since its first frame arrived.
+    pub fn spawn(pool: Arc<UnixConnectionPool>, max_frames: usize, window: Duration) -> Self {
+        let (tx, rx) = mpsc::channel(max_frames * 4);
+        tokio::spawn(run(pool, rx, max_frames, window));
+        Self { tx }
+    }
+
+    /// Queue a frame and wait for the backend's reply to it. A frame `delivery` was
+    /// abandoned for by the time its batch is written is left out.
+    pub async fn send(&self, frame: Vec<u8>, delivery: Arc<Delivery>) -> anyhow::Result<ApiReply> {
+        let (done_tx, done_rx) = oneshot::channel();
+        self.tx
+            .send((frame, delivery, done_tx))
+            .await
+            .map_err(|_| anyhow::anyhow!("coalescer stopped"))?;
+        done_rx.await?
+    }
+}
+
+async fn run(
+    pool: Arc<UnixConnectionPool>,
+    mut rx: mpsc::Receiver<Pending>,
+    max_frames: usize,
+    window: Duration,
+) {
+    let mut batch: Vec<Pending> = Vec::with_capacity(max_frames);
+
+    while let Some(first) = rx.recv().await {
+        batch.push(first);
+        let deadline = Instant::now() + window;
+
+        while batch.len() < max_frames {
+            match tokio::time::timeout_at(deadline, rx.recv()).await {
+                Ok(Some(pending)) => batch.push(pending),
+                _ => break,
+            }
+        }
+
+        batch.retain(|(_, delivery, _)| delivery.start());
+        if batch.is_empty() {
+            continue;
+        }
+        match write_batch(&pool, &batch).await {
+            Ok(replies) => {
+                for ((_, _, done), reply) in batch.drain(..).zip(replies) {
+                    let _ = done.send(Ok(reply));
+                }
+            }
+            Err(e) => {
+                for (_, _, done) in batch.drain(..) {
+                    let _ = done.send(Err(anyhow::anyhow!("{e}")));
+                }
+            }
+        }
+    }
+}
+
+/// Write the whole batch, then read one reply per frame. The api worker answers frames in
+/// the order it reads them, so replies line up with the batch. Each frame is marked written
+/// as soon as its last byte is, since a write failing later leaves it with the worker.
+async fn write_batch(
+    pool: &UnixConnectionPool,
+    batch: &[Pending],
+) -> anyhow::Result<Vec<ApiReply>> {
+    let Some(mut stream) = pool.acquire().await?.take() else {
+        anyhow::bail!("pooled connection was already taken");
+    };
+
+    let mut slices: Vec<IoSlice> = batch.iter().map(|(f, _, _)| IoSlice::new(f)).collect();
+    let mut bufs = &mut slices[..];
+    // Bytes written so far, and how many frames they cover whole, ending at `covered`.
+    let (mut written, mut frames, mut covered) = (0, 0, 0);
+    while !bufs.is_empty() {
+        let n = stream.write_vectored(bufs).await?;
+        if n == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into());
+        }
+        IoSlice::advance_slices(&mut bufs, n);
+        written += n;
+        while let Some((frame, delivery, _)) = batch.get(frames) {
+            if covered + frame.len() > written {
+                break;
+            }
+            covered += frame.len();
+            frames += 1;
+            delivery.written();
+        }
+    }
+    stream.flush().await?;
+
+    let mut reader = BufReader::new(&mut stream);
+    let mut replies = Vec::with_capacity(batch.len());
+    for _ in batch {
+        replies.push(control::read_reply(&mut reader).await?);
+    }
+
+    pool.return_connection(stream);
+    Ok(replies)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use tokio::{io::AsyncBufReadExt, net::UnixListener};
+
+    use super::*;
+
+    /// A backend that reads frames and never answers them, passing on each one it read.
+    fn silent_backend(name: &str) -> (Coalescer, mpsc::UnboundedReceiver<String>) {
+        let path = env::temp_dir().join(format!("gateway-{name}-{}.sock", process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut lines = BufReader::new(stream).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let _ = tx.send(line);
+            }
+        });
+        let pool = Arc::new(UnixConnectionPool::new_lazy(&path, 1));
+        (Coalescer::spawn(pool, 2, Duration::from_millis(10)), rx)
+    }
+
+    async fn send(coalescer: &Coalescer, frame: &str, delivery: &Arc<Delivery>) {
+        let send = coalescer.send(format!("{frame}\n").into_bytes(), delivery.clone());
+        let _ = tokio::time::timeout(Duration::from_millis(200), send).await;
+    }
+
+    #[tokio::test]
+    async fn frames_written_without_a_reply_reached_the_backend() {
+        let (coalescer, mut frames) = silent_backend("unanswered");
+        let deliveries = [(); 2].map(|_| Arc::new(Delivery::default()));
+        tokio::join!(
+            send(&coalescer, "a", &deliveries[0]),
+            send(&coalescer, "b", &deliveries[1])
+        );
+        for delivery in &deliveries {
+            assert!(delivery.reached_backend());
+            assert!(!delivery.abandon());
+        }
+        assert_eq!(frames.recv().await.unwrap(), "a");
+        assert_eq!(frames.recv().await.unwrap(), "b");
+    }
+
+    #[tokio::test]
+    async fn abandoned_frames_are_never_written() {
+        let (coalescer, mut frames) = silent_backend("abandoned");
+        let abandoned = Arc::new(Delivery::default());
+        assert!(abandoned.abandon());
+        send(&coalescer, "abandoned", &abandoned).await;
+        assert!(!abandoned.reached_backend());
+
+        send(&coalescer, "sent", &Arc::new(Delivery::default())).await;
+        assert_eq!(frames.recv().await.unwrap(), "sent");
+    }
+}
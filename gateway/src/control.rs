@@ -0,0 +1,70 @@
+use std::{
+    sync::{Arc, atomic::Ordering},
+    time::Duration,
+};
+
+use shared_types::{ApiFrame, ApiReply, UnixConnectionPool};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+};
+
+use crate::BackendStats;
+
+/// Send a control frame on a dedicated connection and wait for the reply.
+pub async fn request(pool: &UnixConnectionPool, frame: &ApiFrame) -> anyhow::Result<ApiReply> {
+    let stream = pool.connect().await?;
+    exchange(&mut BufReader::new(stream), frame).await
+}
+
+/// Periodically ask a backend for its queue depth over a long-lived connection.
+pub fn spawn_depth_poller(
+    pool: Arc<UnixConnectionPool>,
+    stats: Arc<BackendStats>,
+    interval: Duration,
+) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        let mut conn: Option<BufReader<UnixStream>> = None;
+
+        loop {
+            ticker.tick().await;
+
+            let stream = match conn.as_mut() {
+                Some(stream) => stream,
+                None => match pool.connect().await {
+                    Ok(stream) => conn.insert(BufReader::new(stream)),
+                    Err(_) => continue,
+                },
+            };
+
+            match exchange(stream, &ApiFrame::Depth).await {
+                Ok(ApiReply::Depth { queued }) => {
+                    stats.queue_depth.store(queued, Ordering::Relaxed)
+                }
+                Ok(_) => {}
+                Err(_) => conn = None,
+            }
+        }
+    });
+}
+
+async fn exchange(
+    stream: &mut BufReader<UnixStream>,
+    frame: &ApiFrame,
+) -> anyhow::Result<ApiReply> {
+    let mut buf = serde_json::to_vec(frame)?;
+    buf.push(b'\n');
+    stream.get_mut().write_all(&buf).await?;
+    stream.get_mut().flush().await?;
+    read_reply(stream).await
+}
+
+/// Read the next newline-delimited reply from a backend.
+pub async fn read_reply<R: AsyncBufRead + Unpin>(reader: &mut R) -> anyhow::Result<ApiReply> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        anyhow::bail!("connection closed");
+    }
+    Ok(serde_json::from_str(&line)?)
+}
@@ -0,0 +1,40 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+const QUEUED: u8 = 0;
+const WRITING: u8 = 1;
+const WRITTEN: u8 = 2;
+const ABANDONED: u8 = 3;
+
+/// How far a payment frame got towards a backend, shared by whoever writes it and the handler
+/// waiting for the reply. Only a frame that never reached the backend may be sent to the other
+/// one: the api worker dedups by correlation id on its own, so a payment it took and the other
+/// worker got as well would be processed twice.
+#[derive(Default)]
+pub struct Delivery(AtomicU8);
+
+impl Delivery {
+    /// Claim the frame for writing, false if the handler already gave up on it.
+    pub fn start(&self) -> bool {
+        self.0
+            .compare_exchange(QUEUED, WRITING, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+
+    /// The whole frame was handed to the backend, whether or not it replies.
+    pub fn written(&self) {
+        self.0.store(WRITTEN, Ordering::Release);
+    }
+
+    /// Give up on a frame that timed out, true if it was never claimed and so will never be
+    /// written.
+    pub fn abandon(&self) -> bool {
+        self.0
+            .compare_exchange(QUEUED, ABANDONED, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+
+    /// Whether a failed exchange may still have left the payment with the backend.
+    pub fn reached_backend(&self) -> bool {
+        self.0.load(Ordering::Acquire) == WRITTEN
+    }
+}
@@ -1,41 +1,83 @@
-use reqwest::{Client, Request};
-use serde::{Deserialize, Serialize};
+mod coalescer;
+mod control;
+mod delivery;
+mod error;
+mod serve;
+
+use chrono::Utc;
+use coalescer::Coalescer;
+use delivery::Delivery;
+use error::ApiError;
+use reqwest::Client;
+use serde::Serialize;
+use serve::Protocol;
 use std::{
     collections::HashMap,
+    env,
     path::Path,
     sync::{
-        Arc,
+        Arc, Mutex,
         atomic::{AtomicU64, Ordering},
     },
+    time::Duration,
 };
-use uuid::Uuid;
+use tracing::{Span, error, info, warn};
 
-use anyhow;
 use axum::{
     Json, Router,
-    extract::{Query, State},
-    http::{HeaderMap, StatusCode},
-    response::IntoResponse,
+    body::Body,
+    extract::{self, Query, Request, State, rejection::JsonRejection},
+    http::{HeaderMap, StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
     routing::{get, post},
 };
-use shared_types::{self, GlobalSummary, PaymentDTO, UnixConnectionPool};
+use shared_types::{
+    self, ApiFrame, ApiReply, CancelOutcome, GlobalSummary, PaymentDTO, UnixConnectionPool,
+    WorkerMetrics, WorkerStatus, logging,
+};
 use tokio::{
-    self,
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::UnixStream,
+    io::{AsyncWriteExt, BufReader},
+    task::JoinSet,
 };
+use uuid::Uuid;
 
 #[derive(Clone)]
 struct AppState {
     db_client: Client,
+    /// rinha-db base URLs; the first one is the primary and receives writes.
+    db_urls: Arc<[String]>,
+    /// Replication position of the primary's latest summary, which a replica's must reach.
+    primary_position: Arc<AtomicU64>,
+    db_timeout: Duration,
+    hedge_delay: Duration,
+    backend_timeout: Duration,
+    /// Drain the api workers' pending DB writes before reading a summary.
+    flush_before_summary: bool,
     api_pool: [Arc<UnixConnectionPool>; 2],
+    coalescers: Option<[Coalescer; 2]>,
+    stats: [Arc<BackendStats>; 2],
     balancer: Arc<AtomicU64>,
+    token: Option<Arc<str>>,
+}
+
+/// Live counters for a single api backend, reported by `/admin/state`.
+#[derive(Default)]
+struct BackendStats {
+    in_flight: AtomicU64,
+    queue_depth: AtomicU64,
+    last_error: Mutex<Option<String>>,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    shared_types::logging::init("gateway")?;
     let mut headers = HeaderMap::new();
     headers.insert("Content-Type", "application/json".parse()?);
+    // rinha-db's HTTP endpoints may want their own token.
+    if let Ok(token) = env::var("DB_TOKEN") {
+        headers.insert("X-Rinha-Token", token.parse()?);
+    }
     // TODO: Tweak Client config
     let db_client = Client::builder()
         .no_gzip()
@@ -43,22 +85,120 @@ async fn main() -> anyhow::Result<()> {
         .default_headers(headers.clone())
         .build()?;
 
+    // The sockets the two api workers listen on, see their `API_PATH`.
+    let api_paths = env::var("API_PATHS").unwrap_or("/tmp/api-1.sock,/tmp/api-2.sock".to_string());
+    let &[first, second] = &api_paths.split(',').map(str::trim).collect::<Vec<_>>()[..] else {
+        anyhow::bail!("invalid API_PATHS {api_paths:?}, expected two comma-separated paths");
+    };
+    let api_pool = [
+        Arc::new(UnixConnectionPool::new(Path::new(first), 200).await?),
+        Arc::new(UnixConnectionPool::new(Path::new(second), 200).await?),
+    ];
+
+    // Frame coalescing is opt-in: BATCH_MAX_FRAMES > 1 enables it.
+    let batch_max_frames: usize = env::var("BATCH_MAX_FRAMES")
+        .unwrap_or("1".to_string())
+        .parse()?;
+    let batch_window_us: u64 = env::var("BATCH_WINDOW_US")
+        .unwrap_or("1000".to_string())
+        .parse()?;
+    let coalescers = (batch_max_frames > 1).then(|| {
+        api_pool.clone().map(|pool| {
+            Coalescer::spawn(
+                pool,
+                batch_max_frames,
+                Duration::from_micros(batch_window_us),
+            )
+        })
+    });
+
+    let stats: [Arc<BackendStats>; 2] = Default::default();
+
+    // Queue depth reports drive routing; DEPTH_POLL_MS=0 falls back to in-flight counts only.
+    let depth_poll_ms: u64 = env::var("DEPTH_POLL_MS")
+        .unwrap_or("50".to_string())
+        .parse()?;
+    if depth_poll_ms > 0 {
+        for (pool, stats) in api_pool.iter().zip(&stats) {
+            control::spawn_depth_poller(
+                Arc::clone(pool),
+                Arc::clone(stats),
+                Duration::from_millis(depth_poll_ms),
+            );
+        }
+    }
+
     // HTTP router
-    let app = Router::new()
+    let db_urls: Arc<[String]> = env::var("DB_URLS")
+        .unwrap_or("http://rinha-db:8888".to_string())
+        .split(',')
+        .map(|url| url.trim().to_string())
+        .collect();
+    let hedge_delay_ms: u64 = env::var("HEDGE_DELAY_MS")
+        .unwrap_or("5".to_string())
+        .parse()?;
+    let db_timeout_ms: u64 = env::var("DB_TIMEOUT_MS")
+        .unwrap_or("2000".to_string())
+        .parse()?;
+    let backend_timeout_ms: u64 = env::var("BACKEND_TIMEOUT_MS")
+        .unwrap_or("1000".to_string())
+        .parse()?;
+
+    let state = AppState {
+        db_client,
+        db_urls,
+        primary_position: Arc::new(AtomicU64::new(0)),
+        db_timeout: Duration::from_millis(db_timeout_ms),
+        hedge_delay: Duration::from_millis(hedge_delay_ms),
+        backend_timeout: Duration::from_millis(backend_timeout_ms),
+        flush_before_summary: env::var("SUMMARY_FLUSH")
+            .unwrap_or("true".to_string())
+            .parse()?,
+        api_pool,
+        coalescers,
+        stats,
+        balancer: Arc::new(AtomicU64::new(0)),
+        token: env::var("RINHA_TOKEN").ok().map(Into::into),
+    };
+    let auth_all_routes = env::var("AUTH_ALL_ROUTES").is_ok_and(|v| v == "true");
+
+    let public = Router::new()
         .route("/payments-summary", get(get_payments_summary))
         .route("/payments", post(exec_payment))
-        .route("/purge-payments", post(purge_payments))
-        .with_state(AppState {
-            db_client,
-            api_pool: [
-                Arc::new(UnixConnectionPool::new(Path::new("/tmp/api-1.sock"), 200).await?),
-                Arc::new(UnixConnectionPool::new(Path::new("/tmp/api-2.sock"), 200).await?),
-            ],
-            balancer: Arc::new(AtomicU64::new(0)),
-        });
+        .route("/payments-export", get(export_payments));
+    let mut protected = Router::new().route("/purge-payments", post(purge_payments));
+    // Admin routes are only exposed when a token is configured.
+    if state.token.is_some() {
+        protected = protected
+            .route("/admin/state", get(admin_state))
+            .route("/admin/payments/{id}/cancel", post(cancel_payment));
+    }
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:9999").await?;
-    axum::serve(listener, app).await?;
+    let auth = middleware::from_fn_with_state(state.clone(), require_token);
+    let app = if auth_all_routes {
+        public.merge(protected).route_layer(auth)
+    } else {
+        public.merge(protected.route_layer(auth))
+    }
+    .with_state(state);
+
+    // LISTEN=unix:/path serves over a unix socket for a reverse proxy in the same pod.
+    let listen = env::var("LISTEN").unwrap_or("0.0.0.0:9999".to_string());
+    let protocol: Protocol = env::var("HTTP_PROTOCOL")
+        .unwrap_or("http1".to_string())
+        .parse()?;
+    if let Some(path) = listen.strip_prefix("unix:") {
+        if Path::new(path).exists() {
+            std::fs::remove_file(path)?;
+        }
+        let listener = tokio::net::UnixListener::bind(path)?;
+        info!("Gateway listening on unix:{path} ({protocol:?})");
+        serve::serve(listener, app, protocol).await;
+    } else {
+        let listener = tokio::net::TcpListener::bind(listen.as_str()).await?;
+        info!("Gateway listening on {listen} ({protocol:?})");
+        serve::serve(listener, app, protocol).await;
+    }
 
     Ok(())
 }
@@ -66,7 +206,7 @@ async fn main() -> anyhow::Result<()> {
 async fn get_payments_summary(
     Query(params): Query<HashMap<String, String>>,
     State(state): State<AppState>,
-) -> impl IntoResponse {
+) -> Result<Json<GlobalSummary>, ApiError> {
     let from = params
         .get("from")
         .cloned()
@@ -76,56 +216,431 @@ async fn get_payments_summary(
         .cloned()
         .unwrap_or_else(|| "9999-12-31T23:59:59Z".to_string());
 
+    if state.flush_before_summary {
+        flush_backends(&state).await;
+    }
+
+    match fetch_summary(&state, &from, &to).await {
+        Ok(summary) => Ok(Json(summary)),
+        Err(e) => {
+            error!("Failed to fetch summary: {e}");
+            Err(ApiError::backend(&e))
+        }
+    }
+}
+
+/// Have both backends write the records they batched for rinha-db, so a summary includes every
+/// payment already acknowledged. A backend that can't is logged and the summary served anyway.
+async fn flush_backends(state: &AppState) {
+    let flush = |pool| async move {
+        match tokio::time::timeout(state.backend_timeout, flush_backend(pool)).await {
+            Ok(res) => res,
+            Err(elapsed) => Err(elapsed.into()),
+        }
+    };
+    let flushed = tokio::join!(flush(&state.api_pool[0]), flush(&state.api_pool[1]));
+    for (idx, res) in [flushed.0, flushed.1].into_iter().enumerate() {
+        match res {
+            Ok(0) => {}
+            Ok(unwritten) => warn!("api-{} still has {unwritten} unwritten records", idx + 1),
+            Err(e) => error!("Failed to flush api-{}: {e}", idx + 1),
+        }
+    }
+}
+
+async fn flush_backend(pool: &UnixConnectionPool) -> anyhow::Result<u64> {
+    match control::request(pool, &ApiFrame::Flush).await? {
+        ApiReply::Flushed { unwritten } => Ok(unwritten),
+        reply => anyhow::bail!("unexpected reply to flush: {reply:?}"),
+    }
+}
+
+/// Query every rinha-db instance, starting each one `hedge_delay` after the previous. The
+/// primary's answer is served whenever it comes. A replica's is only served when the
+/// primary's failed, and only if the replica applied every change the primary had logged by
+/// its latest summary, so a summary never goes back in time.
+async fn fetch_summary(state: &AppState, from: &str, to: &str) -> anyhow::Result<GlobalSummary> {
+    let mut requests = JoinSet::new();
+    for (i, base) in state.db_urls.iter().enumerate() {
+        let request = state
+            .db_client
+            .get(format!("{base}/summary"))
+            .query(&[("from", from), ("to", to)])
+            .timeout(state.db_timeout);
+        let delay = state.hedge_delay * i as u32;
+        requests.spawn(async move {
+            tokio::time::sleep(delay).await;
+            (i, db_summary(request).await)
+        });
+    }
+
+    let mut last_error = anyhow::anyhow!("no rinha-db instances configured");
+    // A replica's answer held until the primary's fails.
+    let mut fallback = None;
+    let mut primary_failed = false;
+    while let Some(res) = requests.join_next().await {
+        // Dropping the set aborts the requests still in flight.
+        match res {
+            Ok((0, Ok((position, summary)))) => {
+                state
+                    .primary_position
+                    .fetch_max(position, Ordering::Relaxed);
+                return Ok(summary);
+            }
+            Ok((i, Ok((position, summary)))) => {
+                let reached = state.primary_position.load(Ordering::Relaxed);
+                if position < reached {
+                    warn!(
+                        "rinha-db replica {i} is at entry {position}, behind the primary's {reached}"
+                    );
+                    last_error = anyhow::anyhow!("replica {i} is behind the primary");
+                } else if primary_failed {
+                    return Ok(summary);
+                } else {
+                    fallback.get_or_insert(summary);
+                }
+            }
+            Ok((i, Err(e))) => {
+                if i == 0 {
+                    primary_failed = true;
+                    if let Some(summary) = fallback.take() {
+                        return Ok(summary);
+                    }
+                }
+                last_error = e;
+            }
+            Err(e) => last_error = e.into(),
+        }
+    }
+
+    fallback.ok_or(last_error)
+}
+
+/// A rinha-db summary with the replication position it covers, 0 when it doesn't replicate.
+async fn db_summary(request: reqwest::RequestBuilder) -> anyhow::Result<(u64, GlobalSummary)> {
+    let res = request.send().await?.error_for_status()?;
+    let position = res
+        .headers()
+        .get("X-Replication-Position")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    Ok((position, res.json().await?))
+}
+
+/// Proxy rinha-db's ndjson export, forwarding chunks as they arrive.
+async fn export_payments(
+    Query(params): Query<HashMap<String, String>>,
+    State(state): State<AppState>,
+) -> Result<Response, ApiError> {
     let res = state
         .db_client
-        .get(format!(
-            "http://rinha-db:8888/summary?from={}&to={}",
-            from, to
-        ))
+        .get(format!("{}/export", state.db_urls[0]))
+        .query(&params)
         .send()
-        .await;
+        .await
+        .and_then(|res| res.error_for_status());
 
     match res {
-        Ok(res) => {
-            let summary = res.json::<GlobalSummary>().await.unwrap();
-            (StatusCode::OK, Json(summary))
+        Ok(res) => Ok((
+            [(header::CONTENT_TYPE, "application/x-ndjson")],
+            Body::from_stream(res.bytes_stream()),
+        )
+            .into_response()),
+        Err(e) => {
+            error!("Failed to export payments: {e}");
+            Err(ApiError::backend(&e.into()))
         }
-        Err(_) => todo!(),
     }
 }
 
+#[tracing::instrument(level = "debug", skip_all, fields(correlation_id, trace_id))]
 async fn exec_payment(
     State(state): State<AppState>,
-    Json(payload): Json<PaymentDTO>,
-) -> impl IntoResponse {
-    let api_pool = if (state.balancer.fetch_add(1, Ordering::Relaxed) & 1) == 0 {
-        &state.api_pool[0]
-    } else {
-        &state.api_pool[1]
+    headers: HeaderMap,
+    payload: Result<Json<PaymentDTO>, JsonRejection>,
+) -> Result<StatusCode, ApiError> {
+    let Json(mut payload) = payload.map_err(|e| ApiError::validation(e.body_text()))?;
+    let correlation_id = payload.correlation_id;
+    payload.trace_id = headers
+        .get("x-trace-id")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let span = Span::current();
+    span.record("correlation_id", tracing::field::display(correlation_id));
+    span.record("trace_id", payload.trace_id.as_deref());
+    logging::follow(
+        &span,
+        headers.get("traceparent").and_then(|v| v.to_str().ok()),
+    );
+    payload.traceparent = logging::traceparent(&span);
+    if !(payload.amount.is_finite() && payload.amount > 0.0) {
+        return Err(ApiError::validation("amount must be a positive number")
+            .with_correlation_id(correlation_id));
+    }
+
+    payload.requested_at = Some(Utc::now().to_rfc3339());
+    // Only the header marks a payment as high priority, whatever the body says.
+    payload.priority = headers
+        .get("x-payment-priority")
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"high"));
+
+    let mut frame = serde_json::to_vec(&ApiFrame::Payment(payload))
+        .map_err(|e| ApiError::validation(e.to_string()).with_correlation_id(correlation_id))?;
+    frame.push(b'\n');
+
+    // Start on the less loaded backend and fail over to the other one if the payment is
+    // nacked or never reached it. Once written it may be queued there even without a reply,
+    // and sending it again could have both workers process it.
+    let first = pick_backend(&state);
+    let mut last_error = None;
+    for idx in [first, first ^ 1] {
+        let stats = &state.stats[idx];
+        stats.in_flight.fetch_add(1, Ordering::Relaxed);
+        let delivery = Arc::new(Delivery::default());
+        let send = async {
+            match &state.coalescers {
+                Some(coalescers) => coalescers[idx].send(frame.clone(), delivery.clone()).await,
+                None => send_frame(&state.api_pool[idx], &frame, &delivery).await,
+            }
+        };
+        let res = match tokio::time::timeout(state.backend_timeout, send).await {
+            Ok(Ok(ApiReply::Ack { .. })) => Ok(()),
+            Ok(Ok(ApiReply::Nack { reason, .. })) => {
+                Err((anyhow::anyhow!("rejected: {reason:?}"), false))
+            }
+            Ok(Ok(reply)) => Err((anyhow::anyhow!("unexpected reply: {reply:?}"), true)),
+            Ok(Err(e)) => Err((e, delivery.reached_backend())),
+            Err(elapsed) => Err((elapsed.into(), !delivery.abandon())),
+        };
+        stats.in_flight.fetch_sub(1, Ordering::Relaxed);
+
+        match res {
+            Ok(()) => return Ok(StatusCode::OK),
+            Err((e, reached)) => {
+                *stats.last_error.lock().unwrap() = Some(e.to_string());
+                if reached {
+                    error!(
+                        "Payment may be queued on api-{} without a reply, not failing over: {e}",
+                        idx + 1
+                    );
+                    return Err(ApiError::backend(&e).with_correlation_id(correlation_id));
+                }
+                error!("Failed to send payment to api-{}: {e}", idx + 1);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    let e = last_error.expect("at least one backend was tried");
+    Err(ApiError::backend(&e).with_correlation_id(correlation_id))
+}
+
+/// Power of two choices: with two backends this means preferring the one with less queued
+/// work, alternating between them when loads are equal.
+fn pick_backend(state: &AppState) -> usize {
+    let turn = (state.balancer.fetch_add(1, Ordering::Relaxed) & 1) as usize;
+    let load = |stats: &BackendStats| {
+        stats.queue_depth.load(Ordering::Relaxed) + stats.in_flight.load(Ordering::Relaxed)
     };
-    let mut stream = api_pool.acquire().await.unwrap();
-    let serialized = serde_json::to_string(&payload).expect("failed to serialize payload");
 
-    stream
-        .write_all(serialized.as_bytes())
-        .await
-        .expect("failed to write to API-1");
-    stream
-        .write_all(b"\n")
-        .await
-        .expect("failed to write newline");
+    match load(&state.stats[0]).cmp(&load(&state.stats[1])) {
+        std::cmp::Ordering::Less => 0,
+        std::cmp::Ordering::Greater => 1,
+        std::cmp::Ordering::Equal => turn,
+    }
+}
+
+/// Write a single frame to a backend and wait for its Ack or Nack. The connection only goes
+/// back to the pool once the reply was read, so a cancelled or failed exchange never leaves
+/// a stray reply behind for the next caller.
+async fn send_frame(
+    pool: &UnixConnectionPool,
+    frame: &[u8],
+    delivery: &Delivery,
+) -> anyhow::Result<ApiReply> {
+    let Some(mut stream) = pool.acquire().await?.take() else {
+        anyhow::bail!("pooled connection was already taken");
+    };
 
-    stream.flush().await.expect("failed to flush");
+    if !delivery.start() {
+        anyhow::bail!("payment was abandoned");
+    }
+    stream.write_all(frame).await?;
+    stream.flush().await?;
+    delivery.written();
+    let reply = control::read_reply(&mut BufReader::new(&mut stream)).await?;
 
-    StatusCode::OK
+    pool.return_connection(stream);
+    Ok(reply)
 }
 
-async fn purge_payments(State(state): State<AppState>) -> impl IntoResponse {
-    let _ = state
+/// Reject requests whose `X-Rinha-Token` header doesn't match `RINHA_TOKEN`. No-op when unset.
+async fn require_token(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    if let Some(token) = &state.token {
+        let authorized = req
+            .headers()
+            .get("X-Rinha-Token")
+            .is_some_and(|value| value.as_bytes() == token.as_bytes());
+        if !authorized {
+            return ApiError::unauthorized().into_response();
+        }
+    }
+
+    next.run(req).await
+}
+
+async fn purge_payments(State(state): State<AppState>) -> Result<StatusCode, ApiError> {
+    // Drain the workers first so queued payments can't land after the DB is cleared.
+    for (idx, pool) in state.api_pool.iter().enumerate() {
+        let res = match tokio::time::timeout(state.backend_timeout, purge_backend(pool)).await {
+            Ok(res) => res,
+            Err(elapsed) => Err(elapsed.into()),
+        };
+        if let Err(e) = res {
+            error!("Failed to purge api-{}: {e}", idx + 1);
+            return Err(ApiError::backend(&e));
+        }
+    }
+
+    state
         .db_client
-        .delete("http://rinha-db:8888/purge")
+        .delete(format!("{}/purge", state.db_urls[0]))
+        .timeout(state.db_timeout)
         .send()
-        .await;
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| {
+            error!("Failed to purge rinha-db: {e}");
+            ApiError::backend(&e.into())
+        })?;
+
+    Ok(StatusCode::OK)
+}
+
+/// Ask a backend to drop its queued payments and wait for the acknowledgement.
+async fn purge_backend(pool: &UnixConnectionPool) -> anyhow::Result<()> {
+    match control::request(pool, &ApiFrame::Purge).await? {
+        ApiReply::Purged { .. } => Ok(()),
+        reply => anyhow::bail!("unexpected reply to purge: {reply:?}"),
+    }
+}
+
+#[derive(Serialize)]
+struct CancelResponse {
+    #[serde(rename = "correlationId")]
+    correlation_id: Uuid,
+    outcome: CancelOutcome,
+}
+
+/// Cancel a payment on both backends, since a failover may have sent it to either.
+async fn cancel_payment(
+    State(state): State<AppState>,
+    extract::Path(correlation_id): extract::Path<Uuid>,
+) -> Result<Json<CancelResponse>, ApiError> {
+    let frame = ApiFrame::Cancel { correlation_id };
+    let mut outcome = None;
+    let mut last_error = None;
+    for (idx, pool) in state.api_pool.iter().enumerate() {
+        let request = control::request(pool, &frame);
+        let res = match tokio::time::timeout(state.backend_timeout, request).await {
+            Ok(Ok(ApiReply::Cancelled { outcome, .. })) => Ok(outcome),
+            Ok(Ok(reply)) => Err(anyhow::anyhow!("unexpected reply to cancel: {reply:?}")),
+            Ok(Err(e)) => Err(e),
+            Err(elapsed) => Err(elapsed.into()),
+        };
+        match res {
+            Ok(CancelOutcome::Unknown) => {
+                outcome.get_or_insert(CancelOutcome::Unknown);
+            }
+            Ok(found) => outcome = Some(found),
+            Err(e) => {
+                error!("Failed to cancel {correlation_id} on api-{}: {e}", idx + 1);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    match (outcome, last_error) {
+        // A backend that didn't answer may hold the payment.
+        (Some(CancelOutcome::Unknown) | None, Some(e)) => {
+            Err(ApiError::backend(&e).with_correlation_id(correlation_id))
+        }
+        (outcome, _) => Ok(Json(CancelResponse {
+            correlation_id,
+            outcome: outcome.unwrap_or(CancelOutcome::Unknown),
+        })),
+    }
+}
+
+#[derive(Serialize)]
+struct AdminState {
+    balancer: u64,
+    backends: Vec<BackendState>,
+}
+
+#[derive(Serialize)]
+struct BackendState {
+    name: String,
+    #[serde(rename = "poolSize")]
+    pool_size: usize,
+    #[serde(rename = "idleConnections")]
+    idle_connections: usize,
+    #[serde(rename = "inFlight")]
+    in_flight: u64,
+    #[serde(rename = "queueDepth")]
+    queue_depth: u64,
+    #[serde(rename = "lastError")]
+    last_error: Option<String>,
+    /// Provider counters reported by the backend, missing when it didn't answer in time.
+    metrics: Option<WorkerMetrics>,
+    /// Queue, circuit and DB state reported by the backend, missing when it didn't answer.
+    status: Option<WorkerStatus>,
+}
+
+async fn fetch_metrics(state: &AppState, idx: usize) -> Option<WorkerMetrics> {
+    let request = control::request(&state.api_pool[idx], &ApiFrame::Metrics);
+    match tokio::time::timeout(state.backend_timeout, request).await {
+        Ok(Ok(ApiReply::Metrics(metrics))) => Some(metrics),
+        _ => None,
+    }
+}
+
+async fn fetch_status(state: &AppState, idx: usize) -> Option<WorkerStatus> {
+    let request = control::request(&state.api_pool[idx], &ApiFrame::Status);
+    match tokio::time::timeout(state.backend_timeout, request).await {
+        Ok(Ok(ApiReply::Status(status))) => Some(status),
+        _ => None,
+    }
+}
+
+async fn admin_state(State(state): State<AppState>) -> impl IntoResponse {
+    let (metrics_1, metrics_2, status_1, status_2) = tokio::join!(
+        fetch_metrics(&state, 0),
+        fetch_metrics(&state, 1),
+        fetch_status(&state, 0),
+        fetch_status(&state, 1),
+    );
+    let backends = state
+        .api_pool
+        .iter()
+        .zip(&state.stats)
+        .zip([(metrics_1, status_1), (metrics_2, status_2)])
+        .enumerate()
+        .map(|(idx, ((pool, stats), (metrics, status)))| BackendState {
+            name: format!("api-{}", idx + 1),
+            pool_size: pool.pool_size(),
+            idle_connections: pool.idle(),
+            in_flight: stats.in_flight.load(Ordering::Relaxed),
+            queue_depth: stats.queue_depth.load(Ordering::Relaxed),
+            last_error: stats.last_error.lock().unwrap().clone(),
+            metrics,
+            status,
+        })
+        .collect();
 
-    StatusCode::OK
+    Json(AdminState {
+        balancer: state.balancer.load(Ordering::Relaxed),
+        backends,
+    })
 }
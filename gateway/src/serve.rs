@@ -0,0 +1,53 @@
+use std::str::FromStr;
+
+use axum::{Router, serve::Listener};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+    service::TowerToHyperService,
+};
+use tracing::error;
+
+/// Wire protocol accepted by the gateway listener.
+#[derive(Clone, Copy, Debug)]
+pub enum Protocol {
+    Http1,
+    /// HTTP/2 over cleartext with prior knowledge.
+    H2c,
+    /// Detect HTTP/1.1 or h2c per connection from the preface.
+    Auto,
+}
+
+impl FromStr for Protocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "http1" => Ok(Protocol::Http1),
+            "h2c" => Ok(Protocol::H2c),
+            "auto" => Ok(Protocol::Auto),
+            other => anyhow::bail!("unknown HTTP_PROTOCOL {other:?}, expected http1, h2c or auto"),
+        }
+    }
+}
+
+/// Accept loop replacing `axum::serve` so the protocol can be pinned per deployment.
+pub async fn serve<L: Listener>(mut listener: L, app: Router, protocol: Protocol) {
+    let builder = Builder::new(TokioExecutor::new());
+    let builder = match protocol {
+        Protocol::Http1 => builder.http1_only(),
+        Protocol::H2c => builder.http2_only(),
+        Protocol::Auto => builder,
+    };
+
+    loop {
+        let (io, _) = listener.accept().await;
+        let service = TowerToHyperService::new(app.clone());
+        let builder = builder.clone();
+        tokio::spawn(async move {
+            if let Err(e) = builder.serve_connection(TokioIo::new(io), service).await {
+                error!("Connection error: {e}");
+            }
+        });
+    }
+}
@@ -0,0 +1,257 @@
+//! Stand-in for the official payment processor, with the endpoints the api workers call and
+//! the admin ones the test scripts use, plus scripted failures and latency.
+
+mod profile;
+
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    env,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    Json, Router,
+    extract::{Path, Query, Request, State},
+    http::StatusCode,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::profile::{Playing, Profile, Script};
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+struct Payment {
+    correlation_id: Uuid,
+    amount: f64,
+    requested_at: DateTime<Utc>,
+}
+
+struct Processor {
+    /// Share of each payment's amount charged, from `TRANSACTION_FEE`.
+    fee: f64,
+    /// Expected as `X-Rinha-Token` on admin endpoints, from `INITIAL_TOKEN`.
+    token: String,
+    /// Only one health check is answered per `RATE_LIMIT_SECONDS`, the rest get 429.
+    health_interval: Duration,
+    last_health: Mutex<Option<Instant>>,
+    payments: Mutex<HashMap<Uuid, Payment>>,
+    playing: Mutex<Playing>,
+}
+
+type AppState = Arc<Processor>;
+
+impl Processor {
+    fn profile(&self) -> Profile {
+        self.playing.lock().unwrap().current()
+    }
+
+    fn play(&self, script: Script) {
+        *self.playing.lock().unwrap() = Playing::new(script);
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    shared_types::logging::init("mock-processor")?;
+    let health_interval: u64 = env::var("RATE_LIMIT_SECONDS")
+        .unwrap_or("5".to_string())
+        .parse()?;
+    let state = Arc::new(Processor {
+        fee: env::var("TRANSACTION_FEE")
+            .unwrap_or("0.05".to_string())
+            .parse()?,
+        token: env::var("INITIAL_TOKEN").unwrap_or("123".to_string()),
+        health_interval: Duration::from_secs(health_interval),
+        last_health: Mutex::new(None),
+        payments: Mutex::new(HashMap::new()),
+        playing: Mutex::new(Playing::new(Script::from_env()?)),
+    });
+
+    let admin = Router::new()
+        .route("/admin/payments-summary", get(summary))
+        .route("/admin/purge-payments", post(purge))
+        .route("/admin/configurations/delay", put(set_delay))
+        .route("/admin/configurations/failure", put(set_failure))
+        .route("/admin/script", get(get_script).put(set_script))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_token));
+    let app = Router::new()
+        .route("/payments", post(pay))
+        .route("/payments/{id}", get(payment))
+        .route("/payments/service-health", get(health))
+        .merge(admin)
+        .with_state(state);
+
+    let listen = env::var("LISTEN").unwrap_or("0.0.0.0:8080".to_string());
+    let listener = tokio::net::TcpListener::bind(listen.as_str()).await?;
+    info!("Mock processor listening on {listen}");
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn require_token(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let authorized = req
+        .headers()
+        .get("X-Rinha-Token")
+        .is_some_and(|value| value.as_bytes() == state.token.as_bytes());
+    if !authorized {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    next.run(req).await
+}
+
+/// Accepted payments are recorded before the delay, so a caller timing out leaves them
+/// processed, like the official processor under load.
+async fn pay(State(state): State<AppState>, Json(payment): Json<Payment>) -> Response {
+    let profile = state.profile();
+    let outcome = if profile.fails() {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "message": "Payment processor is failing" })),
+        )
+    } else if !(payment.amount.is_finite() && payment.amount > 0.0) {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!({ "message": "amount must be a positive number" })),
+        )
+    } else {
+        match state.payments.lock().unwrap().entry(payment.correlation_id) {
+            Entry::Occupied(_) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(json!({ "message": "CorrelationId already exists" })),
+            ),
+            Entry::Vacant(entry) => {
+                entry.insert(payment);
+                (
+                    StatusCode::OK,
+                    Json(json!({ "message": "payment processed successfully" })),
+                )
+            }
+        }
+    };
+    tokio::time::sleep(profile.delay()).await;
+    outcome.into_response()
+}
+
+async fn payment(State(state): State<AppState>, Path(id): Path<Uuid>) -> Response {
+    match state.payments.lock().unwrap().get(&id) {
+        Some(payment) => Json(*payment).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn health(State(state): State<AppState>) -> Response {
+    {
+        let mut last = state.last_health.lock().unwrap();
+        if last.is_some_and(|last| last.elapsed() < state.health_interval) {
+            return StatusCode::TOO_MANY_REQUESTS.into_response();
+        }
+        *last = Some(Instant::now());
+    }
+    let profile = state.profile();
+    Json(json!({ "failing": profile.failing, "minResponseTime": profile.latency_ms }))
+        .into_response()
+}
+
+#[derive(Deserialize)]
+struct SummaryQuery {
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+}
+
+/// Totals of the payments requested between `from` and `to`, both optional and inclusive.
+async fn summary(State(state): State<AppState>, Query(range): Query<SummaryQuery>) -> Response {
+    let payments = state.payments.lock().unwrap();
+    let (requests, amount) = payments
+        .values()
+        .filter(|payment| range.from.is_none_or(|from| payment.requested_at >= from))
+        .filter(|payment| range.to.is_none_or(|to| payment.requested_at <= to))
+        .fold((0u64, 0.0), |(requests, amount), payment| {
+            (requests + 1, amount + payment.amount)
+        });
+    Json(json!({
+        "totalRequests": requests,
+        "totalAmount": amount,
+        "totalFee": amount * state.fee,
+        "feePerTransaction": state.fee,
+    }))
+    .into_response()
+}
+
+async fn purge(State(state): State<AppState>) -> Response {
+    state.payments.lock().unwrap().clear();
+    Json(json!({ "message": "All payments purged." })).into_response()
+}
+
+#[derive(Deserialize)]
+struct Delay {
+    delay: u64,
+}
+
+/// Like the official processor's, replacing any script with the current profile at the new
+/// latency.
+async fn set_delay(
+    State(state): State<AppState>,
+    Json(Delay { delay }): Json<Delay>,
+) -> StatusCode {
+    let profile = Profile {
+        latency_ms: delay,
+        ..state.profile()
+    };
+    state.play(Script::constant(profile));
+    info!("Latency set to {delay}ms");
+    StatusCode::OK
+}
+
+#[derive(Deserialize)]
+struct Failure {
+    failure: bool,
+}
+
+/// Like the official processor's, replacing any script with the current profile failing or
+/// not.
+async fn set_failure(
+    State(state): State<AppState>,
+    Json(Failure { failure }): Json<Failure>,
+) -> StatusCode {
+    let profile = Profile {
+        failing: failure,
+        ..state.profile()
+    };
+    state.play(Script::constant(profile));
+    info!("Failing set to {failure}");
+    StatusCode::OK
+}
+
+async fn get_script(State(state): State<AppState>) -> Response {
+    let playing = state.playing.lock().unwrap();
+    Json(json!({
+        "script": playing.script,
+        "current": playing.current(),
+        "elapsedMs": playing.since.elapsed().as_millis() as u64,
+    }))
+    .into_response()
+}
+
+/// Play a script from now on, see [`Script`].
+async fn set_script(State(state): State<AppState>, body: axum::body::Bytes) -> Response {
+    match Script::parse(&body) {
+        Ok(script) => {
+            info!("Playing a script of {} phases", script.phases.len());
+            state.play(script);
+            StatusCode::OK.into_response()
+        }
+        Err(e) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!({ "message": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    env, fs,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// How the processor answers while a phase lasts.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Profile {
+    /// Response time of every call, also reported as `minResponseTime`.
+    pub latency_ms: u64,
+    /// Up to this much is randomly added to every call.
+    pub jitter_ms: u64,
+    /// Probability that a payment is answered 500.
+    pub fail_rate: f64,
+    /// Answer every payment 500 and report the processor as failing.
+    pub failing: bool,
+}
+
+impl Profile {
+    /// From `LATENCY_MS`, `JITTER_MS`, `FAIL_RATE` and `FAILING`.
+    fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            latency_ms: env::var("LATENCY_MS").unwrap_or("0".to_string()).parse()?,
+            jitter_ms: env::var("JITTER_MS").unwrap_or("0".to_string()).parse()?,
+            fail_rate: env::var("FAIL_RATE").unwrap_or("0".to_string()).parse()?,
+            failing: env::var("FAILING").unwrap_or("false".to_string()).parse()?,
+        })
+    }
+
+    pub fn delay(&self) -> Duration {
+        Duration::from_millis(self.latency_ms + fastrand::u64(0..=self.jitter_ms))
+    }
+
+    pub fn fails(&self) -> bool {
+        self.failing || fastrand::f64() < self.fail_rate
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Phase {
+    /// How long the phase lasts. The last one lasts for good unless the script repeats.
+    pub duration_ms: u64,
+    #[serde(flatten)]
+    pub profile: Profile,
+}
+
+/// Phases played one after the other from when the script is set, e.g. a healthy minute, ten
+/// seconds failing, then slow.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Script {
+    pub phases: Vec<Phase>,
+    /// Start over after the last phase.
+    #[serde(default)]
+    pub repeat: bool,
+}
+
+impl Script {
+    /// The script at `SCRIPT`, a JSON file, or the single profile from the env.
+    pub fn from_env() -> anyhow::Result<Self> {
+        match env::var("SCRIPT") {
+            Ok(path) => Self::parse(&fs::read(path)?),
+            Err(_) => Ok(Self::constant(Profile::from_env()?)),
+        }
+    }
+
+    pub fn parse(json: &[u8]) -> anyhow::Result<Self> {
+        let script: Self = serde_json::from_slice(json)?;
+        if script.phases.is_empty() {
+            anyhow::bail!("a script needs at least one phase");
+        }
+        Ok(script)
+    }
+
+    pub fn constant(profile: Profile) -> Self {
+        Self {
+            phases: vec![Phase {
+                duration_ms: 0,
+                profile,
+            }],
+            repeat: false,
+        }
+    }
+
+    /// The profile in effect `elapsed` after the script was set.
+    pub fn at(&self, elapsed: Duration) -> Profile {
+        let total: u64 = self.phases.iter().map(|phase| phase.duration_ms).sum();
+        let mut at = elapsed.as_millis() as u64;
+        if self.repeat && total > 0 {
+            at %= total;
+        }
+        for phase in &self.phases {
+            if at < phase.duration_ms {
+                return phase.profile;
+            }
+            at -= phase.duration_ms;
+        }
+        self.phases[self.phases.len() - 1].profile
+    }
+}
+
+/// The script being played and when it was set.
+pub struct Playing {
+    pub script: Script,
+    pub since: Instant,
+}
+
+impl Playing {
+    pub fn new(script: Script) -> Self {
+        Self {
+            script,
+            since: Instant::now(),
+        }
+    }
+
+    pub fn current(&self) -> Profile {
+        self.script.at(self.since.elapsed())
+    }
+}
@@ -0,0 +1,267 @@
+use std::{
+    env,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use shared_types::CircuitStatus;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl From<CircuitState> for CircuitStatus {
+    fn from(state: CircuitState) -> Self {
+        match state {
+            CircuitState::Closed => CircuitStatus::Closed,
+            CircuitState::Open => CircuitStatus::Open,
+            CircuitState::HalfOpen => CircuitStatus::HalfOpen,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BreakerConfig {
+    /// Failure ratio within a window that opens the circuit.
+    pub failure_rate: f64,
+    /// Minimum number of calls in a window before the ratio is evaluated.
+    pub min_requests: u32,
+    pub window: Duration,
+    /// How long the circuit stays open before a single probe is let through.
+    pub cooldown: Duration,
+}
+
+impl BreakerConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            failure_rate: env::var("BREAKER_FAILURE_RATE")
+                .unwrap_or("0.5".to_string())
+                .parse()?,
+            min_requests: env::var("BREAKER_MIN_REQUESTS")
+                .unwrap_or("10".to_string())
+                .parse()?,
+            window: Duration::from_millis(
+                env::var("BREAKER_WINDOW_MS")
+                    .unwrap_or("5000".to_string())
+                    .parse()?,
+            ),
+            cooldown: Duration::from_millis(
+                env::var("BREAKER_COOLDOWN_MS")
+                    .unwrap_or("1000".to_string())
+                    .parse()?,
+            ),
+        })
+    }
+}
+
+struct Inner {
+    state: CircuitState,
+    window_start: Instant,
+    successes: u32,
+    failures: u32,
+    opened_at: Instant,
+    probe_in_flight: bool,
+}
+
+/// Closed/open/half-open circuit breaker around a single payment processor.
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            config,
+            inner: Mutex::new(Inner {
+                state: CircuitState::Closed,
+                window_start: now,
+                successes: 0,
+                failures: 0,
+                opened_at: now,
+                probe_in_flight: false,
+            }),
+        }
+    }
+
+    /// Whether a call may go through. Once the cool-down has elapsed this lets exactly one
+    /// probe through and moves the circuit to half-open.
+    pub fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            CircuitState::Closed => true,
+            CircuitState::Open if inner.opened_at.elapsed() >= self.config.cooldown => {
+                inner.state = CircuitState::HalfOpen;
+                inner.probe_in_flight = true;
+                true
+            }
+            CircuitState::Open => false,
+            CircuitState::HalfOpen if !inner.probe_in_flight => {
+                inner.probe_in_flight = true;
+                true
+            }
+            CircuitState::HalfOpen => false,
+        }
+    }
+
+    /// Whether calls are currently being rejected without a probe being due.
+    pub fn is_open(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.state == CircuitState::Open && inner.opened_at.elapsed() < self.config.cooldown
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.inner.lock().unwrap().state
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            CircuitState::HalfOpen => Self::close(&mut inner),
+            _ => {
+                self.roll_window(&mut inner);
+                inner.successes += 1;
+            }
+        }
+    }
+
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            CircuitState::HalfOpen => Self::open(&mut inner),
+            CircuitState::Open => {}
+            CircuitState::Closed => {
+                self.roll_window(&mut inner);
+                inner.failures += 1;
+
+                let total = inner.successes + inner.failures;
+                if total >= self.config.min_requests
+                    && inner.failures as f64 / total as f64 >= self.config.failure_rate
+                {
+                    Self::open(&mut inner);
+                }
+            }
+        }
+    }
+
+    fn roll_window(&self, inner: &mut Inner) {
+        if inner.window_start.elapsed() >= self.config.window {
+            inner.window_start = Instant::now();
+            inner.successes = 0;
+            inner.failures = 0;
+        }
+    }
+
+    fn open(inner: &mut Inner) {
+        inner.state = CircuitState::Open;
+        inner.opened_at = Instant::now();
+        inner.probe_in_flight = false;
+    }
+
+    fn close(inner: &mut Inner) {
+        inner.state = CircuitState::Closed;
+        inner.window_start = Instant::now();
+        inner.successes = 0;
+        inner.failures = 0;
+        inner.probe_in_flight = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_millis(20);
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(BreakerConfig {
+            failure_rate: 0.5,
+            min_requests: 4,
+            window: Duration::from_secs(60),
+            cooldown: COOLDOWN,
+        })
+    }
+
+    fn opened() -> CircuitBreaker {
+        let breaker = breaker();
+        (0..4).for_each(|_| breaker.record_failure());
+        assert_eq!(breaker.state(), CircuitState::Open);
+        breaker
+    }
+
+    #[test]
+    fn stays_closed_below_min_requests() {
+        let breaker = breaker();
+        (0..3).for_each(|_| breaker.record_failure());
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn stays_closed_below_the_failure_rate() {
+        let breaker = breaker();
+        (0..3).for_each(|_| breaker.record_success());
+        (0..2).for_each(|_| breaker.record_failure());
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn opens_at_the_failure_rate_and_rejects_calls() {
+        let breaker = breaker();
+        (0..2).for_each(|_| breaker.record_success());
+        (0..2).for_each(|_| breaker.record_failure());
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(breaker.is_open());
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn lets_one_probe_through_after_the_cooldown() {
+        let breaker = opened();
+        thread::sleep(COOLDOWN);
+        assert!(!breaker.is_open());
+        assert!(breaker.allow());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn a_successful_probe_closes_it() {
+        let breaker = opened();
+        thread::sleep(COOLDOWN);
+        assert!(breaker.allow());
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        // The window starts over, so earlier failures don't count.
+        (0..3).for_each(|_| breaker.record_failure());
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn a_failed_probe_opens_it_again() {
+        let breaker = opened();
+        thread::sleep(COOLDOWN);
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn failures_from_an_earlier_window_are_forgotten() {
+        let breaker = CircuitBreaker::new(BreakerConfig {
+            window: Duration::from_millis(20),
+            ..breaker().config
+        });
+        (0..3).for_each(|_| breaker.record_failure());
+        thread::sleep(Duration::from_millis(20));
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+}
@@ -0,0 +1,67 @@
+use std::{env, time::Duration};
+
+use reqwest::{Client, header::HeaderMap};
+use uuid::Uuid;
+
+use crate::{CurrentProvider, registry::ProviderRegistry, timeout::TimeoutConfig};
+
+#[derive(Clone, Copy, Debug)]
+pub struct ClientConfig {
+    /// Idle connections kept per provider host.
+    pub pool_max_idle: usize,
+    /// How long an idle connection is kept. `None` keeps it until the provider closes it.
+    pub pool_idle_timeout: Option<Duration>,
+    /// Connections opened to each provider at startup, so the first burst after boot or an
+    /// idle period doesn't pay for TCP handshakes.
+    pub prewarm: usize,
+}
+
+impl ClientConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let idle_timeout: u64 = env::var("PROVIDER_POOL_IDLE_TIMEOUT_MS")
+            .unwrap_or("0".to_string())
+            .parse()?;
+
+        Ok(Self {
+            pool_max_idle: env::var("PROVIDER_POOL_MAX_IDLE")
+                .unwrap_or("256".to_string())
+                .parse()?,
+            pool_idle_timeout: (idle_timeout > 0).then(|| Duration::from_millis(idle_timeout)),
+            prewarm: env::var("PROVIDER_PREWARM")
+                .unwrap_or("32".to_string())
+                .parse()?,
+        })
+    }
+
+    /// The client shared by every worker for provider, health and rinha-db HTTP calls.
+    pub fn build(&self, timeouts: &TimeoutConfig, headers: HeaderMap) -> anyhow::Result<Client> {
+        Ok(Client::builder()
+            .no_gzip()
+            .no_zstd()
+            .http1_only()
+            .tcp_nodelay(true)
+            .tcp_keepalive(Duration::from_secs(30))
+            .pool_max_idle_per_host(self.pool_max_idle)
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .connect_timeout(timeouts.connect)
+            .timeout(timeouts.total)
+            .default_headers(headers)
+            .build()?)
+    }
+
+    /// Open `prewarm` connections to both providers in the background. Any response keeps
+    /// the connection in the pool, so looking up a payment that doesn't exist is enough. The
+    /// health endpoint is rate limited and would starve the poller.
+    pub fn spawn_prewarm(&self, client: &Client, registry: &ProviderRegistry) {
+        for provider in [CurrentProvider::Default, CurrentProvider::Fallback] {
+            let url = format!("{}/{}", registry.get(provider).payments_url, Uuid::nil());
+            for _ in 0..self.prewarm {
+                let client = client.clone();
+                let url = url.clone();
+                tokio::spawn(async move {
+                    let _ = client.get(url).send().await;
+                });
+            }
+        }
+    }
+}
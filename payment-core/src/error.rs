@@ -0,0 +1,173 @@
+use reqwest::{Response, StatusCode};
+
+/// Why a provider didn't process a payment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProviderError {
+    /// A 5xx or a 429. Worth retrying after a backoff.
+    Retryable(StatusCode),
+    /// The provider already has this correlation id, so it processed the payment before.
+    Duplicate,
+    /// The provider refused the payload. Retrying won't help.
+    InvalidPayload(String),
+    /// Unreachable, timed out or behind an open circuit. It may still process a timed out
+    /// call and is likely to stay slow, so the caller moves on to the other provider.
+    Down,
+}
+
+impl ProviderError {
+    /// Classify the outcome of a payment call, reading the body of 4xx responses to tell
+    /// duplicates apart from invalid payloads.
+    pub async fn from_response(res: Result<Response, reqwest::Error>) -> Result<(), Self> {
+        let res = match res {
+            Ok(res) => res,
+            Err(_) => return Err(ProviderError::Down),
+        };
+
+        let status = res.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(ProviderError::Retryable(status));
+        }
+        if !status.is_client_error() {
+            return Err(ProviderError::Retryable(status));
+        }
+
+        let body = res.text().await.unwrap_or_default();
+        let lower = body.to_lowercase();
+        if status == StatusCode::CONFLICT
+            || lower.contains("already exists")
+            || lower.contains("duplicate")
+        {
+            return Err(ProviderError::Duplicate);
+        }
+        Err(ProviderError::InvalidPayload(format!("{status}: {body}")))
+    }
+
+    /// Whether the provider holds the payment, either from this call or an earlier one.
+    pub fn processed(res: &Result<(), Self>) -> bool {
+        matches!(res, Ok(()) | Err(ProviderError::Duplicate))
+    }
+
+    /// Whether the provider itself misbehaved, as opposed to rejecting this payment.
+    pub fn is_provider_failure(&self) -> bool {
+        matches!(self, ProviderError::Retryable(_) | ProviderError::Down)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{Router, http::StatusCode as Status, routing::post};
+    use reqwest::Client;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// A processor answering each path the way the real one would in that case, and `/slow`
+    /// only after a second.
+    async fn processor() -> String {
+        let app = Router::new()
+            .route("/200", post(|| async { "payment processed successfully" }))
+            .route("/500", post(|| async { Status::INTERNAL_SERVER_ERROR }))
+            .route("/503", post(|| async { Status::SERVICE_UNAVAILABLE }))
+            .route("/429", post(|| async { Status::TOO_MANY_REQUESTS }))
+            .route("/409", post(|| async { Status::CONFLICT }))
+            .route(
+                "/duplicate",
+                post(|| async {
+                    (
+                        Status::UNPROCESSABLE_ENTITY,
+                        r#"{"message":"CorrelationId already exists"}"#,
+                    )
+                }),
+            )
+            .route(
+                "/invalid",
+                post(|| async {
+                    (
+                        Status::UNPROCESSABLE_ENTITY,
+                        r#"{"message":"amount must be a positive number"}"#,
+                    )
+                }),
+            )
+            .route(
+                "/slow",
+                post(|| async {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    "payment processed successfully"
+                }),
+            );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    async fn classify(client: &Client, url: &str) -> Result<(), ProviderError> {
+        ProviderError::from_response(client.post(url).send().await).await
+    }
+
+    #[tokio::test]
+    async fn responses_are_classified_by_status_and_body() {
+        let url = processor().await;
+        let client = Client::new();
+        let at = |path: &str| format!("{url}/{path}");
+
+        assert_eq!(classify(&client, &at("200")).await, Ok(()));
+        for (path, status) in [
+            ("500", StatusCode::INTERNAL_SERVER_ERROR),
+            ("503", StatusCode::SERVICE_UNAVAILABLE),
+            ("429", StatusCode::TOO_MANY_REQUESTS),
+        ] {
+            assert_eq!(
+                classify(&client, &at(path)).await,
+                Err(ProviderError::Retryable(status))
+            );
+        }
+        for path in ["409", "duplicate"] {
+            assert_eq!(
+                classify(&client, &at(path)).await,
+                Err(ProviderError::Duplicate)
+            );
+        }
+        let invalid = classify(&client, &at("invalid")).await;
+        assert!(
+            matches!(&invalid, Err(ProviderError::InvalidPayload(reason)) if reason.contains("positive")),
+            "{invalid:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn unreachable_and_timed_out_providers_are_down() {
+        let url = processor().await;
+        let client = Client::builder()
+            .timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        assert_eq!(
+            classify(&client, &format!("{url}/slow")).await,
+            Err(ProviderError::Down)
+        );
+
+        // Nothing listens on a port once its listener is dropped.
+        let closed = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            format!("http://{}/200", listener.local_addr().unwrap())
+        };
+        assert_eq!(classify(&client, &closed).await, Err(ProviderError::Down));
+    }
+
+    #[test]
+    fn duplicates_count_as_processed_and_rejections_as_not() {
+        assert!(ProviderError::processed(&Ok(())));
+        assert!(ProviderError::processed(&Err(ProviderError::Duplicate)));
+        assert!(!ProviderError::processed(&Err(ProviderError::Down)));
+        assert!(ProviderError::Down.is_provider_failure());
+        assert!(ProviderError::Retryable(StatusCode::BAD_GATEWAY).is_provider_failure());
+        assert!(!ProviderError::InvalidPayload(String::new()).is_provider_failure());
+        assert!(!ProviderError::Duplicate.is_provider_failure());
+    }
+}
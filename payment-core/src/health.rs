@@ -0,0 +1,73 @@
+use std::{sync::Arc, time::Duration};
+
+use arc_swap::ArcSwap;
+use reqwest::Client;
+use serde::Deserialize;
+
+use crate::{CurrentProvider, registry::ProviderRegistry};
+
+/// Last known health of a single payment processor.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProviderHealth {
+    pub failing: bool,
+    pub min_response_time: u64,
+}
+
+/// Health of both processors, swapped atomically by the poller.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProviderState {
+    pub default: ProviderHealth,
+    pub fallback: ProviderHealth,
+}
+
+#[derive(Deserialize)]
+struct ProviderHealthResponse {
+    failing: bool,
+    #[serde(rename = "minResponseTime")]
+    min_response_time: u64,
+}
+
+/// Poll both `service-health` endpoints. The processors only allow one call every 5 seconds,
+/// so a rejected or failed poll keeps the previous value.
+pub fn spawn_health_poller(
+    client: Client,
+    registry: Arc<ProviderRegistry>,
+    state: Arc<ArcSwap<ProviderState>>,
+) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(5));
+
+        loop {
+            ticker.tick().await;
+
+            let (default, fallback) = tokio::join!(
+                fetch_health(&client, &registry.get(CurrentProvider::Default).health_url),
+                fetch_health(&client, &registry.get(CurrentProvider::Fallback).health_url),
+            );
+
+            let current = state.load();
+            state.store(Arc::new(ProviderState {
+                default: default.unwrap_or(current.default),
+                fallback: fallback.unwrap_or(current.fallback),
+            }));
+        }
+    });
+}
+
+async fn fetch_health(client: &Client, url: &str) -> Option<ProviderHealth> {
+    let res = client
+        .get(url)
+        .send()
+        .await
+        .ok()?
+        .error_for_status()
+        .ok()?
+        .json::<ProviderHealthResponse>()
+        .await
+        .ok()?;
+
+    Some(ProviderHealth {
+        failing: res.failing,
+        min_response_time: res.min_response_time,
+    })
+}
@@ -0,0 +1,101 @@
+use std::env;
+
+use anyhow::Context;
+use tracing::info;
+
+use crate::CurrentProvider;
+
+/// Where and how to reach one payment processor.
+#[derive(Clone, Debug)]
+pub struct ProviderEndpoint {
+    pub name: String,
+    /// Sent as `X-Rinha-Token` on admin calls.
+    pub token: String,
+    /// Fee hint used by fee-aware strategies.
+    pub fee: f64,
+    pub payments_url: String,
+    pub health_url: String,
+    pub admin_summary_url: String,
+}
+
+impl ProviderEndpoint {
+    /// Read `PAYMENT_PROCESSOR_{URL,TOKEN,FEE,HEALTH_PATH}_<NAME>`.
+    fn from_env(name: &str) -> anyhow::Result<Self> {
+        let suffix = name.to_uppercase().replace('-', "_");
+        let var = |key: &str| env::var(format!("PAYMENT_PROCESSOR_{key}_{suffix}")).ok();
+
+        let (default_url, default_fee) = match name {
+            "default" => (Some("http://0.0.0.0:8001"), "0.05"),
+            "fallback" => (Some("http://0.0.0.0:8002"), "0.15"),
+            _ => (None, "0.05"),
+        };
+        let base_url = var("URL")
+            .or(default_url.map(str::to_string))
+            .with_context(|| format!("PAYMENT_PROCESSOR_URL_{suffix} is not set"))?;
+        let health_path = var("HEALTH_PATH").unwrap_or("/payments/service-health".to_string());
+
+        Ok(Self {
+            name: name.to_string(),
+            token: var("TOKEN")
+                .or_else(|| env::var("PROCESSOR_ADMIN_TOKEN").ok())
+                .unwrap_or("123".to_string()),
+            fee: var("FEE").unwrap_or(default_fee.to_string()).parse()?,
+            payments_url: format!("{base_url}/payments"),
+            health_url: format!("{base_url}{health_path}"),
+            admin_summary_url: format!("{base_url}/admin/payments-summary"),
+        })
+    }
+}
+
+/// Every processor listed in `PAYMENT_PROCESSORS`, along with the two that take the default
+/// and fallback roles in routing. Switching to another processor only takes config.
+#[derive(Debug)]
+pub struct ProviderRegistry {
+    providers: Vec<ProviderEndpoint>,
+    default: usize,
+    fallback: usize,
+}
+
+impl ProviderRegistry {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let providers = env::var("PAYMENT_PROCESSORS")
+            .unwrap_or("default,fallback".to_string())
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(ProviderEndpoint::from_env)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let role = |key: &str, default: &str| -> anyhow::Result<usize> {
+            let name = env::var(key).unwrap_or(default.to_string());
+            providers
+                .iter()
+                .position(|provider| provider.name == name)
+                .with_context(|| format!("{key}={name} is not listed in PAYMENT_PROCESSORS"))
+        };
+        let default = role("DEFAULT_PROCESSOR", "default")?;
+        let fallback = role("FALLBACK_PROCESSOR", "fallback")?;
+
+        if providers.len() > 2 {
+            info!(
+                "Routing to {} and {} out of {} processors",
+                providers[default].name,
+                providers[fallback].name,
+                providers.len()
+            );
+        }
+        Ok(Self {
+            providers,
+            default,
+            fallback,
+        })
+    }
+
+    /// The processor currently serving `provider`'s role.
+    pub fn get(&self, provider: CurrentProvider) -> &ProviderEndpoint {
+        match provider {
+            CurrentProvider::Default => &self.providers[self.default],
+            CurrentProvider::Fallback => &self.providers[self.fallback],
+        }
+    }
+}
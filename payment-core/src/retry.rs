@@ -0,0 +1,83 @@
+use std::{env, time::Duration};
+
+/// Capped exponential backoff with jitter, shared by the in-line provider retries and the
+/// retry queue.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Attempts before giving up.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Fraction of each delay that is randomized, from 0.0 (none) to 1.0 (full jitter).
+    pub jitter: f64,
+}
+
+impl RetryPolicy {
+    /// Read `{prefix}_MAX_ATTEMPTS`, `{prefix}_BASE_MS`, `{prefix}_MAX_MS` and `{prefix}_JITTER`,
+    /// keeping `defaults` for unset variables.
+    pub fn from_env(prefix: &str, defaults: RetryPolicy) -> anyhow::Result<Self> {
+        let var = |name: &str| env::var(format!("{prefix}_{name}")).ok();
+
+        let mut policy = defaults;
+        if let Some(v) = var("MAX_ATTEMPTS") {
+            policy.max_attempts = v.parse()?;
+        }
+        if let Some(v) = var("BASE_MS") {
+            policy.base_delay = Duration::from_millis(v.parse()?);
+        }
+        if let Some(v) = var("MAX_MS") {
+            policy.max_delay = Duration::from_millis(v.parse()?);
+        }
+        if let Some(v) = var("JITTER") {
+            policy.jitter = v.parse::<f64>()?.clamp(0.0, 1.0);
+        }
+        Ok(policy)
+    }
+
+    /// Delay before the attempt following `attempt` (zero-based).
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_delay);
+        backoff.mul_f64(1.0 - self.jitter * fastrand::f64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(jitter: f64) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(100),
+            jitter,
+        }
+    }
+
+    #[test]
+    fn delays_double_up_to_the_cap() {
+        let policy = policy(0.0);
+        let delays = (0..6).map(|attempt| policy.delay(attempt).as_millis());
+        assert_eq!(delays.collect::<Vec<_>>(), [10, 20, 40, 80, 100, 100]);
+    }
+
+    #[test]
+    fn late_attempts_stay_capped() {
+        let policy = policy(0.0);
+        assert_eq!(policy.delay(u32::MAX), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn jitter_only_shortens_delays() {
+        let half = policy(0.5);
+        for _ in 0..1000 {
+            let delay = half.delay(2);
+            assert!(delay >= Duration::from_millis(20) && delay <= Duration::from_millis(40));
+        }
+        let full = policy(1.0);
+        assert!((0..1000).all(|_| full.delay(0) <= Duration::from_millis(10)));
+    }
+}
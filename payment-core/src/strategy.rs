@@ -0,0 +1,254 @@
+use std::str::FromStr;
+
+use crate::CurrentProvider;
+
+/// What a strategy knows about one provider when routing a payment.
+#[derive(Clone, Copy, Debug)]
+pub struct ProviderView {
+    pub failing: bool,
+    pub circuit_open: bool,
+    pub fee: f64,
+    pub min_response_time: u64,
+}
+
+impl ProviderView {
+    pub fn available(&self) -> bool {
+        !self.failing && !self.circuit_open
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RoutingContext {
+    /// Provider to start on while both are available, switched by the fee watch.
+    pub preferred: CurrentProvider,
+    pub default: ProviderView,
+    pub fallback: ProviderView,
+}
+
+impl RoutingContext {
+    pub fn view(&self, provider: CurrentProvider) -> &ProviderView {
+        match provider {
+            CurrentProvider::Default => &self.default,
+            CurrentProvider::Fallback => &self.fallback,
+        }
+    }
+}
+
+/// Providers to try for a payment, in order.
+#[derive(Clone, Copy, Debug)]
+pub struct ProviderChoice {
+    pub primary: CurrentProvider,
+    pub secondary: Option<CurrentProvider>,
+}
+
+impl ProviderChoice {
+    fn first(primary: CurrentProvider) -> Self {
+        Self {
+            primary,
+            secondary: Some(primary.other()),
+        }
+    }
+}
+
+pub trait RoutingStrategy: Send + Sync {
+    fn choose(&self, ctx: &RoutingContext) -> ProviderChoice;
+}
+
+/// Always start on the default provider and fall back on failure.
+pub struct DefaultFirst;
+
+impl RoutingStrategy for DefaultFirst {
+    fn choose(&self, _ctx: &RoutingContext) -> ProviderChoice {
+        ProviderChoice::first(CurrentProvider::Default)
+    }
+}
+
+/// Start on the preferred provider unless it's known to be failing and the other one isn't.
+pub struct HealthAware;
+
+impl RoutingStrategy for HealthAware {
+    fn choose(&self, ctx: &RoutingContext) -> ProviderChoice {
+        let preferred = ctx.preferred;
+        if !ctx.view(preferred).available() && ctx.view(preferred.other()).available() {
+            ProviderChoice::first(preferred.other())
+        } else {
+            ProviderChoice::first(preferred)
+        }
+    }
+}
+
+/// Prefer the cheapest provider among the available ones.
+pub struct FeeOptimized;
+
+impl RoutingStrategy for FeeOptimized {
+    fn choose(&self, ctx: &RoutingContext) -> ProviderChoice {
+        let cheapest = if ctx.fallback.fee < ctx.default.fee {
+            CurrentProvider::Fallback
+        } else {
+            CurrentProvider::Default
+        };
+
+        match (ctx.default.available(), ctx.fallback.available()) {
+            (true, false) => ProviderChoice::first(CurrentProvider::Default),
+            (false, true) => ProviderChoice::first(CurrentProvider::Fallback),
+            _ => ProviderChoice::first(cheapest),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum StrategyKind {
+    DefaultFirst,
+    HealthAware,
+    FeeOptimized,
+}
+
+impl StrategyKind {
+    pub fn build(self) -> Box<dyn RoutingStrategy> {
+        match self {
+            StrategyKind::DefaultFirst => Box::new(DefaultFirst),
+            StrategyKind::HealthAware => Box::new(HealthAware),
+            StrategyKind::FeeOptimized => Box::new(FeeOptimized),
+        }
+    }
+}
+
+impl FromStr for StrategyKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "default-first" => Ok(StrategyKind::DefaultFirst),
+            "health-aware" => Ok(StrategyKind::HealthAware),
+            "fee-optimized" => Ok(StrategyKind::FeeOptimized),
+            other => anyhow::bail!(
+                "unknown ROUTING_STRATEGY {other:?}, expected default-first, health-aware or fee-optimized"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UP: ProviderView = ProviderView {
+        failing: false,
+        circuit_open: false,
+        fee: 0.05,
+        min_response_time: 0,
+    };
+
+    fn context(default: ProviderView, fallback: ProviderView) -> RoutingContext {
+        RoutingContext {
+            preferred: CurrentProvider::Default,
+            default,
+            fallback,
+        }
+    }
+
+    fn failing() -> ProviderView {
+        ProviderView {
+            failing: true,
+            ..UP
+        }
+    }
+
+    fn open() -> ProviderView {
+        ProviderView {
+            circuit_open: true,
+            ..UP
+        }
+    }
+
+    fn assert_order(choice: ProviderChoice, primary: CurrentProvider) {
+        assert_eq!(choice.primary, primary);
+        assert_eq!(choice.secondary, Some(primary.other()));
+    }
+
+    #[test]
+    fn default_first_ignores_health() {
+        let ctx = context(failing(), UP);
+        assert_order(DefaultFirst.choose(&ctx), CurrentProvider::Default);
+    }
+
+    #[test]
+    fn health_aware_leaves_an_unavailable_preferred_provider() {
+        for down in [failing(), open()] {
+            assert_order(
+                HealthAware.choose(&context(down, UP)),
+                CurrentProvider::Fallback,
+            );
+        }
+        let ctx = RoutingContext {
+            preferred: CurrentProvider::Fallback,
+            ..context(UP, failing())
+        };
+        assert_order(HealthAware.choose(&ctx), CurrentProvider::Default);
+    }
+
+    #[test]
+    fn health_aware_stays_on_the_preferred_provider_unless_the_other_is_up() {
+        assert_order(
+            HealthAware.choose(&context(UP, UP)),
+            CurrentProvider::Default,
+        );
+        assert_order(
+            HealthAware.choose(&context(failing(), open())),
+            CurrentProvider::Default,
+        );
+        let ctx = RoutingContext {
+            preferred: CurrentProvider::Fallback,
+            ..context(UP, UP)
+        };
+        assert_order(HealthAware.choose(&ctx), CurrentProvider::Fallback);
+    }
+
+    #[test]
+    fn fee_optimized_prefers_the_cheapest_available_provider() {
+        let cheap = ProviderView { fee: 0.01, ..UP };
+        assert_order(
+            FeeOptimized.choose(&context(UP, cheap)),
+            CurrentProvider::Fallback,
+        );
+        assert_order(
+            FeeOptimized.choose(&context(cheap, UP)),
+            CurrentProvider::Default,
+        );
+        // Equal fees keep the default.
+        assert_order(
+            FeeOptimized.choose(&context(UP, UP)),
+            CurrentProvider::Default,
+        );
+    }
+
+    #[test]
+    fn fee_optimized_skips_an_unavailable_provider_however_cheap() {
+        let cheap_but_down = ProviderView {
+            fee: 0.01,
+            failing: true,
+            ..UP
+        };
+        assert_order(
+            FeeOptimized.choose(&context(UP, cheap_but_down)),
+            CurrentProvider::Default,
+        );
+        let cheap_but_open = ProviderView {
+            fee: 0.01,
+            circuit_open: true,
+            ..UP
+        };
+        assert_order(
+            FeeOptimized.choose(&context(cheap_but_open, UP)),
+            CurrentProvider::Fallback,
+        );
+    }
+
+    #[test]
+    fn strategies_parse_from_their_names() {
+        for name in ["default-first", "health-aware", "fee-optimized"] {
+            assert!(name.parse::<StrategyKind>().is_ok());
+        }
+        assert!("round-robin".parse::<StrategyKind>().is_err());
+    }
+}
@@ -0,0 +1,63 @@
+use std::{env, time::Duration};
+
+#[derive(Clone, Copy, Debug)]
+pub struct TimeoutConfig {
+    /// Upper bound on establishing a connection to a provider.
+    pub connect: Duration,
+    /// Upper bound on a whole request, applied to every call made by the shared client.
+    pub total: Duration,
+    /// Shortest per-call timeout, used while a provider reports a fast `minResponseTime`.
+    pub min: Duration,
+    /// Multiple of the provider's `minResponseTime` allowed before a call is abandoned.
+    pub factor: f64,
+}
+
+impl TimeoutConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            connect: Duration::from_millis(
+                env::var("PROVIDER_CONNECT_TIMEOUT_MS")
+                    .unwrap_or("200".to_string())
+                    .parse()?,
+            ),
+            total: Duration::from_millis(
+                env::var("PROVIDER_TIMEOUT_MS")
+                    .unwrap_or("1500".to_string())
+                    .parse()?,
+            ),
+            min: Duration::from_millis(
+                env::var("PROVIDER_MIN_TIMEOUT_MS")
+                    .unwrap_or("100".to_string())
+                    .parse()?,
+            ),
+            factor: env::var("PROVIDER_TIMEOUT_FACTOR")
+                .unwrap_or("3.0".to_string())
+                .parse()?,
+        })
+    }
+
+    /// Timeout for a single payment call given the provider's last reported `minResponseTime`,
+    /// clamped between `min` and `total`.
+    pub fn for_call(&self, min_response_time: u64) -> Duration {
+        let scaled = Duration::from_millis(min_response_time).mul_f64(self.factor);
+        scaled.clamp(self.min, self.total.max(self.min))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn call_timeouts_scale_between_min_and_total() {
+        let config = TimeoutConfig {
+            connect: Duration::from_millis(200),
+            total: Duration::from_millis(1500),
+            min: Duration::from_millis(100),
+            factor: 3.0,
+        };
+        assert_eq!(config.for_call(0), Duration::from_millis(100));
+        assert_eq!(config.for_call(200), Duration::from_millis(600));
+        assert_eq!(config.for_call(1000), Duration::from_millis(1500));
+    }
+}
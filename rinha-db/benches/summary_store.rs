@@ -0,0 +1,87 @@
+//! Inserts into one `SummaryStore` from several threads at once, all within the same few
+//! seconds like under load, alone and with a reader summing the store meanwhile.
+
+use std::{
+    hint::black_box,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, SecondsFormat};
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use rinha_db::{
+    keys,
+    storage::{Bucket, Granularity, SummaryStore},
+};
+use shared_types::{SledTree, payment_key};
+use uuid::Uuid;
+
+/// 2025-07-15T12:00:00Z.
+const START: u64 = 1_752_580_800_000;
+/// Records each thread inserts per iteration.
+const RECORDS: usize = 10_000;
+
+/// Keys spread over five seconds, the buckets being written to under load.
+fn keys(count: usize) -> Vec<Vec<u8>> {
+    (0..count)
+        .map(|i| {
+            let requested_at = DateTime::from_timestamp_millis((START + i as u64 % 5000) as i64)
+                .unwrap()
+                .to_rfc3339_opts(SecondsFormat::Millis, true);
+            keys::from_write_key(&payment_key(&requested_at, &Uuid::new_v4())).unwrap()
+        })
+        .collect()
+}
+
+/// Time `threads` threads inserting `keys` into a fresh store at once, with a thread summing
+/// the store until they're done when `reading`.
+fn insert(threads: usize, keys: &[Vec<u8>], reading: bool) -> Duration {
+    let store = SummaryStore::new(Granularity::Second);
+    let done = AtomicBool::new(false);
+    thread::scope(|scope| {
+        if reading {
+            scope.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    black_box(
+                        store.summary(&SledTree::DEFAULT, (START, START + 5000), |_| {
+                            Bucket::default()
+                        }),
+                    );
+                }
+            });
+        }
+        let started = Instant::now();
+        let writers = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    for key in keys {
+                        store.record(&SledTree::DEFAULT, key, 12.5, None);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let elapsed = started.elapsed();
+        done.store(true, Ordering::Relaxed);
+        elapsed
+    })
+}
+
+fn contention(c: &mut Criterion) {
+    let keys = keys(RECORDS);
+    let mut group = c.benchmark_group("summary_store_insert");
+    for threads in [1, 2, 4, 8] {
+        for (name, reading) in [("writers", false), ("writers_and_reader", true)] {
+            group.bench_with_input(BenchmarkId::new(name, threads), &threads, |b, &threads| {
+                b.iter_custom(|iters| (0..iters).map(|_| insert(threads, &keys, reading)).sum());
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, contention);
+criterion_main!(benches);
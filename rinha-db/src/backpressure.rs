@@ -0,0 +1,79 @@
+use std::{
+    env,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::unapplied::Ticket;
+
+/// Turns writers away once too many writes are queued or being applied, with a delay to wait
+/// before retrying, so they slow down instead of timing out and sending the same records
+/// again.
+pub struct Backpressure {
+    /// Writes admitted and not applied yet.
+    in_flight: AtomicUsize,
+    high_water: usize,
+    retry_after: Duration,
+}
+
+impl Backpressure {
+    /// From `BACKPRESSURE_HIGH_WATER`, how many writes may be in flight, and
+    /// `BACKPRESSURE_RETRY_MS`, the delay suggested to writers turned away. `None` when the
+    /// high-water mark is 0.
+    pub fn from_env() -> anyhow::Result<Option<Arc<Self>>> {
+        let high_water: usize = env::var("BACKPRESSURE_HIGH_WATER")
+            .unwrap_or("16384".to_string())
+            .parse()?;
+        let retry_after: u64 = env::var("BACKPRESSURE_RETRY_MS")
+            .unwrap_or("100".to_string())
+            .parse()?;
+        Ok((high_water > 0).then(|| {
+            Arc::new(Self {
+                in_flight: AtomicUsize::new(0),
+                high_water,
+                retry_after: Duration::from_millis(retry_after),
+            })
+        }))
+    }
+
+    /// Admit `records` writes, or the delay to suggest when they'd pass the high-water mark. A
+    /// batch larger than the mark is still admitted when nothing else is in flight.
+    pub fn admit(self: &Arc<Self>, records: usize, ticket: Ticket) -> Result<Admitted, Duration> {
+        let in_flight = self.in_flight.fetch_add(records, Ordering::AcqRel);
+        if in_flight > 0 && in_flight + records > self.high_water {
+            self.in_flight.fetch_sub(records, Ordering::AcqRel);
+            return Err(self.retry_after);
+        }
+        Ok(Admitted {
+            writes: Some((self.clone(), records)),
+            _ticket: ticket,
+        })
+    }
+}
+
+/// Writes admitted and not applied yet, counted off and their ticket released once dropped.
+pub struct Admitted {
+    writes: Option<(Arc<Backpressure>, usize)>,
+    _ticket: Ticket,
+}
+
+impl Admitted {
+    /// For writes taken without checking, when backpressure is off.
+    pub fn unchecked(ticket: Ticket) -> Self {
+        Self {
+            writes: None,
+            _ticket: ticket,
+        }
+    }
+}
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        if let Some((backpressure, records)) = self.writes.take() {
+            backpressure.in_flight.fetch_sub(records, Ordering::AcqRel);
+        }
+    }
+}
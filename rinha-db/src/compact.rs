@@ -0,0 +1,152 @@
+use std::{collections::BTreeMap, env, time::Duration};
+
+use chrono::{DateTime, Utc};
+use shared_types::AggregateDelta;
+use sled::{
+    IVec, Transactional,
+    transaction::{ConflictableTransactionError, TransactionError},
+};
+use tracing::{error, info};
+
+use crate::{AppState, keys, replication::Replicated, storage::decode_amount};
+
+#[derive(Clone, Copy, Debug)]
+pub struct CompactConfig {
+    /// How long raw records are kept, `None` keeps them forever.
+    pub retention: Option<Duration>,
+    pub interval: Duration,
+}
+
+impl CompactConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let retention: u64 = env::var("RETENTION_SECS")
+            .unwrap_or("0".to_string())
+            .parse()?;
+
+        Ok(Self {
+            retention: (retention > 0).then(|| Duration::from_secs(retention)),
+            interval: Duration::from_millis(
+                env::var("COMPACT_INTERVAL_MS")
+                    .unwrap_or("60000".to_string())
+                    .parse()?,
+            ),
+        })
+    }
+}
+
+impl AppState {
+    /// Fold every record requested before `before` milliseconds, rounded down to the start of
+    /// an in-memory bucket, into the aggregate buckets and drop it along with its index entry. Each tree is
+    /// compacted in one transaction, so a crash can't count a record both ways.
+    pub fn compact(&self, before: u64) -> sled::Result<usize> {
+        let before = self.store.granularity().floor(before);
+        self.accepted(
+            || self.compact_trees(before),
+            |&compacted| {
+                (compacted > 0)
+                    .then_some(Replicated::Compact(before))
+                    .into_iter()
+                    .collect()
+            },
+        )
+    }
+
+    fn compact_trees(&self, before: u64) -> sled::Result<usize> {
+        let mut compacted = 0;
+        for (tree, provider) in self.providers.all() {
+            let keys = provider
+                .records
+                .range(keys::range(0, before.saturating_sub(1)))
+                .keys()
+                .collect::<sled::Result<Vec<_>>>()?;
+            if keys.is_empty() {
+                continue;
+            }
+
+            let trees = (&provider.records, &provider.buckets, &self.index);
+            let removed = trees
+                .transaction(|(records, buckets, index)| {
+                    let mut removed = Vec::new();
+                    let mut seconds = BTreeMap::<String, (i64, f64)>::new();
+                    for key in &keys {
+                        let Some(value) = records.remove(key)? else {
+                            continue;
+                        };
+                        let Some(stored) = decode_amount(key, &value) else {
+                            // Left where it is rather than folded in as nothing.
+                            records.insert(key, value)?;
+                            continue;
+                        };
+                        let (requests, amount) = if keys::is_void(key) {
+                            (-1, -stored)
+                        } else {
+                            (1, stored)
+                        };
+                        let bucket = seconds.entry(bucket_of(key)).or_default();
+                        bucket.0 += requests;
+                        bucket.1 += amount;
+                        if let Some((correlation_id, _)) = keys::index_entry(&tree, key) {
+                            index.remove(&correlation_id)?;
+                        }
+                        removed.push((key.clone(), stored));
+                    }
+                    for (bucket, (requests, amount)) in seconds {
+                        let delta = AggregateDelta {
+                            tree: tree.clone(),
+                            bucket,
+                            requests,
+                            amount,
+                        };
+                        let stored = buckets.get(delta.bucket.as_bytes())?;
+                        buckets.insert(delta.bucket.as_bytes(), delta.merge(stored.as_deref()))?;
+                    }
+                    Ok::<_, ConflictableTransactionError<()>>(removed)
+                })
+                .map_err(|e| match e {
+                    TransactionError::Storage(e) => e,
+                    TransactionError::Abort(()) => unreachable!("compaction never aborts"),
+                })?;
+
+            self.store.compacted(&tree, before, &removed);
+            compacted += removed.len();
+        }
+        Ok(compacted)
+    }
+}
+
+/// The aggregate bucket a stored key falls in.
+fn bucket_of(key: &IVec) -> String {
+    let millis = keys::millis(key).unwrap_or_default();
+    DateTime::<Utc>::from_timestamp_millis(millis as i64)
+        .unwrap_or_default()
+        .format("%Y-%m-%dT%H:%M:%S")
+        .to_string()
+}
+
+/// Compact the records older than `RETENTION_SECS` every `COMPACT_INTERVAL_MS`.
+pub fn spawn(state: AppState, config: CompactConfig) {
+    let Some(retention) = config.retention else {
+        return;
+    };
+    info!(
+        "Compacting records older than {}s into aggregate buckets",
+        retention.as_secs()
+    );
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(config.interval);
+
+        loop {
+            ticker.tick().await;
+
+            let before = keys::to_millis(Utc::now()).saturating_sub(retention.as_millis() as u64);
+            let state = state.clone();
+            match tokio::task::spawn_blocking(move || state.compact(before)).await {
+                Ok(Ok(0)) => {}
+                Ok(Ok(compacted)) => info!("Compacted {compacted} records"),
+                Ok(Err(e)) => error!("Error compacting records: {}", e),
+                Err(e) => error!("Compaction task failed: {}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,276 @@
+use std::{collections::BTreeMap, env, sync::Arc};
+
+use shared_types::{DBWrite, SledTree, Summary};
+use sled::{Db, Tree};
+
+use crate::{
+    keys, metrics::Metrics, providers::Providers, range::TimeRange, storage::Bucket,
+    storage::SummaryStore, storage::decode_amount, storage_key, value::StoredValue,
+};
+
+/// Which engine stores payment records, from `DB_ENGINE`, or `STORAGE` when that's unset.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Engine {
+    Sled,
+    /// Append-only segment files, see [`crate::segments`].
+    Segments,
+    /// Nothing written to disk, see [`crate::memory`].
+    Memory,
+}
+
+impl Engine {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let engine = env::var("DB_ENGINE").or_else(|_| env::var("STORAGE"));
+        match engine.unwrap_or("sled".to_string()).as_str() {
+            "sled" => Ok(Engine::Sled),
+            "segments" => Ok(Engine::Segments),
+            "memory" => Ok(Engine::Memory),
+            other => {
+                anyhow::bail!("invalid DB_ENGINE {other:?}, expected sled, segments or memory")
+            }
+        }
+    }
+}
+
+/// Where payment records are written and summed. The write path, summaries and purges go
+/// through it, so engines can be swapped at startup and benchmarked against each other
+/// without touching the handlers.
+pub trait PaymentStore: Send + Sync {
+    fn insert(&self, write: &DBWrite) -> sled::Result<()>;
+
+    /// Store every write, each provider's all at once.
+    fn insert_batch(&self, writes: &[DBWrite]) -> sled::Result<()>;
+
+    /// Totals of the provider's records requested within the range.
+    fn summary(&self, tree: &SledTree, range: &TimeRange) -> Summary;
+
+    /// Remove every record of the provider, returning how many were removed.
+    fn purge(&self, tree: &SledTree) -> sled::Result<usize>;
+
+    /// Remove the provider's records requested between `from` and `to` milliseconds, both
+    /// inclusive, returning how many were removed. Aggregate buckets are left alone.
+    fn purge_range(&self, tree: &SledTree, range: (u64, u64)) -> sled::Result<usize>;
+
+    /// Remove every provider's records, returning how many each had.
+    fn purge_all(&self) -> sled::Result<Vec<(SledTree, usize)>>;
+
+    /// Make every write applied so far durable.
+    fn flush(&self) -> sled::Result<()>;
+
+    /// Make every write durable before the process exits, a flush unless the engine has a
+    /// better way.
+    fn close(&self) -> sled::Result<()> {
+        self.flush()
+    }
+}
+
+/// Records in sled, one tree per provider, with the in-memory per-second totals and the
+/// correlation id index kept alongside.
+pub struct SledStore {
+    pub db: Db,
+    pub providers: Arc<Providers>,
+    pub store: Arc<SummaryStore>,
+    pub index: Tree,
+    pub metrics: Arc<Metrics>,
+}
+
+impl PaymentStore for SledStore {
+    fn insert(&self, write: &DBWrite) -> sled::Result<()> {
+        let key = storage_key(write)?;
+        let value = StoredValue::new(write.value, &key, &write.tree);
+        let replaced = self
+            .providers
+            .get_or_open(&write.tree)?
+            .records
+            .insert(key.as_slice(), value.encode())?;
+        let replaced = replaced.and_then(|value| decode_amount(&key, &value));
+        self.store
+            .record(&write.tree, &key, value.amount(), replaced);
+        if let Some((correlation_id, value)) = keys::index_entry(&write.tree, &key) {
+            self.index.insert(correlation_id, value)?;
+        }
+        self.metrics.record_inserts(1);
+        Ok(())
+    }
+
+    /// Write every record with one sled batch per tree, so a batch lands in full or not at all
+    /// on each tree. Every key is checked before anything is written, and a key written more
+    /// than once in the batch keeps its last value.
+    fn insert_batch(&self, writes: &[DBWrite]) -> sled::Result<()> {
+        let mut by_tree = BTreeMap::<&SledTree, BTreeMap<Vec<u8>, StoredValue>>::new();
+        for write in writes {
+            let key = storage_key(write)?;
+            let value = StoredValue::new(write.value, &key, &write.tree);
+            by_tree.entry(&write.tree).or_default().insert(key, value);
+        }
+        let by_tree = by_tree
+            .into_iter()
+            .map(|(tree, writes)| Ok((tree, self.providers.get_or_open(tree)?.records, writes)))
+            .collect::<sled::Result<Vec<_>>>()?;
+
+        for (tree, records, writes) in by_tree {
+            let mut batch = sled::Batch::default();
+            let mut index = sled::Batch::default();
+            let mut written = Vec::new();
+            for (key, value) in writes {
+                // Overwritten records must come off the summary buckets.
+                let replaced = records
+                    .get(&key)?
+                    .and_then(|value| decode_amount(&key, &value));
+                batch.insert(key.as_slice(), value.encode());
+                if let Some((correlation_id, value)) = keys::index_entry(tree, &key) {
+                    index.insert(&correlation_id, value);
+                }
+                written.push((key, value.amount(), replaced));
+            }
+            records.apply_batch(batch)?;
+            self.index.apply_batch(index)?;
+            self.metrics.record_batch(written.len());
+            for (key, amount, replaced) in written {
+                self.store.record(tree, &key, amount, replaced);
+            }
+        }
+        Ok(())
+    }
+
+    /// Served from the in-memory totals, with sled only read for the partial seconds at either
+    /// end of the range and the aggregate buckets it touches.
+    fn summary(&self, tree: &SledTree, range: &TimeRange) -> Summary {
+        let Some(trees) = self.providers.get(tree) else {
+            return Summary::new();
+        };
+        if range.covers_everything() {
+            return self.store.total(tree);
+        }
+
+        let mut summary = self.store.summary(tree, range.millis(), |keys| {
+            Bucket::sum(trees.records.range(keys))
+        });
+        summary.add_buckets(trees.buckets.range(range.buckets()));
+        summary
+    }
+
+    /// Also removes the provider's aggregate buckets and index entries.
+    fn purge(&self, tree: &SledTree) -> sled::Result<usize> {
+        let Some(trees) = self.providers.get(tree) else {
+            return Ok(0);
+        };
+        let records = &trees.records;
+        let mut index = sled::Batch::default();
+        let mut removed = 0;
+        for key in records.iter().keys() {
+            if let Some((correlation_id, _)) = keys::index_entry(tree, &key?) {
+                index.remove(&correlation_id);
+            }
+            removed += 1;
+        }
+        self.index.apply_batch(index)?;
+        records.clear()?;
+        trees.buckets.clear()?;
+        self.store.clear(tree);
+        Ok(removed)
+    }
+
+    /// Along with the index entries, in one batch per tree.
+    fn purge_range(&self, tree: &SledTree, (from, to): (u64, u64)) -> sled::Result<usize> {
+        let Some(trees) = self.providers.get(tree) else {
+            return Ok(0);
+        };
+        let records = &trees.records;
+        let keys = records
+            .range(keys::range(from, to))
+            .keys()
+            .collect::<sled::Result<Vec<_>>>()?;
+        self.store
+            .purge_range(tree, (from, to), |keys| Bucket::sum(records.range(keys)));
+
+        let mut batch = sled::Batch::default();
+        let mut index = sled::Batch::default();
+        for key in &keys {
+            batch.remove(key);
+            if let Some((correlation_id, _)) = keys::index_entry(tree, key) {
+                index.remove(&correlation_id);
+            }
+        }
+        records.apply_batch(batch)?;
+        self.index.apply_batch(index)?;
+        Ok(keys.len())
+    }
+
+    fn purge_all(&self) -> sled::Result<Vec<(SledTree, usize)>> {
+        self.providers
+            .all()
+            .into_iter()
+            .map(|(tree, _)| Ok((tree.clone(), self.purge(&tree)?)))
+            .collect()
+    }
+
+    fn flush(&self) -> sled::Result<()> {
+        self.db.flush().map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared_types::{Ack, payment_key};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::storage::Granularity;
+
+    fn store() -> SledStore {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        SledStore {
+            providers: Arc::new(Providers::open(&db).unwrap()),
+            store: Arc::new(SummaryStore::new(Granularity::Second)),
+            index: db.open_tree("index").unwrap(),
+            metrics: Arc::new(Metrics::new()),
+            db,
+        }
+    }
+
+    fn write(tree: SledTree, id: u128, value: f64) -> DBWrite {
+        DBWrite {
+            key: payment_key("2025-07-15T12:00:00.000Z", &Uuid::from_u128(id)),
+            value,
+            tree,
+            ack: Ack::default(),
+            trace_id: None,
+            traceparent: None,
+        }
+    }
+
+    fn total(store: &SledStore, tree: &SledTree) -> (u64, f64) {
+        let total = store.store.total(tree);
+        (total.total_requests, total.total_amount)
+    }
+
+    #[test]
+    fn a_key_written_twice_in_a_batch_counts_once() {
+        let store = store();
+        store
+            .insert_batch(&[
+                write(SledTree::DEFAULT, 1, 2.0),
+                write(SledTree::DEFAULT, 2, 1.0),
+                write(SledTree::DEFAULT, 1, 3.0),
+            ])
+            .unwrap();
+        assert_eq!(total(&store, &SledTree::DEFAULT), (2, 4.0));
+        let records = store.providers.get(&SledTree::DEFAULT).unwrap().records;
+        let stored = Summary::from(Bucket::sum(records.iter()));
+        assert_eq!((stored.total_requests, stored.total_amount), (2, 4.0));
+    }
+
+    #[test]
+    fn a_bad_write_in_any_tree_writes_nothing() {
+        let store = store();
+        let mut bad = write(SledTree::FALLBACK, 2, 1.0);
+        bad.key = "not a key".to_string();
+        let writes = [write(SledTree::DEFAULT, 1, 2.0), bad];
+        assert!(store.insert_batch(&writes).is_err());
+
+        assert_eq!(total(&store, &SledTree::DEFAULT), (0, 0.0));
+        let records = store.providers.get_or_open(&SledTree::DEFAULT).unwrap();
+        assert!(records.records.is_empty());
+    }
+}
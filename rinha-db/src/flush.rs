@@ -0,0 +1,171 @@
+use std::{
+    env,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::time;
+use tracing::error;
+
+use crate::{engine::PaymentStore, metrics::Metrics};
+
+/// When accepted writes are made durable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// Only by the periodic flush.
+    Async,
+    /// Before the write is acknowledged.
+    FlushEveryWrite,
+    /// By the periodic flush, and before a summary is computed, even without `SUMMARY_FLUSH`.
+    FlushOnSummary,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FlushConfig {
+    /// How often pending writes are flushed, `None` leaves it to sled.
+    pub interval: Option<Duration>,
+    pub durability: Durability,
+    /// Whether a summary waits for the writes accepted before it and flushes them first.
+    pub on_summary: bool,
+}
+
+impl FlushConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let interval: u64 = env::var("FLUSH_INTERVAL_MS")
+            .unwrap_or("100".to_string())
+            .parse()?;
+        let durability = match env::var("DURABILITY")
+            .unwrap_or("async".to_string())
+            .as_str()
+        {
+            "async" => Durability::Async,
+            "flush_every_write" => Durability::FlushEveryWrite,
+            "flush_on_summary" => Durability::FlushOnSummary,
+            other => anyhow::bail!(
+                "invalid DURABILITY {other:?}, expected async, flush_every_write or flush_on_summary"
+            ),
+        };
+
+        let on_summary: bool = env::var("SUMMARY_FLUSH")
+            .unwrap_or("true".to_string())
+            .parse()?;
+
+        Ok(Self {
+            interval: (interval > 0).then(|| Duration::from_millis(interval)),
+            durability,
+            on_summary,
+        })
+    }
+}
+
+/// Flushes the payment store according to the durability mode, skipping it when nothing was
+/// written since the last flush.
+pub struct Flusher {
+    payments: Arc<dyn PaymentStore>,
+    config: FlushConfig,
+    dirty: AtomicBool,
+    /// Set once the periodic flush started.
+    running: AtomicBool,
+    metrics: Arc<Metrics>,
+}
+
+impl Flusher {
+    pub fn new(
+        payments: Arc<dyn PaymentStore>,
+        config: FlushConfig,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            payments,
+            config,
+            dirty: AtomicBool::new(false),
+            running: AtomicBool::new(false),
+            metrics,
+        }
+    }
+
+    /// Called once a write was applied, before it is acknowledged.
+    pub fn written(&self) -> sled::Result<()> {
+        self.dirty.store(true, Ordering::Release);
+        match self.config.durability {
+            Durability::FlushEveryWrite => self.flush(),
+            Durability::Async | Durability::FlushOnSummary => Ok(()),
+        }
+    }
+
+    /// Whether summaries wait for the writes accepted before them, from `SUMMARY_FLUSH`.
+    pub fn on_summary(&self) -> bool {
+        self.config.on_summary
+    }
+
+    /// Called before a summary is computed.
+    pub fn before_summary(&self) {
+        if self.config.on_summary || self.config.durability == Durability::FlushOnSummary {
+            if let Err(e) = self.flush() {
+                error!("Error flushing before a summary: {}", e);
+            }
+        }
+    }
+
+    /// Whether writes are being flushed, always true when that's left to sled.
+    pub fn running(&self) -> bool {
+        self.config.interval.is_none() || self.running.load(Ordering::Acquire)
+    }
+
+    /// Make the writes applied so far durable, for a write only acknowledged once it's on disk.
+    /// Flushes even when another flush is under way, which may not cover it yet.
+    pub fn durable(&self) -> sled::Result<()> {
+        if self.config.durability == Durability::FlushEveryWrite {
+            return Ok(());
+        }
+        let started = Instant::now();
+        self.payments.flush()?;
+        self.metrics.flush.record(started.elapsed());
+        Ok(())
+    }
+
+    /// Flush the writes applied since the last flush, if any.
+    pub fn flush(&self) -> sled::Result<()> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let started = Instant::now();
+        if let Err(e) = self.payments.flush() {
+            self.dirty.store(true, Ordering::Release);
+            return Err(e);
+        }
+        self.metrics.flush.record(started.elapsed());
+        Ok(())
+    }
+
+    /// The last flush before exiting, whether or not anything was written since the previous.
+    pub fn close(&self) -> sled::Result<()> {
+        let started = Instant::now();
+        self.payments.close()?;
+        self.metrics.flush.record(started.elapsed());
+        Ok(())
+    }
+
+    /// Flush pending writes every `FLUSH_INTERVAL_MS`.
+    pub fn spawn(self: Arc<Self>) {
+        let Some(interval) = self.config.interval else {
+            return;
+        };
+
+        tokio::spawn(async move {
+            let mut ticker = time::interval(interval);
+            self.running.store(true, Ordering::Release);
+
+            loop {
+                ticker.tick().await;
+
+                if let Err(e) = self.flush() {
+                    error!("Error flushing: {}", e);
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,152 @@
+use std::{env, time::Duration};
+
+use shared_types::{Ack, DBWrite};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tracing::{error, info};
+
+use crate::{AppState, backpressure::Admitted, storage_key};
+
+/// Most writes applied in one group.
+const MAX_GROUP: usize = 1024;
+
+type Pending = (DBWrite, Admitted, oneshot::Sender<Result<(), String>>);
+
+/// Single writes waiting to be applied together, see [`spawn`].
+#[derive(Clone)]
+pub struct GroupCommit {
+    tx: mpsc::Sender<Pending>,
+}
+
+impl GroupCommit {
+    /// The window writes are grouped over, from `GROUP_COMMIT_US`. `None` when it's 0, which
+    /// applies every write on its own.
+    pub fn window_from_env() -> anyhow::Result<Option<Duration>> {
+        let window: u64 = env::var("GROUP_COMMIT_US")
+            .unwrap_or("1000".to_string())
+            .parse()?;
+        Ok((window > 0).then(|| Duration::from_micros(window)))
+    }
+
+    pub fn channel() -> (Self, mpsc::Receiver<Pending>) {
+        let (tx, rx) = mpsc::channel(MAX_GROUP * 4);
+        (Self { tx }, rx)
+    }
+
+    /// Queue a write without waiting for it to be applied.
+    pub async fn enqueue(&self, write: DBWrite, admitted: Admitted) -> Result<(), String> {
+        let (reply, _) = oneshot::channel();
+        self.tx
+            .send((write, admitted, reply))
+            .await
+            .map_err(|_| "group commit stopped".to_string())
+    }
+
+    /// Queue a write, resolving once the group holding it was applied, and flushed if it asks
+    /// for that.
+    pub async fn submit(&self, write: DBWrite, admitted: Admitted) -> Result<(), String> {
+        let (reply, applied) = oneshot::channel();
+        self.tx
+            .send((write, admitted, reply))
+            .await
+            .map_err(|_| "group commit stopped".to_string())?;
+        applied
+            .await
+            .map_err(|_| "group commit dropped the write".to_string())?
+    }
+}
+
+/// The task applying grouped writes.
+pub struct Running {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Running {
+    /// Stop taking writes and return once every write already queued was applied.
+    pub async fn drain(self) {
+        let _ = self.stop.send(());
+        if let Err(e) = self.task.await {
+            error!("Group commit task failed: {}", e);
+        }
+    }
+}
+
+/// Apply the writes queued within `window` of the first one as a single batch, so they share
+/// one sled batch, one replication log entry and at most one flush, and acknowledge them all
+/// once it's done.
+pub fn spawn(state: AppState, mut rx: mpsc::Receiver<Pending>, window: Duration) -> Running {
+    info!("Grouping single writes over {}us", window.as_micros());
+
+    let (stop, mut stopped) = oneshot::channel();
+    let task = tokio::spawn(async move {
+        let mut stopping = false;
+        loop {
+            let first = tokio::select! {
+                pending = rx.recv() => match pending {
+                    Some(pending) => pending,
+                    None => break,
+                },
+                // Closing refuses new writes but still hands out the queued ones.
+                _ = &mut stopped, if !stopping => {
+                    rx.close();
+                    stopping = true;
+                    continue;
+                }
+            };
+            let mut group = vec![first];
+            let deadline = Instant::now() + window;
+            while group.len() < MAX_GROUP {
+                match time::timeout_at(deadline, rx.recv()).await {
+                    Ok(Some(pending)) => group.push(pending),
+                    Ok(None) | Err(_) => break,
+                }
+            }
+
+            let state = state.clone();
+            if let Err(e) = tokio::task::spawn_blocking(move || commit(&state, group)).await {
+                error!("Group commit task failed: {}", e);
+            }
+        }
+    });
+    Running { stop, task }
+}
+
+fn commit(state: &AppState, group: Vec<Pending>) {
+    // A write that can't be stored fails on its own instead of taking the group down with it.
+    let mut writes = Vec::with_capacity(group.len());
+    let mut replies = Vec::with_capacity(group.len());
+    // Dropped once the group was applied, which takes its writes off the ones in flight.
+    let mut admitted = Vec::with_capacity(group.len());
+    for (write, admission, reply) in group {
+        admitted.push(admission);
+        let valid =
+            storage_key(&write).and_then(|_| state.providers.get_or_open(&write.tree).map(|_| ()));
+        match valid {
+            Ok(()) => {
+                writes.push(write);
+                replies.push(reply);
+            }
+            Err(e) => {
+                let _ = reply.send(Err(e.to_string()));
+            }
+        }
+    }
+    if writes.is_empty() {
+        return;
+    }
+
+    // One flush covers every write of the group waiting for it.
+    let flushed = writes.iter().any(|write| write.ack == Ack::Flushed);
+    let mut result = state.insert_batch(&writes);
+    if flushed && result.is_ok() {
+        result = state.flusher.durable();
+    }
+    let result = result.map_err(|e| e.to_string());
+    for reply in replies {
+        let _ = reply.send(result.clone());
+    }
+}
@@ -0,0 +1,64 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use axum::{
+    Json,
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use tracing::info;
+
+use crate::AppState;
+
+/// What `/readyz` waits for past startup. Sled is opened and the summary store rebuilt from it
+/// before HTTP is served at all, so those need no flag.
+pub struct Readiness {
+    /// Cleared on followers until they caught up with the primary once.
+    caught_up: AtomicBool,
+}
+
+impl Readiness {
+    pub fn new(follower: bool) -> Self {
+        Self {
+            caught_up: AtomicBool::new(!follower),
+        }
+    }
+
+    /// Called by a follower once it applied every change the primary had when it connected.
+    pub fn caught_up(&self) {
+        if !self.caught_up.swap(true, Ordering::AcqRel) {
+            info!("Caught up with the primary");
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ReadyReport {
+    ready: bool,
+    flusher: bool,
+    #[serde(rename = "caughtUp")]
+    caught_up: bool,
+}
+
+/// The process is up and serving.
+pub async fn healthz() -> StatusCode {
+    StatusCode::OK
+}
+
+/// 200 once the store can take traffic, 503 with what it's still waiting for otherwise.
+pub async fn readyz(State(state): State<AppState>) -> Response {
+    let flusher = state.flusher.running();
+    let caught_up = state.readiness.caught_up.load(Ordering::Acquire);
+    let report = ReadyReport {
+        ready: flusher && caught_up,
+        flusher,
+        caught_up,
+    };
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report)).into_response()
+}
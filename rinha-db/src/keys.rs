@@ -0,0 +1,175 @@
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+use shared_types::{SledTree, is_void_key, split_payment_key};
+use sled::Tree;
+use tracing::warn;
+
+use crate::providers::ProviderTrees;
+use uuid::Uuid;
+
+/// Stored keys are the big-endian `requestedAt` in milliseconds followed by the correlation
+/// id, so range scans stay ordered by time and payments in the same millisecond don't
+/// overwrite each other. Void records add a trailing marker and sort right after the
+/// payment they offset.
+const PAYMENT_KEY_LEN: usize = 24;
+const VOID_MARKER: u8 = 1;
+
+/// An RFC 3339 timestamp, or one without an offset taken as UTC.
+pub fn parse_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    match DateTime::parse_from_rfc3339(timestamp) {
+        Ok(at) => Some(at.to_utc()),
+        Err(_) => NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%M:%S%.f")
+            .ok()
+            .map(|at| at.and_utc()),
+    }
+}
+
+/// Milliseconds since the epoch, instants before it clamp to it.
+pub fn to_millis(at: DateTime<Utc>) -> u64 {
+    at.timestamp_millis().max(0) as u64
+}
+
+fn parse_millis(timestamp: &str) -> Option<u64> {
+    parse_timestamp(timestamp).map(to_millis)
+}
+
+fn encode(millis: u64, correlation_id: &Uuid, void: bool) -> Vec<u8> {
+    let mut key = Vec::with_capacity(PAYMENT_KEY_LEN + 1);
+    key.extend_from_slice(&millis.to_be_bytes());
+    key.extend_from_slice(correlation_id.as_bytes());
+    if void {
+        key.push(VOID_MARKER);
+    }
+    key
+}
+
+/// Storage key for the string key of a `DBWrite`.
+pub fn from_write_key(key: &str) -> Option<Vec<u8>> {
+    let (requested_at, correlation_id) = split_payment_key(key.as_bytes());
+    let correlation_id = Uuid::parse_str(&correlation_id?).ok()?;
+    Some(encode(
+        parse_millis(&requested_at)?,
+        &correlation_id,
+        is_void_key(key.as_bytes()),
+    ))
+}
+
+pub fn millis(key: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(key.get(..8)?.try_into().ok()?))
+}
+
+pub fn is_void(key: &[u8]) -> bool {
+    key.len() == PAYMENT_KEY_LEN + 1 && key[PAYMENT_KEY_LEN] == VOID_MARKER
+}
+
+/// Timestamp and correlation id of a stored key. Payments migrated from keys without an id
+/// carry a placeholder one, which reads back as `None`.
+pub fn decode(key: &[u8]) -> Option<(String, Option<String>)> {
+    let requested_at = DateTime::<Utc>::from_timestamp_millis(millis(key)? as i64)?
+        .to_rfc3339_opts(SecondsFormat::Millis, true);
+    key.get(8..PAYMENT_KEY_LEN)?;
+    Some((requested_at, correlation_id(key).map(|id| id.to_string())))
+}
+
+/// The correlation id a stored key carries, unless it's a placeholder.
+pub fn correlation_id(key: &[u8]) -> Option<Uuid> {
+    let correlation_id = Uuid::from_slice(key.get(8..PAYMENT_KEY_LEN)?).ok()?;
+    (correlation_id.as_u64_pair().0 != 0).then_some(correlation_id)
+}
+
+/// Byte bounds covering every key requested between `from` and `to` milliseconds, both
+/// inclusive.
+pub fn range(from: u64, to: u64) -> std::ops::Range<Vec<u8>> {
+    from.to_be_bytes().to_vec()..to.saturating_add(1).to_be_bytes().to_vec()
+}
+
+/// Rewrite the RFC 3339 string keys trees used to hold into the binary layout, in one batch.
+/// Keys that can't be parsed are left alone, and are never part of a range scan since they
+/// sort after every binary key.
+pub fn migrate(tree: &Tree) -> sled::Result<usize> {
+    let mut batch = sled::Batch::default();
+    let (mut migrated, mut placeholders, mut skipped) = (0, 0u64, 0);
+    for entry in tree.iter() {
+        let (key, value) = entry?;
+        // Binary keys start with the top byte of the millis, which stays zero for the next
+        // two million years. String keys start with a digit.
+        if key.first() == Some(&0) {
+            continue;
+        }
+        let (requested_at, correlation_id) = split_payment_key(&key);
+        let Some(millis) = parse_millis(&requested_at) else {
+            skipped += 1;
+            continue;
+        };
+        let correlation_id = match correlation_id.and_then(|id| Uuid::parse_str(&id).ok()) {
+            Some(id) => id,
+            // Older keys had no id, a zero upper half marks the ones standing in for it.
+            None => {
+                placeholders += 1;
+                Uuid::from_u64_pair(0, placeholders)
+            }
+        };
+        batch.remove(&key);
+        batch.insert(encode(millis, &correlation_id, is_void_key(&key)), value);
+        migrated += 1;
+    }
+
+    if skipped > 0 {
+        warn!("Left {skipped} unreadable keys in place while migrating the keys");
+    }
+    if migrated > 0 {
+        tree.apply_batch(batch)?;
+    }
+    Ok(migrated)
+}
+
+/// Entry of the correlation id index for a payment stored under `key`: the id, and the
+/// provider holding the record followed by its key. Void records and placeholder ids aren't
+/// indexed.
+pub fn index_entry(tree: &SledTree, key: &[u8]) -> Option<([u8; 16], Vec<u8>)> {
+    if key.len() != PAYMENT_KEY_LEN {
+        return None;
+    }
+    let correlation_id: [u8; 16] = key[8..].try_into().ok()?;
+    if Uuid::from_bytes(correlation_id).as_u64_pair().0 == 0 {
+        return None;
+    }
+    let mut value = Vec::with_capacity(tree.name().len() + PAYMENT_KEY_LEN);
+    value.extend_from_slice(tree.name().as_bytes());
+    value.extend_from_slice(key);
+    Some((correlation_id, value))
+}
+
+/// Provider and key an index entry points at.
+pub fn decode_index(value: &[u8]) -> Option<(SledTree, &[u8])> {
+    let (tree, key) = value.split_at(value.len().checked_sub(PAYMENT_KEY_LEN)?);
+    let tree = match tree {
+        // Entries written before providers were named.
+        [0] => SledTree::DEFAULT,
+        [1] => SledTree::FALLBACK,
+        name => SledTree::new(std::str::from_utf8(name).ok()?),
+    };
+    Some((tree, key))
+}
+
+/// Key of the void record offsetting the payment stored under `key`.
+pub fn void_of(key: &[u8]) -> Vec<u8> {
+    let mut void = key.to_vec();
+    void.push(VOID_MARKER);
+    void
+}
+
+/// Index every payment of `providers`, for databases written before the index existed.
+pub fn reindex(index: &Tree, providers: &[(SledTree, ProviderTrees)]) -> sled::Result<usize> {
+    let mut batch = sled::Batch::default();
+    let mut indexed = 0;
+    for (tree, trees) in providers {
+        for key in trees.records.iter().keys() {
+            if let Some((correlation_id, value)) = index_entry(tree, &key?) {
+                batch.insert(&correlation_id, value);
+                indexed += 1;
+            }
+        }
+    }
+    index.apply_batch(batch)?;
+    Ok(indexed)
+}
@@ -0,0 +1,6 @@
+//! The storage modules of rinha-db, also built as a library so the benches can reach them.
+
+pub mod keys;
+pub mod providers;
+pub mod storage;
+pub mod value;
@@ -1,106 +1,1309 @@
-use axum::extract::{Query, State};
-use axum::http::StatusCode;
-use axum::response::IntoResponse;
+mod backpressure;
+mod compact;
+mod engine;
+mod flush;
+mod group_commit;
+mod health;
+mod memory;
+mod metrics;
+mod page;
+mod range;
+mod replication;
+mod segments;
+mod socket;
+mod unapplied;
+
+use axum::body::Body;
+use axum::extract::{Path as UrlPath, Query, Request, State};
+use axum::http::{StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
 use axum::routing::{delete, get};
 use axum::{Json, Router, routing::post};
-use serde_json::json;
-use shared_types::{DBWrite, GlobalSummary, SledTree, Summary};
-use sled::{self, Db, Tree};
-use std::collections::HashMap;
+use backpressure::{Admitted, Backpressure};
+use chrono::{DateTime, SecondsFormat};
+use engine::{Engine, PaymentStore, SledStore};
+use flush::{FlushConfig, Flusher};
+use group_commit::GroupCommit;
+use health::Readiness;
+use memory::MemoryStore;
+use metrics::Metrics;
+use page::PageQuery;
+use providers::{ProviderTrees, Providers};
+use range::RangeQuery;
+use range::SeriesQuery;
+use range::TimeRange;
+use replication::{Replicated, ReplicationLog};
+use rinha_db::{keys, providers, storage, value};
+use segments::SegmentStore;
+use serde::{Deserialize, Serialize};
+use shared_types::{
+    Ack, AggregateDelta, AggregatePush, DBWrite, GlobalSummary, PaymentRecord, PushToken, SledTree,
+    Summary, payment_key, void_key,
+};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::{self, Db, Transactional, Tree};
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::time;
+use std::time::{Duration, Instant};
+use storage::decode_amount;
+use storage::{Granularity, SummaryStore, Verification};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, warn};
+use unapplied::Unapplied;
+use uuid::Uuid;
+
+/// Summary ranges longer than this many seconds are summed in parts.
+const PARALLEL_SUMMARY_SECS: i64 = 3600;
+/// Most parts a summary range is cut into.
+const MAX_SUMMARY_PARTS: usize = 4;
 
 #[derive(Clone)]
 struct AppState {
     db: Db,
-    default_tree: Tree,
-    fallback_tree: Tree,
+    /// Records and aggregate buckets of every provider.
+    providers: Arc<Providers>,
+    /// What summaries are served from, updated on every insert.
+    store: Arc<SummaryStore>,
+    /// Correlation id to the tree and key of its payment.
+    index: Tree,
+    /// Last sequence merged from each worker's aggregate pushes, keyed by the worker's id.
+    pushes: Tree,
+    /// Where payments are written and summed from.
+    payments: Arc<dyn PaymentStore>,
+    engine: Engine,
+    /// Set when followers replicate from this instance.
+    replication: Option<Arc<ReplicationLog>>,
+    /// Set on a follower, the entry of the primary's log it resumes from under `next`.
+    progress: Option<Tree>,
+    flusher: Arc<Flusher>,
+    metrics: Arc<Metrics>,
+    /// Set when single writes are grouped into batches.
+    group_commit: Option<GroupCommit>,
+    /// Shared secret HTTP clients send as `X-Rinha-Token`, unset to leave HTTP open.
+    token: Option<Arc<str>>,
+    readiness: Arc<Readiness>,
+    /// Set with `ROLE=replica`, which refuses every write that doesn't come from the primary.
+    read_only: bool,
+    /// Unset when writers are never turned away.
+    backpressure: Option<Arc<Backpressure>>,
+    /// `Ack::None` writes acknowledged and still being applied, waited for on shutdown.
+    unacknowledged: TaskTracker,
+    /// Writes accepted and not applied yet, waited for before a summary.
+    unapplied: Arc<Unapplied>,
+}
+
+impl AppState {
+    /// How far into the primary's log this instance is: every change logged on a primary,
+    /// every one applied on a follower, `None` without replication.
+    fn position(&self) -> sled::Result<Option<u64>> {
+        match (&self.replication, &self.progress) {
+            (Some(log), _) => Ok(Some(log.head())),
+            (None, Some(progress)) => replication::applied(progress).map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// With `SUMMARY_FLUSH`, wait for the writes accepted so far to be applied, then flush them.
+    async fn before_summary(&self) {
+        if self.flusher.on_summary() {
+            self.unapplied.caught_up().await;
+        }
+        self.flusher.before_summary();
+    }
+
+    async fn summary(&self, range: &TimeRange) -> GlobalSummary {
+        let started = Instant::now();
+        self.before_summary().await;
+        let (default, fallback) = tokio::join!(
+            self.provider_summary(SledTree::DEFAULT, *range),
+            self.provider_summary(SledTree::FALLBACK, *range),
+        );
+        self.metrics.summary.record(started.elapsed());
+        GlobalSummary { default, fallback }
+    }
+
+    /// Summaries of every provider, keyed by the name of its tree.
+    async fn providers_summary(&self, range: &TimeRange) -> BTreeMap<String, Summary> {
+        let started = Instant::now();
+        self.before_summary().await;
+        let providers = self.providers.all().into_iter().map(|(tree, trees)| {
+            let (state, range) = (self.clone(), *range);
+            let summary = tokio::spawn(async move { state.provider_summary(tree, range).await });
+            (trees.name, summary)
+        });
+        let mut summary = BTreeMap::new();
+        for (name, provider) in providers.collect::<Vec<_>>() {
+            summary.insert(name, provider.await.expect("summary task panicked"));
+        }
+        self.metrics.summary.record(started.elapsed());
+        summary
+    }
+
+    /// Totals of one provider. Ranges longer than `PARALLEL_SUMMARY_SECS` are cut into parts
+    /// summed on the blocking pool at the same time.
+    async fn provider_summary(&self, tree: SledTree, range: TimeRange) -> Summary {
+        // Both engines keep the overall totals in memory.
+        if range.covers_everything() {
+            return self.payments.summary(&tree, &range);
+        }
+
+        let parts = range
+            .split(PARALLEL_SUMMARY_SECS, MAX_SUMMARY_PARTS)
+            .into_iter()
+            .map(|part| {
+                let (payments, tree) = (self.payments.clone(), tree.clone());
+                tokio::task::spawn_blocking(move || payments.summary(&tree, &part))
+            })
+            .collect::<Vec<_>>();
+        let mut summary = Summary::new();
+        for part in parts {
+            let part = part.await.expect("summary task panicked");
+            summary.total_requests += part.total_requests;
+            summary.total_amount += part.total_amount;
+        }
+        summary
+    }
+
+    /// Summaries of the range grouped by `interval` seconds, for each provider.
+    async fn series(&self, range: &TimeRange, interval: u64) -> SummarySeries {
+        self.before_summary().await;
+        let series = |tree: &SledTree, trees: &ProviderTrees| {
+            self.store
+                .series(
+                    tree,
+                    &trees.records,
+                    trees.buckets.range(range.buckets()),
+                    range.millis(),
+                    interval,
+                )
+                .into_iter()
+                .map(|(second, summary)| SeriesPoint {
+                    bucket: DateTime::from_timestamp(second as i64, 0)
+                        .unwrap_or_default()
+                        .to_rfc3339_opts(SecondsFormat::Secs, true),
+                    summary,
+                })
+                .collect()
+        };
+        self.providers
+            .all()
+            .into_iter()
+            .map(|(tree, trees)| {
+                let points = series(&tree, &trees);
+                (trees.name, points)
+            })
+            .collect()
+    }
+
+    /// Merge a worker's push, unless its token shows it was merged already, as when the reply
+    /// to it was lost and the worker sent it again. Returns whether it was merged.
+    fn merge_push(&self, push: &AggregatePush) -> sled::Result<bool> {
+        self.merge(&push.deltas, push.token, None)
+    }
+
+    /// Add deltas to their aggregate buckets in one transaction, along with the push's token
+    /// when it has one. A follower passes its progress tree and the entry to resume from,
+    /// committed along with the deltas, since replaying them after a crash would count them
+    /// twice.
+    fn merge(
+        &self,
+        deltas: &[AggregateDelta],
+        token: Option<PushToken>,
+        progress: Option<(&Tree, u64)>,
+    ) -> sled::Result<bool> {
+        self.sled_only("merging aggregates")?;
+        let mut names = Vec::<&SledTree>::new();
+        let mut trees = Vec::new();
+        for delta in deltas {
+            if !names.contains(&&delta.tree) {
+                names.push(&delta.tree);
+                trees.push(self.providers.get_or_open(&delta.tree)?.buckets);
+            }
+        }
+        let progress_at = trees.len();
+        if let Some((progress, _)) = progress {
+            trees.push(progress.clone());
+        }
+        let pushes_at = trees.len();
+        trees.push(self.pushes.clone());
+
+        self.accepted(
+            || {
+                let merged = trees
+                    .as_slice()
+                    .transaction(|trees| {
+                        if let Some(token) = token {
+                            let pushes = &trees[pushes_at];
+                            let last = pushes.get(token.worker.as_bytes())?.and_then(|last| {
+                                Some(u64::from_be_bytes(last.as_ref().try_into().ok()?))
+                            });
+                            if last.is_some_and(|last| last >= token.sequence) {
+                                return Ok(false);
+                            }
+                            pushes
+                                .insert(token.worker.as_bytes(), &token.sequence.to_be_bytes())?;
+                        }
+                        for delta in deltas {
+                            let buckets =
+                                &trees[names.iter().position(|n| **n == delta.tree).unwrap()];
+                            let stored = buckets.get(delta.bucket.as_bytes())?;
+                            buckets
+                                .insert(delta.bucket.as_bytes(), delta.merge(stored.as_deref()))?;
+                        }
+                        if let Some((_, next)) = progress {
+                            trees[progress_at].insert("next", &next.to_be_bytes())?;
+                        }
+                        Ok::<_, ConflictableTransactionError<()>>(true)
+                    })
+                    .map_err(|e| match e {
+                        TransactionError::Storage(e) => e,
+                        TransactionError::Abort(()) => unreachable!("merging never aborts"),
+                    })?;
+                if merged {
+                    for delta in deltas {
+                        self.store
+                            .aggregate(&delta.tree, delta.requests, delta.amount);
+                    }
+                }
+                Ok(merged)
+            },
+            |merged| match merged {
+                true => vec![Replicated::Aggregate(deltas.to_vec())],
+                false => Vec::new(),
+            },
+        )
+    }
+
+    /// Apply a change, then make it durable as the durability mode asks, before it is
+    /// acknowledged. With followers, the changes `logged` returns for what was applied are
+    /// appended to the log in the same step, so no other change is applied in between and the
+    /// log holds them in the order they were applied.
+    fn accepted<T>(
+        &self,
+        apply: impl FnOnce() -> sled::Result<T>,
+        logged: impl FnOnce(&T) -> Vec<Replicated>,
+    ) -> sled::Result<T> {
+        let applied = match &self.replication {
+            Some(log) => log.record(apply, logged)?,
+            None => apply()?,
+        };
+        self.flusher.written()?;
+        Ok(applied)
+    }
+
+    /// Refuse what reads or changes the sled trees directly when payments are stored elsewhere.
+    fn sled_only(&self, what: &str) -> sled::Result<()> {
+        match self.engine {
+            Engine::Sled => Ok(()),
+            Engine::Segments | Engine::Memory => Err(sled::Error::Unsupported(format!(
+                "{what} needs DB_ENGINE=sled"
+            ))),
+        }
+    }
+
+    /// Apply a change streamed from the primary and record `next` in `progress` as the entry to
+    /// resume from. Applying any other change again, after a crash between the two, leaves the
+    /// trees as they were, but aggregates add up, so theirs is recorded in the same transaction.
+    fn apply(&self, change: Replicated, progress: &Tree, next: u64) -> sled::Result<()> {
+        match change {
+            Replicated::Writes(writes) => self.insert_batch(&writes)?,
+            Replicated::Aggregate(deltas) => {
+                return self
+                    .merge(&deltas, None, Some((progress, next)))
+                    .map(|_| ());
+            }
+            Replicated::Purge(tree) => self.purge(&tree).map(|_| ())?,
+            Replicated::PurgeRange(tree, from, to) => {
+                self.purge_range(&tree, (from, to)).map(|_| ())?
+            }
+            Replicated::Compact(before) => self.compact(before).map(|_| ())?,
+            Replicated::Delete(correlation_id) => self.delete(&correlation_id).map(|_| ())?,
+        }
+        progress.insert("next", &next.to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Every record in the range, one provider after the other ordered by name.
+    fn records(&self, range: &TimeRange) -> impl Iterator<Item = sled::Result<PaymentRecord>> {
+        let (from, to) = range.millis();
+        let range = keys::range(from, to);
+        let providers = self.providers.all();
+        providers.into_iter().flat_map(move |(tree, trees)| {
+            trees.records.range(range.clone()).filter_map(move |entry| {
+                let (key, value) = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => return Some(Err(e)),
+                };
+                stored_record(&tree, &key, &value).map(Ok)
+            })
+        })
+    }
+
+    fn insert(&self, write: &DBWrite) -> sled::Result<()> {
+        self.accepted(
+            || self.payments.insert(write),
+            |()| vec![Replicated::Writes(vec![write.clone()])],
+        )
+    }
+
+    /// Store a single write, through the group commit when it's on, returning once it's as
+    /// durable as the write asks.
+    async fn write(&self, write: DBWrite, admitted: Admitted) -> Result<(), String> {
+        match &self.group_commit {
+            Some(group_commit) if write.ack == Ack::None => {
+                group_commit.enqueue(write, admitted).await
+            }
+            Some(group_commit) => group_commit.submit(write, admitted).await,
+            None => self
+                .acknowledged(write.ack, admitted, move |state| state.insert(&write))
+                .map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Store a batch, acknowledged as its most demanding write asks.
+    fn write_batch(&self, writes: Vec<DBWrite>, admitted: Admitted) -> sled::Result<()> {
+        let ack = writes
+            .iter()
+            .map(|write| write.ack)
+            .max()
+            .unwrap_or_default();
+        self.acknowledged(ack, admitted, move |state| state.insert_batch(&writes))
+    }
+
+    /// Apply a change and return once it's as durable as `ack` asks. With `Ack::None` it's
+    /// applied on the blocking pool and only failures are logged. The writes count as in
+    /// flight until they're applied.
+    fn acknowledged(
+        &self,
+        ack: Ack,
+        admitted: Admitted,
+        apply: impl FnOnce(&AppState) -> sled::Result<()> + Send + 'static,
+    ) -> sled::Result<()> {
+        match ack {
+            Ack::None => {
+                let state = self.clone();
+                self.unacknowledged.spawn_blocking(move || {
+                    if let Err(e) = apply(&state) {
+                        error!("Error applying an unacknowledged write: {}", e);
+                    }
+                    drop(admitted);
+                });
+                Ok(())
+            }
+            Ack::Received => apply(self),
+            Ack::Flushed => {
+                apply(self)?;
+                self.flusher.durable()
+            }
+        }
+    }
+
+    /// Admit `records` writes, or the delay to suggest when too many are in flight already.
+    fn admit(&self, records: usize) -> Result<Admitted, Duration> {
+        let ticket = self.unapplied.accept();
+        let Some(backpressure) = &self.backpressure else {
+            return Ok(Admitted::unchecked(ticket));
+        };
+        backpressure.admit(records, ticket).inspect_err(|_| {
+            self.metrics.record_busy(records);
+        })
+    }
+
+    /// The payment stored for a correlation id, through the index.
+    fn lookup(&self, correlation_id: &Uuid) -> sled::Result<Option<PaymentRecord>> {
+        let Some(entry) = self.index.get(correlation_id.as_bytes())? else {
+            return Ok(None);
+        };
+        let Some((tree, key)) = keys::decode_index(&entry) else {
+            return Ok(None);
+        };
+        let Some(records) = self.providers.get(&tree).map(|trees| trees.records) else {
+            return Ok(None);
+        };
+        let (Some(value), Some((requested_at, correlation_id))) =
+            (records.get(key)?, keys::decode(key))
+        else {
+            return Ok(None);
+        };
+        let Some(amount) = decode_amount(key, &value) else {
+            return Ok(None);
+        };
+        Ok(Some(PaymentRecord {
+            tree,
+            requested_at,
+            correlation_id,
+            amount,
+            voided: records.contains_key(keys::void_of(key))?,
+        }))
+    }
+
+    /// Remove the payment stored for a correlation id, along with the void record offsetting it
+    /// and its index entry, in one transaction. Returns the payment as it was.
+    fn delete(&self, correlation_id: &Uuid) -> sled::Result<Option<PaymentRecord>> {
+        self.accepted(
+            || self.remove(correlation_id),
+            |removed| {
+                removed
+                    .iter()
+                    .map(|_| Replicated::Delete(*correlation_id))
+                    .collect()
+            },
+        )
+    }
+
+    fn remove(&self, correlation_id: &Uuid) -> sled::Result<Option<PaymentRecord>> {
+        let Some(entry) = self.index.get(correlation_id.as_bytes())? else {
+            return Ok(None);
+        };
+        let Some((tree, key)) = keys::decode_index(&entry) else {
+            return Ok(None);
+        };
+        let Some(trees) = self.providers.get(&tree) else {
+            return Ok(None);
+        };
+        let void = keys::void_of(key);
+
+        let removed = (&trees.records, &self.index)
+            .transaction(|(records, index)| {
+                let payment = records.remove(key)?;
+                let voided = records.remove(void.as_slice())?;
+                index.remove(correlation_id.as_bytes())?;
+                Ok::<_, ConflictableTransactionError<()>>((payment, voided))
+            })
+            .map_err(|e| match e {
+                TransactionError::Storage(e) => e,
+                TransactionError::Abort(()) => unreachable!("deleting never aborts"),
+            })?;
+        let (Some(payment), voided) = removed else {
+            return Ok(None);
+        };
+
+        // Unreadable values were never counted.
+        if let Some(amount) = decode_amount(key, &payment) {
+            self.store.removed(&tree, key, amount);
+        }
+        if let Some(amount) = voided
+            .as_ref()
+            .and_then(|voided| decode_amount(&void, voided))
+        {
+            self.store.removed(&tree, &void, amount);
+        }
+        Ok(
+            stored_record(&tree, key, &payment).map(|record| PaymentRecord {
+                voided: voided.is_some(),
+                ..record
+            }),
+        )
+    }
+
+    fn insert_batch(&self, writes: &[DBWrite]) -> sled::Result<()> {
+        self.accepted(
+            || self.payments.insert_batch(writes),
+            |()| vec![Replicated::Writes(writes.to_vec())],
+        )
+    }
+
+    /// Remove every record of `tree`, returning how many records were removed.
+    fn purge(&self, tree: &SledTree) -> sled::Result<usize> {
+        self.accepted(
+            || self.payments.purge(tree),
+            |_| vec![Replicated::Purge(tree.clone())],
+        )
+    }
+
+    /// Remove the records of `tree` requested between `from` and `to` milliseconds.
+    fn purge_range(&self, tree: &SledTree, (from, to): (u64, u64)) -> sled::Result<usize> {
+        self.accepted(
+            || self.payments.purge_range(tree, (from, to)),
+            |_| vec![Replicated::PurgeRange(tree.clone(), from, to)],
+        )
+    }
+
+    /// Remove every record of every provider, returning how many each had.
+    fn purge_all(&self) -> sled::Result<Vec<(SledTree, usize)>> {
+        self.accepted(
+            || self.payments.purge_all(),
+            |purged| {
+                purged
+                    .iter()
+                    .map(|(tree, _)| Replicated::Purge(tree.clone()))
+                    .collect()
+            },
+        )
+    }
+}
+
+/// The record stored under `key`, unless the key or its value can't be read back.
+fn stored_record(tree: &SledTree, key: &[u8], value: &[u8]) -> Option<PaymentRecord> {
+    let (requested_at, correlation_id) = keys::decode(key)?;
+    Some(PaymentRecord {
+        tree: tree.clone(),
+        requested_at,
+        correlation_id,
+        amount: decode_amount(key, value)?,
+        voided: keys::is_void(key),
+    })
+}
+
+fn storage_key(write: &DBWrite) -> sled::Result<Vec<u8>> {
+    keys::from_write_key(&write.key)
+        .ok_or_else(|| sled::Error::Unsupported(format!("invalid payment key {:?}", write.key)))
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let db = sled::open("app_db")?;
-    let default_tree = db.open_tree("default")?;
-    let fallback_tree = db.open_tree("fallback")?;
+    shared_types::logging::init("rinha-db")?;
+    let engine = Engine::from_env()?;
+    // The memory engine leaves nothing on disk, sled only keeps the provider registry for it.
+    let db = match engine {
+        Engine::Memory => sled::Config::new().temporary(true).open()?,
+        Engine::Sled | Engine::Segments => sled::open("app_db")?,
+    };
+    let providers = Arc::new(Providers::open(&db)?);
+    let all = providers.all();
+    let mut migrated = 0;
+    for (_, trees) in &all {
+        migrated += keys::migrate(&trees.records)?;
+    }
+    if migrated > 0 {
+        info!("Migrated {migrated} records to binary keys");
+    }
+    let store = Arc::new(SummaryStore::load(&all, Granularity::from_env()?)?);
+    let index = db.open_tree("payment_index")?;
+    let pushes = db.open_tree("aggregate_pushes")?;
+    if index.is_empty() {
+        let indexed = keys::reindex(&index, &all)?;
+        if indexed > 0 {
+            info!("Indexed {indexed} payments by correlation id");
+        }
+    }
 
+    // A primary logs every change it accepts so followers can catch up from where they left.
+    let replication = match env::var("DB_REPLICATION_ADDR") {
+        Ok(addr) => {
+            let retain: u64 = env::var("REPLICATION_RETAIN_SECS")
+                .unwrap_or("86400".to_string())
+                .parse()?;
+            let retain = (retain > 0).then(|| Duration::from_secs(retain));
+            Some((addr, Arc::new(ReplicationLog::open(&db, retain)?)))
+        }
+        Err(_) => None,
+    };
+
+    let read_only = match env::var("ROLE").unwrap_or("primary".to_string()).as_str() {
+        "primary" => false,
+        "replica" => true,
+        other => anyhow::bail!("invalid ROLE {other:?}, expected primary or replica"),
+    };
+    if read_only {
+        info!("Serving as a read-only replica");
+    }
+
+    let metrics = Arc::new(Metrics::new());
+    let compact_config = compact::CompactConfig::from_env()?;
+    // Replication, compaction and aggregates all work on the sled trees.
+    if engine != Engine::Sled
+        && (replication.is_some()
+            || env::var("DB_REPLICA_OF").is_ok()
+            || compact_config.retention.is_some())
+    {
+        anyhow::bail!("only DB_ENGINE=sled can be combined with replication or RETENTION_SECS");
+    }
+    let payments: Arc<dyn PaymentStore> = match engine {
+        Engine::Sled => Arc::new(SledStore {
+            db: db.clone(),
+            providers: providers.clone(),
+            store: store.clone(),
+            index: index.clone(),
+            metrics: metrics.clone(),
+        }),
+        Engine::Segments => Arc::new(SegmentStore::open(
+            providers.clone(),
+            metrics.clone(),
+            PathBuf::from(env::var("SEGMENTS_DIR").unwrap_or("app_segments".to_string())),
+        )?),
+        Engine::Memory => Arc::new(MemoryStore::open(
+            providers.clone(),
+            store.clone(),
+            metrics.clone(),
+            env::var("MEMORY_SNAPSHOT").ok().map(PathBuf::from),
+        )?),
+    };
+    let group_commit_window = GroupCommit::window_from_env()?;
+    let (group_commit, pending) = GroupCommit::channel();
     let app_state = AppState {
         db: db.clone(),
-        default_tree: default_tree.clone(),
-        fallback_tree: fallback_tree.clone(),
+        providers,
+        store,
+        index,
+        pushes,
+        payments: payments.clone(),
+        engine,
+        replication: replication.as_ref().map(|(_, log)| log.clone()),
+        progress: env::var("DB_REPLICA_OF")
+            .is_ok()
+            .then(|| db.open_tree("replication"))
+            .transpose()?,
+        flusher: Arc::new(Flusher::new(
+            payments,
+            FlushConfig::from_env()?,
+            metrics.clone(),
+        )),
+        metrics,
+        group_commit: group_commit_window.map(|_| group_commit),
+        token: env::var("DB_TOKEN").ok().map(Into::into),
+        readiness: Arc::new(Readiness::new(env::var("DB_REPLICA_OF").is_ok())),
+        read_only,
+        backpressure: Backpressure::from_env()?,
+        unacknowledged: TaskTracker::new(),
+        unapplied: Arc::new(Unapplied::default()),
     };
 
-    // Start the periodic flush task
-    let flush_state = app_state.clone();
-    tokio::spawn(async move {
-        periodic_flush(flush_state).await;
-    });
+    if let Some((addr, log)) = replication {
+        tokio::spawn(async move {
+            if let Err(e) = replication::serve(addr, log).await {
+                error!("Replication server stopped: {e}");
+            }
+        });
+    }
+    if let (Ok(primary), Some(progress)) = (env::var("DB_REPLICA_OF"), &app_state.progress) {
+        tokio::spawn(replication::replicate_from(
+            primary,
+            app_state.clone(),
+            progress.clone(),
+        ));
+    }
 
-    let app = Router::new()
-        .route("/payment", post(process_payment))
+    app_state.flusher.clone().spawn();
+    let group_commit =
+        group_commit_window.map(|window| group_commit::spawn(app_state.clone(), pending, window));
+    compact::spawn(app_state.clone(), compact_config);
+
+    let socket_path = env::var("DB_SOCKET_PATH").unwrap_or("/tmp/rinha-db.sock".to_string());
+    if Path::new(socket_path.as_str()).exists() {
+        std::fs::remove_file(socket_path.as_str())?;
+    }
+    let socket = tokio::net::UnixListener::bind(socket_path.as_str())?;
+    info!("rinha-db listening on {socket_path}");
+
+    let shutdown_deadline = Duration::from_millis(
+        env::var("SHUTDOWN_TIMEOUT_MS")
+            .unwrap_or("5000".to_string())
+            .parse()?,
+    );
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let (stop, stopped) = watch::channel(false);
+
+    let mut servers = JoinSet::new();
+    servers.spawn(socket::serve(socket, app_state.clone(), stopped.clone()));
+
+    // Workers only need the socket, HTTP serves the gateway and the tooling.
+    let http_addr = env::var("DB_HTTP_ADDR").unwrap_or("0.0.0.0:8888".to_string());
+    if http_addr != "off" {
+        let listener = tokio::net::TcpListener::bind(http_addr.as_str()).await?;
+        let app = router(app_state.clone());
+        let mut stopped = stopped.clone();
+        servers.spawn(async move {
+            let shutdown = async move {
+                let _ = stopped.wait_for(|stop| *stop).await;
+            };
+            if let Err(e) = axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown)
+                .await
+            {
+                error!("HTTP server stopped: {}", e);
+            }
+        });
+    }
+
+    tokio::select! {
+        _ = sigterm.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+
+    // Stop accepting, let the requests in flight finish, then flush whatever they wrote.
+    info!("Shutting down");
+    stop.send_replace(true);
+    let drained = tokio::time::timeout(shutdown_deadline, async {
+        while servers.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        warn!("Requests still in flight after {shutdown_deadline:?}, flushing anyway");
+    }
+    let _ = std::fs::remove_file(socket_path.as_str());
+    // Writes acknowledged before they were applied must land before the final flush.
+    if let Some(group_commit) = group_commit {
+        group_commit.drain().await;
+    }
+    app_state.unacknowledged.close();
+    app_state.unacknowledged.wait().await;
+    app_state.flusher.close()?;
+    info!("Flushed pending writes");
+    Ok(())
+}
+
+fn router(app_state: AppState) -> Router {
+    let auth_reads = env::var("DB_AUTH_READS").is_ok_and(|v| v == "true");
+    let mut reads = Router::new()
         .route("/summary", get(get_payments_summary))
+        .route("/summary/providers", get(get_providers_summary))
+        .route("/admin/metrics", get(get_metrics));
+    let mut writes = Router::new()
+        .route("/payment", post(process_payment))
+        .route("/payments-batch", post(process_payment_batch))
         .route("/purge", delete(purge_payments))
-        .with_state(app_state);
+        .route("/import", post(import_payments));
+    // These read or change the sled trees directly rather than going through the payment store.
+    if app_state.engine == Engine::Sled {
+        reads = reads
+            .route("/payment/{correlation_id}", get(lookup_payment))
+            .route("/payments", get(list_payments))
+            .route("/summary/buckets", get(get_summary_buckets))
+            .route("/export", get(export_payments))
+            .route("/export.csv", get(export_payments_csv))
+            .route("/admin/verify", get(verify_totals));
+        writes = writes
+            .route("/payment/{correlation_id}", delete(delete_payment))
+            .route("/aggregate", post(merge_aggregates));
+    }
+    if app_state.read_only {
+        writes = writes.route_layer(middleware::from_fn(reject_on_replica));
+    }
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8888").await?;
-    axum::serve(listener, app).await?;
-    Ok(())
+    let auth = middleware::from_fn_with_state(app_state.clone(), require_token);
+    if auth_reads {
+        reads.merge(writes).route_layer(auth)
+    } else {
+        reads.merge(writes.route_layer(auth))
+    }
+    // Probes stay open whatever the token settings.
+    .route("/healthz", get(health::healthz))
+    .route("/readyz", get(health::readyz))
+    .with_state(app_state)
+}
+
+/// Reject requests whose `X-Rinha-Token` header doesn't match `DB_TOKEN`. No-op when unset.
+async fn require_token(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    if let Some(token) = &state.token {
+        let authorized = req
+            .headers()
+            .get("X-Rinha-Token")
+            .is_some_and(|value| value.as_bytes() == token.as_bytes());
+        if !authorized {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(serde_json::json!({ "error": "missing or invalid X-Rinha-Token" })),
+            )
+                .into_response();
+        }
+    }
+
+    next.run(req).await
 }
 
-async fn periodic_flush(state: AppState) {
-    let mut interval = time::interval(Duration::from_millis(100));
+/// What writes to a read-only replica are answered with.
+const READ_ONLY: &str = "this rinha-db is a read-only replica";
 
-    loop {
-        interval.tick().await;
+/// Refuse writes with 403 on a read-only replica.
+async fn reject_on_replica(_: Request, _: Next) -> Response {
+    (
+        StatusCode::FORBIDDEN,
+        Json(serde_json::json!({ "error": READ_ONLY })),
+    )
+        .into_response()
+}
 
-        // Flush both trees
-        if let Err(e) = state.default_tree.flush() {
-            eprintln!("Error flushing default tree: {}", e);
+async fn merge_aggregates(
+    State(state): State<AppState>,
+    Json(push): Json<AggregatePush>,
+) -> impl IntoResponse {
+    if let Err(e) = state.merge_push(&push) {
+        error!("Error merging aggregates: {}", e);
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+    StatusCode::OK
+}
+
+/// Storage counters, along with tree lengths and memory read on the spot. Counting the trees
+/// walks them, so this is meant for tuning rather than frequent scraping.
+async fn get_metrics(State(state): State<AppState>) -> Response {
+    let mut trees = vec![state.index.clone()];
+    for (_, provider) in state.providers.all() {
+        trees.extend([provider.records, provider.buckets]);
+    }
+    let trees = trees
+        .into_iter()
+        .map(|tree| {
+            (
+                String::from_utf8_lossy(&tree.name()).into_owned(),
+                tree.len(),
+            )
+        })
+        .collect();
+    let disk_bytes = match state.db.size_on_disk() {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            error!("Error reading the size on disk: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
         }
+    };
+    Json(state.metrics.snapshot(trees, disk_bytes)).into_response()
+}
+
+#[derive(Serialize)]
+struct VerifyReport {
+    consistent: bool,
+    /// Each provider's verification, keyed by the name of its tree.
+    providers: BTreeMap<String, Verification>,
+}
 
-        if let Err(e) = state.fallback_tree.flush() {
-            eprintln!("Error flushing fallback tree: {}", e);
+/// Add up every tree again and compare it to the totals summaries are served from. It reads the
+/// whole database, and writes landing meanwhile are reported as divergences, so run it while
+/// no payments are coming in.
+async fn verify_totals(State(state): State<AppState>) -> Response {
+    let verified = tokio::task::spawn_blocking(move || {
+        let mut report = VerifyReport {
+            consistent: true,
+            providers: BTreeMap::new(),
+        };
+        for (tree, trees) in state.providers.all() {
+            let verification = state.store.verify(&tree, &trees)?;
+            report.consistent &= verification.consistent;
+            report.providers.insert(trees.name, verification);
+        }
+        Ok::<_, sled::Error>(report)
+    })
+    .await;
+    match verified {
+        Ok(Ok(report)) => Json(report).into_response(),
+        Ok(Err(e)) => {
+            error!("Error verifying the totals: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+        Err(e) => {
+            error!("Verification task failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
         }
     }
 }
 
-async fn purge_payments(State(state): State<AppState>) -> impl IntoResponse {
-    state.db.clear().unwrap();
-    StatusCode::OK
+async fn lookup_payment(
+    UrlPath(correlation_id): UrlPath<Uuid>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    match state.lookup(&correlation_id) {
+        Ok(Some(record)) => Json(record).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            error!("Error looking up payment {correlation_id}: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Remove a payment, answering with it as it was, or 404.
+async fn delete_payment(
+    UrlPath(correlation_id): UrlPath<Uuid>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    match state.delete(&correlation_id) {
+        Ok(Some(record)) => Json(record).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            error!("Error deleting payment {correlation_id}: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Stored records one page at a time, with the cursor of the next page.
+async fn list_payments(Query(query): Query<PageQuery>, State(state): State<AppState>) -> Response {
+    let (range, cursor, limit) = match query.parse() {
+        Ok(parsed) => parsed,
+        Err(e) => return bad_request(e),
+    };
+    match state.page(&range, cursor, limit) {
+        Ok(Some(page)) => Json(page).into_response(),
+        Ok(None) => {
+            bad_request("the cursor points at a provider that no longer exists".to_string())
+        }
+        Err(e) => {
+            error!("Error listing payments: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PurgeQuery {
+    provider: Option<String>,
+    /// With either bound, only the records requested within the range are removed.
+    from: Option<String>,
+    to: Option<String>,
+}
+
+/// Records removed from each provider by a purge, keyed by the name of its tree.
+type Purged = BTreeMap<String, usize>;
+
+async fn purge_payments(
+    Query(query): Query<PurgeQuery>,
+    State(state): State<AppState>,
+) -> Response {
+    let range = match (&query.from, &query.to) {
+        (None, None) => None,
+        (from, to) => match TimeRange::parse(from.as_deref(), to.as_deref()) {
+            Ok(range) => Some(range.millis()),
+            Err(e) => return bad_request(e),
+        },
+    };
+    let trees = match query.provider.as_deref() {
+        None => state
+            .providers
+            .all()
+            .into_iter()
+            .map(|(tree, _)| tree)
+            .collect(),
+        Some(name) => match state.providers.find(name) {
+            Some(tree) => vec![tree],
+            None => return bad_request(format!("unknown provider {name:?}")),
+        },
+    };
+
+    let res = match (query.provider.is_some(), range) {
+        (false, None) => state.purge_all(),
+        (_, range) => trees
+            .into_iter()
+            .map(|tree| {
+                let removed = match range {
+                    Some(range) => state.purge_range(&tree, range)?,
+                    None => state.purge(&tree)?,
+                };
+                Ok((tree, removed))
+            })
+            .collect(),
+    };
+    match res {
+        Ok(removed) => Json(
+            removed
+                .into_iter()
+                .map(|(tree, removed)| (tree.name().to_lowercase(), removed))
+                .collect::<Purged>(),
+        )
+        .into_response(),
+        Err(e) => {
+            error!("Error purging payments: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
 }
 
 async fn get_payments_summary(
-    Query(params): Query<HashMap<String, String>>,
+    Query(query): Query<RangeQuery>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let range = match query.parse() {
+        Ok(range) => range,
+        Err(e) => return bad_request(e),
+    };
+    // Read first, so the summary covers at least every change before it.
+    let position = match state.position() {
+        Ok(position) => position,
+        Err(e) => {
+            error!("Error reading the replication position: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let summary = Json(state.summary(&range).await);
+    match position {
+        Some(position) => {
+            ([("X-Replication-Position", position.to_string())], summary).into_response()
+        }
+        None => summary.into_response(),
+    }
+}
+
+/// Totals of one bucket of a `/summary/buckets` series.
+#[derive(Serialize)]
+struct SeriesPoint {
+    /// The second the bucket starts at.
+    bucket: String,
+    #[serde(flatten)]
+    summary: Summary,
+}
+
+/// Series of each provider, keyed by the name of its tree.
+type SummarySeries = BTreeMap<String, Vec<SeriesPoint>>;
+
+async fn get_summary_buckets(
+    Query(query): Query<SeriesQuery>,
     State(state): State<AppState>,
 ) -> impl IntoResponse {
-    let from = params.get("from").unwrap();
-    let to = params.get("to").unwrap();
-    let default = Summary::from_iter(state.default_tree.range(from.as_str()..=to.as_str()));
-    let fallback = Summary::from_iter(state.fallback_tree.range(from.as_str()..=to.as_str()));
-    let global_summary = GlobalSummary { default, fallback };
-    Json(global_summary)
+    match query.parse() {
+        Ok((range, interval)) => Json(state.series(&range, interval).await).into_response(),
+        Err(e) => bad_request(e),
+    }
 }
 
-async fn process_payment(
+async fn get_providers_summary(
+    Query(query): Query<RangeQuery>,
     State(state): State<AppState>,
-    Json(payload): Json<DBWrite>,
 ) -> impl IntoResponse {
-    let bytes = payload.value.to_be_bytes();
+    match query.parse() {
+        Ok(range) => Json(state.providers_summary(&range).await).into_response(),
+        Err(e) => bad_request(e),
+    }
+}
 
-    match payload.tree {
-        SledTree::Fallback => {
-            if let Err(e) = state.fallback_tree.insert(payload.key.as_bytes(), &bytes) {
-                eprintln!("Error inserting into fallback tree: {}", e);
-                return StatusCode::INTERNAL_SERVER_ERROR;
+fn bad_request(error: String) -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(serde_json::json!({ "error": error })),
+    )
+        .into_response()
+}
+
+/// Stream every record in the range as ndjson without buffering the whole result.
+async fn export_payments(
+    Query(query): Query<RangeQuery>,
+    State(state): State<AppState>,
+) -> Response {
+    match query.parse() {
+        Ok(range) => stream_records(state, range, "application/x-ndjson", None, |record| {
+            let mut line = serde_json::to_vec(record).expect("failed to serialize record");
+            line.push(b'\n');
+            line
+        }),
+        Err(e) => bad_request(e),
+    }
+}
+
+/// Stream every record in the range as CSV, for spreadsheets. Void records come with the
+/// amount they take off, negated, so the amount column adds up to the summary's total.
+async fn export_payments_csv(
+    Query(query): Query<RangeQuery>,
+    State(state): State<AppState>,
+) -> Response {
+    let heading = "timestamp,correlationId,provider,amount\n";
+    match query.parse() {
+        Ok(range) => stream_records(state, range, "text/csv", Some(heading), |record| {
+            let amount = if record.voided {
+                -record.amount
+            } else {
+                record.amount
+            };
+            format!(
+                "{},{},{},{}\n",
+                record.requested_at,
+                record.correlation_id.as_deref().unwrap_or_default(),
+                record.tree.name().to_lowercase(),
+                amount
+            )
+            .into_bytes()
+        }),
+        Err(e) => bad_request(e),
+    }
+}
+
+/// Stream the records of the range, one `line` each after `heading`, from the blocking pool.
+fn stream_records(
+    state: AppState,
+    range: TimeRange,
+    content_type: &'static str,
+    heading: Option<&'static str>,
+    line: fn(&PaymentRecord) -> Vec<u8>,
+) -> Response {
+    let (tx, rx) = mpsc::channel::<std::io::Result<Vec<u8>>>(64);
+    tokio::task::spawn_blocking(move || {
+        if let Some(heading) = heading {
+            if tx.blocking_send(Ok(heading.as_bytes().to_vec())).is_err() {
+                return;
             }
         }
-        SledTree::Default => {
-            if let Err(e) = state.default_tree.insert(payload.key.as_bytes(), &bytes) {
-                eprintln!("Error inserting into default tree: {}", e);
-                return StatusCode::INTERNAL_SERVER_ERROR;
+        for record in state.records(&range) {
+            let line = record
+                .map_err(std::io::Error::other)
+                .map(|record| line(&record));
+            // The client went away.
+            if tx.blocking_send(line).is_err() {
+                return;
             }
         }
+    });
+
+    (
+        [(header::CONTENT_TYPE, content_type)],
+        Body::from_stream(ReceiverStream::new(rx)),
+    )
+        .into_response()
+}
+
+async fn process_payment(State(state): State<AppState>, Json(payload): Json<DBWrite>) -> Response {
+    let admitted = match state.admit(1) {
+        Ok(admitted) => admitted,
+        Err(retry_after) => return busy(retry_after),
     };
+    let tree = payload.tree.clone();
+    if let Err(e) = state.write(payload, admitted).await {
+        error!("Error inserting into {:?} tree: {}", tree, e);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
 
-    StatusCode::OK
+    StatusCode::OK.into_response()
+}
+
+/// 429 for a write turned away by backpressure, with the delay to wait before retrying in
+/// `Retry-After`, rounded up to whole seconds, and in milliseconds in the body.
+fn busy(retry_after: Duration) -> Response {
+    let seconds = retry_after.as_millis().div_ceil(1000).max(1);
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, seconds.to_string())],
+        Json(serde_json::json!({
+            "error": "too many writes in flight",
+            "retryAfterMs": retry_after.as_millis() as u64,
+        })),
+    )
+        .into_response()
+}
+
+/// Records written per batch by `/import`.
+const IMPORT_BATCH: usize = 1024;
+
+/// Records loaded and left out by an import.
+#[derive(Serialize, Default)]
+struct Imported {
+    imported: usize,
+    /// Payments stored before keys carried the correlation id, which can't be keyed again.
+    skipped: usize,
+}
+
+/// The write that stores an exported record again.
+fn record_write(record: PaymentRecord) -> Option<DBWrite> {
+    let correlation_id = Uuid::parse_str(record.correlation_id.as_deref()?).ok()?;
+    let key = payment_key(&record.requested_at, &correlation_id);
+    Some(DBWrite {
+        key: if record.voided { void_key(&key) } else { key },
+        value: record.amount,
+        tree: record.tree,
+        ack: Ack::default(),
+        trace_id: None,
+        traceparent: None,
+    })
+}
+
+/// An import in progress: the records parsed since the last batch was written.
+struct Import {
+    state: AppState,
+    writes: Vec<DBWrite>,
+    imported: Imported,
+    /// Lines read so far.
+    lines: usize,
+}
+
+impl Import {
+    /// Read the dump to the end, returning what it loaded.
+    async fn read(mut self, body: Body) -> Result<Imported, Response> {
+        let mut chunks = body.into_data_stream();
+        // The start of a line whose end hasn't arrived yet.
+        let mut partial = Vec::new();
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk.map_err(|e| bad_request(format!("error reading the dump: {e}")))?;
+            partial.extend_from_slice(&chunk);
+            let Some(end) = partial.iter().rposition(|&byte| byte == b'\n') else {
+                continue;
+            };
+            let lines = partial.drain(..=end).collect::<Vec<_>>();
+            for record in lines[..end].split(|&byte| byte == b'\n') {
+                self.line(record).await?;
+            }
+        }
+        self.line(&partial).await?;
+        self.write().await?;
+        Ok(self.imported)
+    }
+
+    /// Parse the next line of the dump, writing the batch once it's full.
+    async fn line(&mut self, record: &[u8]) -> Result<(), Response> {
+        self.lines += 1;
+        if record.trim_ascii().is_empty() {
+            return Ok(());
+        }
+        let record: PaymentRecord = serde_json::from_slice(record).map_err(|e| {
+            bad_request(format!(
+                "invalid record on line {}: {e}, {} records were imported before it",
+                self.lines, self.imported.imported
+            ))
+        })?;
+        match record_write(record) {
+            Some(write) => self.writes.push(write),
+            None => self.imported.skipped += 1,
+        }
+        if self.writes.len() >= IMPORT_BATCH {
+            self.write().await?;
+        }
+        Ok(())
+    }
+
+    /// Write the batch like any other, waiting out backpressure instead of turning the import
+    /// away halfway.
+    async fn write(&mut self) -> Result<(), Response> {
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        let admitted = loop {
+            match self.state.admit(self.writes.len()) {
+                Ok(admitted) => break admitted,
+                Err(retry_after) => tokio::time::sleep(retry_after).await,
+            }
+        };
+        let writes = std::mem::take(&mut self.writes);
+        let records = writes.len();
+        if let Err(e) = self.state.write_batch(writes, admitted) {
+            error!("Error importing records: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+        self.imported.imported += records;
+        Ok(())
+    }
+}
+
+/// Load records in the ndjson format `/export` streams, line by line as the body arrives, in
+/// batches of `IMPORT_BATCH`. A malformed line stops the import there, keeping the batches
+/// written before it.
+async fn import_payments(State(state): State<AppState>, body: Body) -> Response {
+    let import = Import {
+        state,
+        writes: Vec::with_capacity(IMPORT_BATCH),
+        imported: Imported::default(),
+        lines: 0,
+    };
+    match import.read(body).await {
+        Ok(imported) => Json(imported).into_response(),
+        Err(response) => response,
+    }
+}
+
+async fn process_payment_batch(
+    State(state): State<AppState>,
+    Json(writes): Json<Vec<DBWrite>>,
+) -> Response {
+    let admitted = match state.admit(writes.len()) {
+        Ok(admitted) => admitted,
+        Err(retry_after) => return busy(retry_after),
+    };
+    if let Err(e) = state.write_batch(writes, admitted) {
+        error!("Error inserting batch: {}", e);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    StatusCode::OK.into_response()
 }
@@ -0,0 +1,611 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use serde::{Deserialize, Serialize};
+use shared_types::{DBWrite, SledTree, Summary, codec};
+use tracing::{info, warn};
+
+use crate::{
+    engine::PaymentStore,
+    keys,
+    metrics::Metrics,
+    providers::Providers,
+    range::TimeRange,
+    storage::{Bucket, Snapshot, SummaryStore},
+    storage_key,
+};
+
+/// Shards the records are spread over, so concurrent writers rarely wait on the same lock.
+const SHARDS: usize = 16;
+/// The journal grows to at least this many bytes before it's folded into the snapshot, so a
+/// small snapshot isn't written again on every flush.
+const JOURNAL_FLOOR: u64 = 1 << 20;
+
+/// Each provider's records by storage key, which starts with the time they were requested, so
+/// a range of time is a range of keys.
+#[derive(Default)]
+struct Shard {
+    trees: HashMap<SledTree, BTreeMap<Vec<u8>, f64>>,
+    /// Changes applied to the shard since the last flush, by sequence, when they're journaled.
+    changes: Vec<(u64, Change)>,
+}
+
+impl Shard {
+    /// Store a record, returning the amount it replaced.
+    fn insert(&mut self, tree: &SledTree, key: Vec<u8>, amount: f64) -> Option<f64> {
+        self.trees
+            .entry(tree.clone())
+            .or_default()
+            .insert(key, amount)
+    }
+
+    fn sum(&self, tree: &SledTree, keys: &Range<Vec<u8>>) -> Bucket {
+        let mut total = Bucket::default();
+        for (key, amount) in self
+            .trees
+            .get(tree)
+            .into_iter()
+            .flat_map(|records| records.range(keys.clone()))
+        {
+            total.add(Bucket::of(key, *amount));
+        }
+        total
+    }
+
+    /// Remove the provider's records, returning how many there were.
+    fn purge(&mut self, tree: &SledTree) -> usize {
+        self.trees.remove(tree).map_or(0, |records| records.len())
+    }
+
+    /// Remove the provider's records within `keys`, returning how many there were.
+    fn purge_range(&mut self, tree: &SledTree, keys: &Range<Vec<u8>>) -> usize {
+        let Some(records) = self.trees.get_mut(tree) else {
+            return 0;
+        };
+        let mut after = records.split_off(&keys.start);
+        let mut rest = after.split_off(&keys.end);
+        records.append(&mut rest);
+        after.len()
+    }
+
+    fn records(&self) -> impl Iterator<Item = (SledTree, Vec<u8>, f64)> + '_ {
+        self.trees.iter().flat_map(|(tree, records)| {
+            records
+                .iter()
+                .map(|(key, amount)| (tree.clone(), key.clone(), *amount))
+        })
+    }
+}
+
+/// A change appended to the journal, replayed through the same calls that made it.
+#[derive(Serialize, Deserialize)]
+enum Change {
+    Insert(SledTree, Vec<u8>, f64),
+    Purge(SledTree),
+    PurgeRange(SledTree, u64, u64),
+    PurgeAll,
+}
+
+/// What `MEMORY_SNAPSHOT` holds: every record and the totals they add up to.
+#[derive(Serialize, Deserialize)]
+struct MemorySnapshot {
+    records: Vec<(SledTree, Vec<u8>, f64)>,
+    totals: Snapshot,
+    /// Generation of the journal holding the changes made after the snapshot. A journal of an
+    /// earlier one, left by a crash before it was emptied, only holds changes already in it.
+    journal: u64,
+}
+
+/// The snapshot file and the journal of changes made since it was written, next to it.
+struct Persisted {
+    path: PathBuf,
+    /// Held while either file is written, so a flush never appends to a journal a snapshot
+    /// is about to truncate.
+    journal: Mutex<Journal>,
+}
+
+/// Starts with its generation as a big-endian u64, then holds length-prefixed frames of changes.
+struct Journal {
+    file: File,
+    generation: u64,
+    /// Bytes appended since the snapshot was written.
+    len: u64,
+    /// Size of the snapshot, which is written again once the journal grows past it and
+    /// `JOURNAL_FLOOR`.
+    snapshot_len: u64,
+    /// Set when changes were taken from the shards but not appended, so only a snapshot covers
+    /// them.
+    behind: bool,
+}
+
+/// Records kept in memory only, summed through the same per-second totals as sled's. Nothing
+/// survives a restart, in exchange for writes that never wait on the disk.
+pub struct MemoryStore {
+    providers: Arc<Providers>,
+    store: Arc<SummaryStore>,
+    metrics: Arc<Metrics>,
+    shards: Vec<Mutex<Shard>>,
+    /// Orders the changes journaled from different shards.
+    sequence: AtomicU64,
+    /// Set to restore from and write to a snapshot.
+    persisted: Option<Persisted>,
+}
+
+impl MemoryStore {
+    /// Restores the snapshot at `snapshot` and the journal next to it when there's one.
+    pub fn open(
+        providers: Arc<Providers>,
+        store: Arc<SummaryStore>,
+        metrics: Arc<Metrics>,
+        snapshot: Option<PathBuf>,
+    ) -> anyhow::Result<Self> {
+        let persisted = match snapshot {
+            Some(path) => Some(Persisted {
+                journal: Mutex::new(Journal {
+                    file: OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(journal_path(&path))?,
+                    generation: 0,
+                    len: 0,
+                    snapshot_len: 0,
+                    behind: false,
+                }),
+                path,
+            }),
+            None => None,
+        };
+        let memory = Self {
+            providers,
+            store,
+            metrics,
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            sequence: AtomicU64::new(0),
+            persisted,
+        };
+        let Some(persisted) = &memory.persisted else {
+            info!("Keeping payments in memory only, they're lost on restart");
+            return Ok(memory);
+        };
+        let mut journal = persisted.journal.lock().unwrap();
+        let mut expected = None;
+        if persisted.path.exists() {
+            let (restored, generation) = memory.restore(&fs::read(&persisted.path)?)?;
+            info!(
+                "Restored {restored} payments from {}",
+                persisted.path.display()
+            );
+            expected = Some(generation);
+            journal.generation = generation;
+        }
+        let frames = fs::read(journal_path(&persisted.path))?;
+        if let Some(header) = frames.get(..8) {
+            let generation = u64::from_be_bytes(header.try_into().unwrap());
+            if expected.is_none_or(|expected| expected == generation) {
+                let replayed = memory.replay(&frames[8..])?;
+                if replayed > 0 {
+                    info!("Replayed {replayed} changes from the journal");
+                }
+                journal.generation = generation;
+            } else {
+                info!("Skipping journal {generation}, the snapshot already holds its changes");
+            }
+        }
+        // Folds the journal into the snapshot, and records its size.
+        memory.write_snapshot(persisted, &mut journal)?;
+        drop(journal);
+        Ok(memory)
+    }
+
+    /// Returns how many records were restored, and the generation of the journal following the
+    /// snapshot.
+    fn restore(&self, snapshot: &[u8]) -> anyhow::Result<(usize, u64)> {
+        let snapshot: MemorySnapshot = codec::decode(snapshot.get(4..).unwrap_or_default())?;
+        let restored = snapshot.records.len();
+        for (tree, key, amount) in snapshot.records {
+            self.providers.get_or_open(&tree)?;
+            self.shard(&key).lock().unwrap().insert(&tree, key, amount);
+        }
+        self.store.restore(snapshot.totals)?;
+        Ok((restored, snapshot.journal))
+    }
+
+    /// Apply the changes of every complete frame of the journal, returning how many there
+    /// were. A frame cut short by a crash ends it.
+    fn replay(&self, mut journal: &[u8]) -> anyhow::Result<usize> {
+        let mut replayed = 0;
+        while let Some(header) = journal.get(..4) {
+            let len = u32::from_be_bytes(header.try_into().unwrap()) as usize;
+            let Some(frame) = journal.get(4..4 + len) else {
+                warn!("Ignoring a journal frame cut short");
+                break;
+            };
+            let changes: Vec<(u64, Change)> = codec::decode(frame)?;
+            replayed += changes.len();
+            for (_, change) in changes {
+                match change {
+                    Change::Insert(tree, key, amount) => {
+                        self.providers.get_or_open(&tree)?;
+                        self.insert_key(&tree, key, amount);
+                    }
+                    Change::Purge(tree) => {
+                        self.purge(&tree)?;
+                    }
+                    Change::PurgeRange(tree, from, to) => {
+                        self.purge_range(&tree, (from, to))?;
+                    }
+                    Change::PurgeAll => {
+                        self.purge_all()?;
+                    }
+                }
+            }
+            journal = &journal[4 + len..];
+        }
+        Ok(replayed)
+    }
+
+    /// Append the changes made since the last flush to the journal, or write the snapshot
+    /// instead once the journal outgrew it.
+    fn append(&self, persisted: &Persisted) -> anyhow::Result<()> {
+        let mut journal = persisted.journal.lock().unwrap();
+        if journal.behind || journal.len > journal.snapshot_len.max(JOURNAL_FLOOR) {
+            return self.write_snapshot(persisted, &mut journal);
+        }
+        let mut changes = Vec::new();
+        for mut shard in self.lock_all() {
+            changes.append(&mut shard.changes);
+        }
+        if changes.is_empty() {
+            return Ok(());
+        }
+        changes.sort_unstable_by_key(|(sequence, _)| *sequence);
+        let appended = codec::encode(&changes).and_then(|frame| {
+            journal.file.write_all(&frame)?;
+            journal.file.sync_data()?;
+            Ok(frame.len() as u64)
+        });
+        match appended {
+            Ok(len) => {
+                journal.len += len;
+                Ok(())
+            }
+            Err(e) => {
+                journal.behind = true;
+                Err(e)
+            }
+        }
+    }
+
+    /// Write every record and the totals to a temporary file renamed over the snapshot, all
+    /// shards locked so the totals match the records, then start the journal of the next
+    /// generation over the one it covers.
+    fn write_snapshot(&self, persisted: &Persisted, journal: &mut Journal) -> anyhow::Result<()> {
+        let generation = journal.generation + 1;
+        let mut shards = self.lock_all();
+        for shard in shards.iter_mut() {
+            shard.changes.clear();
+        }
+        let snapshot = MemorySnapshot {
+            records: shards.iter().flat_map(|shard| shard.records()).collect(),
+            totals: self.store.snapshot(),
+            journal: generation,
+        };
+        drop(shards);
+        // Changes made from here on are only covered once the snapshot is written.
+        journal.behind = true;
+
+        let snapshot = codec::encode(&snapshot)?;
+        let tmp = persisted.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&snapshot)?;
+        file.sync_all()?;
+        fs::rename(tmp, &persisted.path)?;
+        journal.generation = generation;
+        journal.file.set_len(0)?;
+        journal.file.write_all(&generation.to_be_bytes())?;
+        journal.file.sync_data()?;
+        journal.len = 0;
+        journal.snapshot_len = snapshot.len() as u64;
+        journal.behind = false;
+        Ok(())
+    }
+
+    /// Shard of a stored key, by the last byte of its correlation id so a payment and its void
+    /// record land on the same one.
+    fn shard(&self, key: &[u8]) -> &Mutex<Shard> {
+        let byte = key.get(23).copied().unwrap_or_default();
+        &self.shards[byte as usize % SHARDS]
+    }
+
+    /// Every shard, locked in order, for changes that span them.
+    fn lock_all(&self) -> Vec<MutexGuard<'_, Shard>> {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap())
+            .collect()
+    }
+
+    /// Keep a change for the next flush to append, when there's a journal. Called with the
+    /// shard it changed locked, so changes to one shard are sequenced in the order they were
+    /// made.
+    fn journal(&self, shard: &mut Shard, change: impl FnOnce() -> Change) {
+        if self.persisted.is_some() {
+            let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+            shard.changes.push((sequence, change()));
+        }
+    }
+
+    fn insert_key(&self, tree: &SledTree, key: Vec<u8>, amount: f64) {
+        // Recorded with the shard still locked, so a snapshot never sees one without the other.
+        let mut shard = self.shard(&key).lock().unwrap();
+        self.journal(&mut shard, || {
+            Change::Insert(tree.clone(), key.clone(), amount)
+        });
+        let replaced = shard.insert(tree, key.clone(), amount);
+        self.store.record(tree, &key, amount, replaced);
+    }
+
+    fn sum(&self, tree: &SledTree, keys: &Range<Vec<u8>>) -> Bucket {
+        let mut total = Bucket::default();
+        for shard in &self.shards {
+            total.add(shard.lock().unwrap().sum(tree, keys));
+        }
+        total
+    }
+}
+
+fn journal_path(snapshot: &Path) -> PathBuf {
+    snapshot.with_extension("journal")
+}
+
+impl PaymentStore for MemoryStore {
+    fn insert(&self, write: &DBWrite) -> sled::Result<()> {
+        let key = storage_key(write)?;
+        self.providers.get_or_open(&write.tree)?;
+        self.insert_key(&write.tree, key, write.value);
+        self.metrics.record_inserts(1);
+        Ok(())
+    }
+
+    /// Every key and provider is checked before anything is stored.
+    fn insert_batch(&self, writes: &[DBWrite]) -> sled::Result<()> {
+        let mut keys = Vec::with_capacity(writes.len());
+        for write in writes {
+            keys.push(storage_key(write)?);
+            self.providers.get_or_open(&write.tree)?;
+        }
+        for (write, key) in writes.iter().zip(keys) {
+            self.insert_key(&write.tree, key, write.value);
+        }
+        self.metrics.record_batch(writes.len());
+        Ok(())
+    }
+
+    fn summary(&self, tree: &SledTree, range: &TimeRange) -> Summary {
+        if range.covers_everything() {
+            return self.store.total(tree);
+        }
+        self.store
+            .summary(tree, range.millis(), |keys| self.sum(tree, &keys))
+    }
+
+    /// Purges lock every shard, so they're journaled in order with the inserts around them.
+    fn purge(&self, tree: &SledTree) -> sled::Result<usize> {
+        let mut shards = self.lock_all();
+        let removed = shards.iter_mut().map(|shard| shard.purge(tree)).sum();
+        self.store.clear(tree);
+        self.journal(&mut shards[0], || Change::Purge(tree.clone()));
+        Ok(removed)
+    }
+
+    fn purge_range(&self, tree: &SledTree, (from, to): (u64, u64)) -> sled::Result<usize> {
+        let mut shards = self.lock_all();
+        self.store.purge_range(tree, (from, to), |keys| {
+            let mut total = Bucket::default();
+            for shard in &shards {
+                total.add(shard.sum(tree, &keys));
+            }
+            total
+        });
+        let keys = keys::range(from, to);
+        let removed = shards
+            .iter_mut()
+            .map(|shard| shard.purge_range(tree, &keys))
+            .sum();
+        self.journal(&mut shards[0], || {
+            Change::PurgeRange(tree.clone(), from, to)
+        });
+        Ok(removed)
+    }
+
+    /// Every shard is emptied at once, with nothing left to count per provider.
+    fn purge_all(&self) -> sled::Result<Vec<(SledTree, usize)>> {
+        let mut removed = self
+            .providers
+            .all()
+            .into_iter()
+            .map(|(tree, _)| (tree, 0))
+            .collect::<HashMap<_, _>>();
+        let mut shards = self.lock_all();
+        for shard in shards.iter_mut() {
+            for (tree, records) in shard.trees.drain() {
+                *removed.entry(tree).or_default() += records.len();
+            }
+        }
+        self.store.purge_all();
+        self.journal(&mut shards[0], || Change::PurgeAll);
+        Ok(removed.into_iter().collect())
+    }
+
+    /// Appends the changes since the last flush to the journal when there's a snapshot,
+    /// nothing is on disk otherwise.
+    fn flush(&self) -> sled::Result<()> {
+        match &self.persisted {
+            Some(persisted) => self
+                .append(persisted)
+                .map_err(|e| sled::Error::Io(io::Error::other(format!("writing journal: {e}")))),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the whole snapshot, so the next start has no journal to replay.
+    fn close(&self) -> sled::Result<()> {
+        match &self.persisted {
+            Some(persisted) => self
+                .write_snapshot(persisted, &mut persisted.journal.lock().unwrap())
+                .map_err(|e| sled::Error::Io(io::Error::other(format!("writing snapshot: {e}")))),
+            None => Ok(()),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+    use crate::storage::Granularity;
+
+    const START: u64 = 1_752_580_800_000;
+
+    /// A payment requested `second`s after `START`.
+    fn key(second: u64, id: u128) -> Vec<u8> {
+        let at = chrono::DateTime::from_timestamp_millis((START + second * 1000) as i64).unwrap();
+        let key = format!("{}#{}", at.to_rfc3339(), uuid::Uuid::from_u128(id));
+        keys::from_write_key(&key).unwrap()
+    }
+
+    /// Keys of the payments requested within `seconds`.
+    fn within(seconds: Range<u64>) -> Range<Vec<u8>> {
+        keys::range(START + seconds.start * 1000, START + seconds.end * 1000 - 1)
+    }
+
+    fn sum(shard: &Shard, tree: &SledTree, seconds: Range<u64>) -> (u64, f64) {
+        let keys = within(seconds);
+        let summary = Summary::from(shard.sum(tree, &keys));
+        (summary.total_requests, summary.total_amount)
+    }
+
+    fn shard() -> Shard {
+        let mut shard = Shard::default();
+        for second in 0..10 {
+            for id in 0..3 {
+                shard.insert(&SledTree::DEFAULT, key(second, id), 1.0);
+            }
+            shard.insert(&SledTree::FALLBACK, key(second, 0), 2.0);
+        }
+        shard
+    }
+
+    fn open(snapshot: &Path, db: &sled::Db) -> MemoryStore {
+        MemoryStore::open(
+            Arc::new(Providers::open(db).unwrap()),
+            Arc::new(SummaryStore::new(Granularity::Second)),
+            Arc::new(Metrics::new()),
+            Some(snapshot.to_path_buf()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn changes_flushed_to_the_journal_are_restored() {
+        let dir = env::temp_dir().join(format!("rinha-memory-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let snapshot = dir.join("snapshot");
+        let db = sled::Config::new().temporary(true).open().unwrap();
+
+        let memory = open(&snapshot, &db);
+        let written = fs::read(&snapshot).unwrap();
+        for second in 0..10 {
+            for id in 0..3 {
+                memory.insert_key(&SledTree::DEFAULT, key(second, id), 1.0);
+            }
+        }
+        memory.flush().unwrap();
+        memory
+            .purge_range(&SledTree::DEFAULT, (START + 2000, START + 4999))
+            .unwrap();
+        memory.insert_key(&SledTree::FALLBACK, key(1, 0), 2.0);
+        memory.insert_key(&SledTree::DEFAULT, key(3, 0), 4.0);
+        memory.flush().unwrap();
+        // Only the changes were written, as if the process then crashed.
+        assert_eq!(fs::read(&snapshot).unwrap(), written);
+        drop(memory);
+
+        let memory = open(&snapshot, &db);
+        let total = memory.store.total(&SledTree::DEFAULT);
+        assert_eq!((total.total_requests, total.total_amount), (22, 25.0));
+        let total = memory.store.total(&SledTree::FALLBACK);
+        assert_eq!((total.total_requests, total.total_amount), (1, 2.0));
+        let restored = memory
+            .shards
+            .iter()
+            .map(|s| s.lock().unwrap().records().count());
+        assert_eq!(restored.sum::<usize>(), 23);
+        // Replayed into the snapshot written on open, leaving just the generation.
+        assert_eq!(fs::metadata(journal_path(&snapshot)).unwrap().len(), 8);
+        drop(memory);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn a_journal_left_behind_a_newer_snapshot_is_skipped() {
+        let dir = env::temp_dir().join(format!("rinha-memory-left-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let snapshot = dir.join("snapshot");
+        let db = sled::Config::new().temporary(true).open().unwrap();
+
+        let memory = open(&snapshot, &db);
+        memory.insert_key(&SledTree::DEFAULT, key(0, 0), 1.0);
+        memory.purge(&SledTree::DEFAULT).unwrap();
+        memory.flush().unwrap();
+        memory.insert_key(&SledTree::DEFAULT, key(1, 0), 2.0);
+        // As if the process crashed after the snapshot was renamed, before the journal holding
+        // the purge was emptied.
+        let journal = fs::read(journal_path(&snapshot)).unwrap();
+        memory.close().unwrap();
+        fs::write(journal_path(&snapshot), journal).unwrap();
+        drop(memory);
+
+        let memory = open(&snapshot, &db);
+        let total = memory.store.total(&SledTree::DEFAULT);
+        assert_eq!((total.total_requests, total.total_amount), (1, 2.0));
+        drop(memory);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn sums_only_the_range_of_one_provider() {
+        let shard = shard();
+        assert_eq!(sum(&shard, &SledTree::DEFAULT, 2..5), (9, 9.0));
+        assert_eq!(sum(&shard, &SledTree::FALLBACK, 2..5), (3, 6.0));
+        assert_eq!(sum(&shard, &SledTree::DEFAULT, 20..30), (0, 0.0));
+    }
+
+    #[test]
+    fn overwrites_replace_the_amount() {
+        let mut shard = shard();
+        assert_eq!(shard.insert(&SledTree::DEFAULT, key(3, 0), 5.0), Some(1.0));
+        assert_eq!(sum(&shard, &SledTree::DEFAULT, 3..4), (3, 7.0));
+    }
+
+    #[test]
+    fn purges_a_range_of_one_provider() {
+        let mut shard = shard();
+        assert_eq!(shard.purge_range(&SledTree::DEFAULT, &within(2..5)), 9);
+        assert_eq!(sum(&shard, &SledTree::DEFAULT, 0..10), (21, 21.0));
+        assert_eq!(sum(&shard, &SledTree::FALLBACK, 0..10), (10, 20.0));
+        assert_eq!(shard.purge(&SledTree::FALLBACK), 10);
+        assert_eq!(shard.records().count(), 21);
+    }
+}
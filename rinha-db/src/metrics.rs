@@ -0,0 +1,195 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+/// Count, total and worst duration of one kind of operation.
+#[derive(Default)]
+pub struct Timing {
+    count: AtomicU64,
+    total_us: AtomicU64,
+    max_us: AtomicU64,
+}
+
+impl Timing {
+    pub fn record(&self, elapsed: Duration) {
+        let us = elapsed.as_micros() as u64;
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_us.fetch_add(us, Ordering::Relaxed);
+        self.max_us.fetch_max(us, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> TimingSnapshot {
+        TimingSnapshot {
+            count: self.count.load(Ordering::Relaxed),
+            total_us: self.total_us.load(Ordering::Relaxed),
+            max_us: self.max_us.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Records written in the current and the previous second since startup.
+struct Rate {
+    started: Instant,
+    /// The current second, records written in it and in the one before.
+    window: Mutex<(u64, u64, u64)>,
+}
+
+impl Rate {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            window: Mutex::new((0, 0, 0)),
+        }
+    }
+
+    /// Move the window to the current second.
+    fn roll(&self, window: &mut (u64, u64, u64)) {
+        let now = self.started.elapsed().as_secs();
+        let (second, current, previous) = window;
+        if now != *second {
+            *previous = if now == *second + 1 { *current } else { 0 };
+            *current = 0;
+            *second = now;
+        }
+    }
+
+    fn add(&self, records: u64) {
+        let mut window = self.window.lock().unwrap();
+        self.roll(&mut window);
+        window.1 += records;
+    }
+
+    /// Records written during the last full second.
+    fn per_second(&self) -> u64 {
+        let mut window = self.window.lock().unwrap();
+        self.roll(&mut window);
+        window.2
+    }
+}
+
+/// Counters of the storage side, reported on `/admin/metrics`.
+pub struct Metrics {
+    inserted: AtomicU64,
+    rate: Rate,
+    batches: AtomicU64,
+    batched: AtomicU64,
+    max_batch: AtomicU64,
+    /// Writes turned away by backpressure.
+    busy: AtomicU64,
+    pub flush: Timing,
+    pub summary: Timing,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            inserted: AtomicU64::new(0),
+            rate: Rate::new(),
+            batches: AtomicU64::new(0),
+            batched: AtomicU64::new(0),
+            max_batch: AtomicU64::new(0),
+            busy: AtomicU64::new(0),
+            flush: Timing::default(),
+            summary: Timing::default(),
+        }
+    }
+
+    pub fn record_inserts(&self, records: usize) {
+        self.inserted.fetch_add(records as u64, Ordering::Relaxed);
+        self.rate.add(records as u64);
+    }
+
+    pub fn record_batch(&self, records: usize) {
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        self.batched.fetch_add(records as u64, Ordering::Relaxed);
+        self.max_batch.fetch_max(records as u64, Ordering::Relaxed);
+        self.record_inserts(records);
+    }
+
+    pub fn record_busy(&self, records: usize) {
+        self.busy.fetch_add(records as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self, trees: BTreeMap<String, usize>, disk_bytes: u64) -> MetricsSnapshot {
+        MetricsSnapshot {
+            inserted: self.inserted.load(Ordering::Relaxed),
+            insert_rate: self.rate.per_second(),
+            batches: BatchSnapshot {
+                count: self.batches.load(Ordering::Relaxed),
+                records: self.batched.load(Ordering::Relaxed),
+                max: self.max_batch.load(Ordering::Relaxed),
+            },
+            busy: self.busy.load(Ordering::Relaxed),
+            trees,
+            flush: self.flush.snapshot(),
+            summary: self.summary.snapshot(),
+            memory: MemorySnapshot {
+                rss_bytes: rss_bytes(),
+                disk_bytes,
+            },
+        }
+    }
+}
+
+/// Resident memory of the process, read from `/proc` where there is one.
+fn rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kb = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+#[derive(Serialize)]
+pub struct MetricsSnapshot {
+    /// Records written since startup.
+    inserted: u64,
+    /// Records written during the last full second.
+    #[serde(rename = "insertRate")]
+    insert_rate: u64,
+    batches: BatchSnapshot,
+    /// Writes turned away by backpressure since startup.
+    busy: u64,
+    /// Entries in each tree, by tree name.
+    trees: BTreeMap<String, usize>,
+    flush: TimingSnapshot,
+    summary: TimingSnapshot,
+    memory: MemorySnapshot,
+}
+
+#[derive(Serialize)]
+struct BatchSnapshot {
+    count: u64,
+    /// Records written by all batches.
+    records: u64,
+    max: u64,
+}
+
+#[derive(Serialize)]
+struct TimingSnapshot {
+    count: u64,
+    #[serde(rename = "totalUs")]
+    total_us: u64,
+    #[serde(rename = "maxUs")]
+    max_us: u64,
+}
+
+#[derive(Serialize)]
+struct MemorySnapshot {
+    #[serde(rename = "rssBytes")]
+    rss_bytes: Option<u64>,
+    #[serde(rename = "diskBytes")]
+    disk_bytes: u64,
+}
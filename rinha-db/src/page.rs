@@ -0,0 +1,141 @@
+use std::ops::Bound;
+
+use serde::{Deserialize, Serialize};
+use shared_types::PaymentRecord;
+
+use crate::{AppState, keys, range::TimeRange, stored_record};
+
+/// Records listed when the query doesn't ask for a number.
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+/// Query string of `/payments`.
+#[derive(Deserialize)]
+pub struct PageQuery {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    /// The `nextCursor` of the previous page.
+    pub cursor: Option<String>,
+    pub limit: Option<usize>,
+}
+
+impl PageQuery {
+    pub fn parse(&self) -> Result<(TimeRange, Option<Cursor>, usize), String> {
+        let range = TimeRange::parse(self.from.as_deref(), self.to.as_deref())?;
+        let cursor = match self.cursor.as_deref() {
+            None => None,
+            Some(cursor) => {
+                Some(Cursor::decode(cursor).ok_or_else(|| format!("invalid cursor {cursor:?}"))?)
+            }
+        };
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT);
+        if !(1..=MAX_LIMIT).contains(&limit) {
+            return Err(format!("invalid limit {limit}, expected 1 to {MAX_LIMIT}"));
+        }
+        Ok((range, cursor, limit))
+    }
+}
+
+/// Where a page ended: the provider's tree name and the last key listed. Clients get it hex
+/// encoded and pass it back as is.
+pub struct Cursor {
+    tree: String,
+    key: Vec<u8>,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        format!("{}.{}", hex(self.tree.as_bytes()), hex(&self.key))
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let (tree, key) = cursor.split_once('.')?;
+        Some(Self {
+            tree: String::from_utf8(unhex(tree)?).ok()?,
+            key: unhex(key)?,
+        })
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[derive(Serialize)]
+pub struct Page {
+    records: Vec<PaymentRecord>,
+    /// Set when there are more records to list.
+    #[serde(rename = "nextCursor", skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+impl AppState {
+    /// Up to `limit` records of the range, in the order `/export` streams them, starting right
+    /// after `cursor`. `None` when the cursor points at a provider that's gone.
+    pub fn page(
+        &self,
+        range: &TimeRange,
+        cursor: Option<Cursor>,
+        limit: usize,
+    ) -> sled::Result<Option<Page>> {
+        let (from, to) = range.millis();
+        let bounds = keys::range(from, to);
+        let mut providers = self.providers.all();
+        if let Some(cursor) = &cursor {
+            let Some(at) = providers
+                .iter()
+                .position(|(_, trees)| trees.name == cursor.tree)
+            else {
+                return Ok(None);
+            };
+            providers.drain(..at);
+        }
+
+        let mut page = Page {
+            records: Vec::with_capacity(limit),
+            next_cursor: None,
+        };
+        let mut last = None;
+        for (tree, trees) in providers {
+            let start = match &cursor {
+                Some(cursor) if cursor.tree == trees.name && cursor.key >= bounds.start => {
+                    if cursor.key >= bounds.end {
+                        continue;
+                    }
+                    Bound::Excluded(cursor.key.clone())
+                }
+                _ => Bound::Included(bounds.start.clone()),
+            };
+            let scan = trees
+                .records
+                .range::<Vec<u8>, _>((start, Bound::Excluded(bounds.end.clone())));
+            for entry in scan {
+                let (key, value) = entry?;
+                let Some(record) = stored_record(&tree, &key, &value) else {
+                    continue;
+                };
+                // One record past the limit tells whether there's another page.
+                if page.records.len() == limit {
+                    page.next_cursor = last.as_ref().map(Cursor::encode);
+                    return Ok(Some(page));
+                }
+                page.records.push(record);
+                last = Some(Cursor {
+                    tree: trees.name.clone(),
+                    key: key.to_vec(),
+                });
+            }
+        }
+        Ok(Some(page))
+    }
+}
@@ -0,0 +1,123 @@
+use std::{collections::BTreeMap, sync::RwLock};
+
+use shared_types::SledTree;
+use sled::{Db, Tree};
+use tracing::info;
+
+/// Trees that aren't a provider's, which a provider name can't map to.
+const RESERVED: [&str; 4] = [
+    "payment_index",
+    "providers",
+    "replication",
+    "replication_log",
+];
+
+/// The trees holding one provider's records and aggregate buckets.
+#[derive(Clone)]
+pub struct ProviderTrees {
+    /// Name of the records tree, the provider name in lowercase. Responses listing every
+    /// provider are keyed by it.
+    pub name: String,
+    pub records: Tree,
+    /// Per-second totals pushed by workers running in aggregate mode.
+    pub buckets: Tree,
+}
+
+impl ProviderTrees {
+    fn open(db: &Db, name: &str) -> sled::Result<Self> {
+        Ok(Self {
+            name: name.to_string(),
+            records: db.open_tree(name)?,
+            buckets: db.open_tree(format!("{name}_buckets"))?,
+        })
+    }
+}
+
+/// Every provider rinha-db holds records for. `Default` and `Fallback` always exist, other
+/// names are registered the first time a record or aggregate is written for them, so a new
+/// provider needs no change here.
+pub struct Providers {
+    db: Db,
+    /// Provider name to the name of its records tree.
+    registry: Tree,
+    open: RwLock<BTreeMap<SledTree, ProviderTrees>>,
+}
+
+impl Providers {
+    pub fn open(db: &Db) -> sled::Result<Self> {
+        let registry = db.open_tree("providers")?;
+        for tree in [SledTree::DEFAULT, SledTree::FALLBACK] {
+            let name = tree.name().to_lowercase();
+            // Fails when the provider was registered by an earlier run already.
+            let _ = registry.compare_and_swap(tree.name(), None::<&[u8]>, Some(name.as_bytes()))?;
+        }
+
+        let mut open = BTreeMap::new();
+        for entry in registry.iter() {
+            let (provider, name) = entry?;
+            let provider = SledTree::new(String::from_utf8_lossy(&provider));
+            open.insert(
+                provider,
+                ProviderTrees::open(db, &String::from_utf8_lossy(&name))?,
+            );
+        }
+        Ok(Self {
+            db: db.clone(),
+            registry,
+            open: RwLock::new(open),
+        })
+    }
+
+    pub fn get(&self, tree: &SledTree) -> Option<ProviderTrees> {
+        self.open.read().unwrap().get(tree).cloned()
+    }
+
+    /// The provider's trees, registering it if it's new.
+    pub fn get_or_open(&self, tree: &SledTree) -> sled::Result<ProviderTrees> {
+        if let Some(trees) = self.get(tree) {
+            return Ok(trees);
+        }
+
+        let mut open = self.open.write().unwrap();
+        if let Some(trees) = open.get(tree) {
+            return Ok(trees.clone());
+        }
+        let name = tree.name().to_lowercase();
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            && !name.ends_with("_buckets")
+            && !RESERVED.contains(&name.as_str())
+            && !open.values().any(|trees| trees.name == name);
+        if !valid {
+            return Err(sled::Error::Unsupported(format!(
+                "invalid provider name {:?}",
+                tree.name()
+            )));
+        }
+
+        self.registry.insert(tree.name(), name.as_bytes())?;
+        let trees = ProviderTrees::open(&self.db, &name)?;
+        info!("Storing records for a new provider, {}", tree.name());
+        open.insert(tree.clone(), trees.clone());
+        Ok(trees)
+    }
+
+    /// Every provider, ordered by name.
+    pub fn all(&self) -> Vec<(SledTree, ProviderTrees)> {
+        let open = self.open.read().unwrap();
+        open.iter()
+            .map(|(tree, trees)| (tree.clone(), trees.clone()))
+            .collect()
+    }
+
+    /// The provider whose records tree is called `name`, ignoring case.
+    pub fn find(&self, name: &str) -> Option<SledTree> {
+        let name = name.to_lowercase();
+        let open = self.open.read().unwrap();
+        open.iter()
+            .find(|(_, trees)| trees.name == name)
+            .map(|(tree, _)| tree.clone())
+    }
+}
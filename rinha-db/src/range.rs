@@ -0,0 +1,118 @@
+use std::ops::RangeInclusive;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Deserialize;
+use shared_types::bucket_range;
+
+use crate::keys;
+
+/// Query string of the routes reading a `requestedAt` range.
+#[derive(Deserialize)]
+pub struct RangeQuery {
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+impl RangeQuery {
+    pub fn parse(&self) -> Result<TimeRange, String> {
+        TimeRange::parse(self.from.as_deref(), self.to.as_deref())
+    }
+}
+
+/// Query string of `/summary/buckets`, a range plus the width of each bucket.
+#[derive(Deserialize)]
+pub struct SeriesQuery {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub interval: Option<String>,
+}
+
+impl SeriesQuery {
+    /// The range and the interval in seconds, one second unless `1m` is asked for.
+    pub fn parse(&self) -> Result<(TimeRange, u64), String> {
+        let interval = match self.interval.as_deref() {
+            None | Some("1s") => 1,
+            Some("1m") => 60,
+            Some(other) => return Err(format!("invalid interval {other:?}, expected 1s or 1m")),
+        };
+        let range = TimeRange::parse(self.from.as_deref(), self.to.as_deref())?;
+        Ok((range, interval))
+    }
+}
+
+/// A `requestedAt` range, both ends inclusive. A missing bound leaves that side open.
+#[derive(Clone, Copy, Debug)]
+pub struct TimeRange {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+}
+
+impl TimeRange {
+    pub fn parse(from: Option<&str>, to: Option<&str>) -> Result<Self, String> {
+        let bound = |name: &str, value: Option<&str>, open: DateTime<Utc>| match value {
+            None => Ok(open),
+            Some(value) => keys::parse_timestamp(value)
+                .ok_or_else(|| format!("invalid {name} {value:?}, expected an RFC 3339 timestamp")),
+        };
+        let range = Self {
+            from: bound("from", from, DateTime::<Utc>::MIN_UTC)?,
+            to: bound("to", to, DateTime::<Utc>::MAX_UTC)?,
+        };
+        if range.from > range.to {
+            return Err(format!("from {} is after to {}", range.from, range.to));
+        }
+        Ok(range)
+    }
+
+    /// Whether every payment a worker could have stored falls in the range, as with the
+    /// gateway's default bounds.
+    pub fn covers_everything(&self) -> bool {
+        self.from <= DateTime::UNIX_EPOCH && self.to >= last_bucket()
+    }
+
+    pub fn millis(&self) -> (u64, u64) {
+        (keys::to_millis(self.from), keys::to_millis(self.to))
+    }
+
+    /// Bounds of the aggregate buckets, keyed by four-digit years, the range touches.
+    pub fn buckets(&self) -> RangeInclusive<Vec<u8>> {
+        let bucket = |at: DateTime<Utc>| {
+            at.clamp(DateTime::UNIX_EPOCH, last_bucket())
+                .format("%Y-%m-%dT%H:%M:%S")
+                .to_string()
+        };
+        bucket_range(&bucket(self.from), &bucket(self.to))
+    }
+
+    /// The range cut at whole seconds into contiguous parts of at most `secs` seconds each,
+    /// and no more than `max_parts` of them. Open ends only count up to the seconds aggregate
+    /// buckets can hold, as no payment is stored outside of them.
+    pub fn split(&self, secs: i64, max_parts: usize) -> Vec<TimeRange> {
+        let first = self.from.max(DateTime::UNIX_EPOCH).timestamp();
+        let last = self.to.min(last_bucket()).timestamp();
+        let seconds = last - first;
+        let parts = ((seconds + secs - 1) / secs).clamp(1, max_parts as i64);
+        if parts == 1 {
+            return vec![*self];
+        }
+
+        let step = seconds / parts;
+        let mut split = Vec::with_capacity(parts as usize);
+        let mut from = self.from;
+        for part in 1..parts {
+            let boundary = DateTime::from_timestamp(first + step * part, 0).unwrap_or(self.to);
+            split.push(TimeRange {
+                from,
+                to: boundary - TimeDelta::milliseconds(1),
+            });
+            from = boundary;
+        }
+        split.push(TimeRange { from, to: self.to });
+        split
+    }
+}
+
+/// Start of the last second four-digit years reach.
+fn last_bucket() -> DateTime<Utc> {
+    "9999-12-31T23:59:59Z".parse().unwrap()
+}
@@ -0,0 +1,367 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use shared_types::{AggregateDelta, DBWrite, SledTree, codec};
+use sled::{Db, Tree};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    sync::watch,
+};
+use tracing::{debug, error, info};
+use uuid::Uuid;
+
+use crate::AppState;
+
+/// How many log entries are read from sled between writes to a follower.
+const CHUNK: usize = 1024;
+/// How often a follower confirms what it applied and flushed, letting the primary trim its log.
+const CONFIRM_EVERY: Duration = Duration::from_secs(1);
+
+/// A change accepted by the primary, replayed as is by its followers.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Replicated {
+    Writes(Vec<DBWrite>),
+    Aggregate(Vec<AggregateDelta>),
+    Purge(SledTree),
+    /// The provider's records requested between these milliseconds, both inclusive, were
+    /// purged.
+    PurgeRange(SledTree, u64, u64),
+    /// Records requested before these milliseconds were compacted.
+    Compact(u64),
+    /// The payment with this correlation id was deleted.
+    Delete(Uuid),
+}
+
+/// Every change the primary accepted, keyed by a big-endian sequence number and stored as the
+/// frame sent to followers, so catching up after a reconnect is a range scan. Entries every
+/// follower confirmed are trimmed.
+pub struct ReplicationLog {
+    tree: Tree,
+    /// Entry each follower confirmed it will resume from, and when, keyed by its id.
+    followers: Tree,
+    /// Next sequence number, held from applying a change to appending it so entries land in
+    /// the order changes were applied.
+    next: Mutex<u64>,
+    /// First entry still logged, held while trimming.
+    first: Mutex<u64>,
+    appended: watch::Sender<u64>,
+    /// Followers not heard from in this long stop holding back trimming, `None` waits for them
+    /// forever.
+    retain: Option<Duration>,
+}
+
+impl ReplicationLog {
+    pub fn open(db: &Db, retain: Option<Duration>) -> sled::Result<Self> {
+        let tree = db.open_tree("replication_log")?;
+        let followers = db.open_tree("replication_followers")?;
+        // A log trimmed whole continues after the last entry a follower confirmed.
+        let mut next = match tree.last()? {
+            Some((key, _)) => sequence(&key) + 1,
+            None => 0,
+        };
+        for entry in followers.iter().values() {
+            next = next.max(sequence(&entry?[..8]));
+        }
+        let first = match tree.first()? {
+            Some((key, _)) => sequence(&key),
+            None => next,
+        };
+        Ok(Self {
+            tree,
+            followers,
+            next: Mutex::new(next),
+            first: Mutex::new(first),
+            appended: watch::channel(next).0,
+            retain,
+        })
+    }
+
+    /// Apply a change and append the changes `logged` returns for it, holding the sequence
+    /// throughout so no other change is applied in between.
+    pub fn record<T>(
+        &self,
+        apply: impl FnOnce() -> sled::Result<T>,
+        logged: impl FnOnce(&T) -> Vec<Replicated>,
+    ) -> sled::Result<T> {
+        let mut next = self.next.lock().unwrap();
+        let applied = apply()?;
+        for change in logged(&applied) {
+            let frame = codec::encode(&(*next, change))
+                .map_err(|e| sled::Error::Unsupported(e.to_string()))?;
+            self.tree.insert(next.to_be_bytes(), frame)?;
+            *next += 1;
+        }
+        self.appended.send_replace(*next);
+        Ok(applied)
+    }
+
+    /// The sequence the next change will be logged under, so every change before it is
+    /// applied.
+    pub fn head(&self) -> u64 {
+        *self.next.lock().unwrap()
+    }
+
+    /// Register a follower resuming from `next`, unless the entries it needs were trimmed
+    /// already, returning the first entry still logged.
+    fn join(&self, follower: Uuid, next: u64) -> sled::Result<u64> {
+        let first = self.first.lock().unwrap();
+        if next >= *first {
+            self.followers
+                .insert(follower.as_bytes(), confirmation(next).as_slice())?;
+        }
+        Ok(*first)
+    }
+
+    /// Record that `follower` applied and flushed every entry before `next`, then trim the
+    /// entries every follower heard from within `retain` confirmed.
+    fn confirm(&self, follower: Uuid, next: u64) -> sled::Result<()> {
+        let mut first = self.first.lock().unwrap();
+        self.followers
+            .insert(follower.as_bytes(), confirmation(next).as_slice())?;
+
+        let now = unix_secs();
+        let mut confirmed: Option<u64> = None;
+        for entry in self.followers.iter().values() {
+            let entry = entry?;
+            let (next, at) = entry.split_at(8);
+            let heard = now.saturating_sub(sequence(at));
+            if self.retain.is_none_or(|retain| heard <= retain.as_secs()) {
+                confirmed = Some(confirmed.map_or(sequence(next), |c| c.min(sequence(next))));
+            }
+        }
+        let Some(confirmed) = confirmed.filter(|confirmed| *confirmed > *first) else {
+            return Ok(());
+        };
+        let mut trimmed = sled::Batch::default();
+        for key in self.tree.range(..confirmed.to_be_bytes()).keys() {
+            trimmed.remove(key?);
+        }
+        self.tree.apply_batch(trimmed)?;
+        *first = confirmed;
+        debug!("Trimmed the replication log up to entry {confirmed}");
+        Ok(())
+    }
+
+    /// Frames of up to `CHUNK` entries starting at `from`, with the sequence after the last.
+    fn read(&self, from: u64) -> sled::Result<(Vec<u8>, u64)> {
+        let (mut frames, mut next) = (Vec::new(), from);
+        for entry in self.tree.range(from.to_be_bytes()..).take(CHUNK) {
+            let (key, frame) = entry?;
+            frames.extend_from_slice(&frame);
+            next = sequence(&key) + 1;
+        }
+        Ok((frames, next))
+    }
+}
+
+fn sequence(key: &[u8]) -> u64 {
+    u64::from_be_bytes(key.try_into().expect("Expected 8 bytes"))
+}
+
+/// A follower's next entry followed by the second it confirmed it.
+fn confirmation(next: u64) -> [u8; 16] {
+    let mut value = [0; 16];
+    value[..8].copy_from_slice(&next.to_be_bytes());
+    value[8..].copy_from_slice(&unix_secs().to_be_bytes());
+    value
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Stream the log to followers connecting on `addr`, a unix socket path or a TCP address.
+pub async fn serve(addr: String, log: Arc<ReplicationLog>) -> anyhow::Result<()> {
+    info!("Replicating to followers on {addr}");
+    if addr.starts_with('/') {
+        if Path::new(&addr).exists() {
+            std::fs::remove_file(&addr)?;
+        }
+        let listener = UnixListener::bind(&addr)?;
+        loop {
+            let (stream, _) = listener.accept().await?;
+            tokio::spawn(follow(stream, log.clone()));
+        }
+    } else {
+        let listener = TcpListener::bind(&addr).await?;
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            info!("Follower connected from {peer}");
+            tokio::spawn(follow(stream, log.clone()));
+        }
+    }
+}
+
+async fn follow<S: AsyncRead + AsyncWrite + Unpin>(stream: S, log: Arc<ReplicationLog>) {
+    if let Err(e) = stream_log(stream, &log).await {
+        error!("Replication stream ended: {e}");
+    }
+}
+
+/// Send every entry from the sequence the follower asks for, then keep sending new ones while
+/// reading what it confirms.
+async fn stream_log<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    log: &ReplicationLog,
+) -> anyhow::Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let Some((follower, next)) = codec::read_frame::<_, (Uuid, u64)>(&mut reader).await? else {
+        return Ok(());
+    };
+    let first = log.join(follower, next)?;
+    let appended = log.appended.subscribe();
+    // Where the log starts and ends, so the follower knows whether it can catch up and when
+    // it did.
+    let head = *appended.borrow();
+    codec::write_frame(&mut writer, &(first, head)).await?;
+    if next < first {
+        anyhow::bail!("follower {follower} asked for entry {next}, the log starts at {first}");
+    }
+
+    tokio::select! {
+        sent = send_log(&mut writer, log, next, appended) => sent,
+        confirmed = read_confirmations(&mut reader, log, follower) => confirmed,
+    }
+}
+
+async fn send_log<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    log: &ReplicationLog,
+    mut next: u64,
+    mut appended: watch::Receiver<u64>,
+) -> anyhow::Result<()> {
+    loop {
+        appended.borrow_and_update();
+        loop {
+            let (frames, after) = log.read(next)?;
+            if frames.is_empty() {
+                break;
+            }
+            writer.write_all(&frames).await?;
+            next = after;
+        }
+        writer.flush().await?;
+        appended.changed().await?;
+    }
+}
+
+async fn read_confirmations<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    log: &ReplicationLog,
+    follower: Uuid,
+) -> anyhow::Result<()> {
+    while let Some(next) = codec::read_frame::<_, u64>(reader).await? {
+        log.confirm(follower, next)?;
+    }
+    Ok(())
+}
+
+/// Follow the primary at `addr`, applying its changes and reconnecting from the last applied
+/// one whenever the connection drops.
+pub async fn replicate_from(addr: String, state: AppState, progress: Tree) {
+    loop {
+        let result = if addr.starts_with('/') {
+            match UnixStream::connect(&addr).await {
+                Ok(stream) => apply_stream(stream, &state, &progress).await,
+                Err(e) => Err(e.into()),
+            }
+        } else {
+            match TcpStream::connect(&addr).await {
+                Ok(stream) => apply_stream(stream, &state, &progress).await,
+                Err(e) => Err(e.into()),
+            }
+        };
+        if let Err(e) = result {
+            error!("Replication from {addr} failed: {e}");
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn apply_stream<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    state: &AppState,
+    progress: &Tree,
+) -> anyhow::Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let follower = follower_id(progress)?;
+    let next = applied(progress)?;
+    codec::write_frame(&mut writer, &(follower, next)).await?;
+    let Some((first, head)) = codec::read_frame::<_, (u64, u64)>(&mut reader).await? else {
+        anyhow::bail!("the primary closed the connection");
+    };
+    if next < first {
+        anyhow::bail!(
+            "the primary trimmed its log up to entry {first} before this follower applied \
+             entry {next}, start it from a copy of the primary's database"
+        );
+    }
+    info!("Following the primary from entry {next}, {head} logged so far");
+    if next >= head {
+        state.readiness.caught_up();
+    }
+
+    let (applied, confirmed) = watch::channel(next);
+    tokio::select! {
+        closed = apply_entries(&mut reader, state, progress, head, applied) => closed,
+        confirmed = confirm(&mut writer, &state.db, confirmed) => confirmed,
+    }
+}
+
+async fn apply_entries<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    state: &AppState,
+    progress: &Tree,
+    head: u64,
+    applied: watch::Sender<u64>,
+) -> anyhow::Result<()> {
+    while let Some((entry, change)) = codec::read_frame::<_, (u64, Replicated)>(reader).await? {
+        state.apply(change, progress, entry + 1)?;
+        applied.send_replace(entry + 1);
+        if entry + 1 >= head {
+            state.readiness.caught_up();
+        }
+    }
+    anyhow::bail!("the primary closed the connection")
+}
+
+/// Every `CONFIRM_EVERY`, tell the primary the entry to resume from, once what came before it
+/// is flushed here, so it can trim what every follower holds.
+async fn confirm<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    db: &Db,
+    applied: watch::Receiver<u64>,
+) -> anyhow::Result<()> {
+    let mut ticks = tokio::time::interval(CONFIRM_EVERY);
+    loop {
+        ticks.tick().await;
+        let next = *applied.borrow();
+        db.flush_async().await?;
+        codec::write_frame(writer, &next).await?;
+    }
+}
+
+/// The entry a follower resumes from, so every one before it is applied.
+pub fn applied(progress: &Tree) -> sled::Result<u64> {
+    Ok(progress.get("next")?.map_or(0, |next| sequence(&next)))
+}
+
+/// The id this follower confirms entries under, generated the first time it follows.
+fn follower_id(progress: &Tree) -> sled::Result<Uuid> {
+    if let Some(id) = progress.get("id")? {
+        if let Ok(id) = Uuid::from_slice(&id) {
+            return Ok(id);
+        }
+    }
+    let id = Uuid::new_v4();
+    progress.insert("id", id.as_bytes())?;
+    Ok(id)
+}
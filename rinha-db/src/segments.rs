@@ -0,0 +1,556 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    ptr::{self, NonNull},
+    slice,
+    sync::{
+        Arc, RwLock,
+        atomic::{Ordering, fence},
+    },
+};
+
+use shared_types::{DBWrite, SledTree, Summary};
+use tracing::{info, warn};
+
+use crate::{
+    engine::PaymentStore, keys, metrics::Metrics, providers::Providers, range::TimeRange,
+    storage::Bucket, storage_key, value::StoredValue,
+};
+
+/// Size of a new segment file. A batch too large for one gets a segment as large as it needs.
+const SEGMENT_BYTES: usize = 64 << 20;
+/// Each frame starts with the length of what follows, as a big-endian u32.
+const FRAME_HEADER: usize = 4;
+
+/// A segment file mapped in memory. Appending copies into the map, which the kernel writes back
+/// on its own, so a crash of the process loses nothing appended before it.
+struct Segment {
+    map: NonNull<u8>,
+    len: usize,
+    /// Where the next frame goes.
+    end: usize,
+}
+
+// The map is owned by the segment like a buffer: written through `&mut self`, read through
+// `&self`.
+unsafe impl Send for Segment {}
+unsafe impl Sync for Segment {}
+
+impl Segment {
+    fn create(path: &Path, len: usize) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        file.set_len(len as u64)?;
+        Self::map(&file, len, 0)
+    }
+
+    /// Map a segment again to append after its last frame, which ends at `end`.
+    fn reopen(path: &Path, end: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let len = file.metadata()?.len() as usize;
+        Self::map(&file, len, end)
+    }
+
+    fn map(file: &File, len: usize, end: usize) -> io::Result<Self> {
+        // SAFETY: a new shared mapping of the whole file, which outlives the file descriptor and
+        // is unmapped once on drop.
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            map: NonNull::new(map.cast()).expect("mmap returned a null pointer"),
+            len,
+            end,
+        })
+    }
+
+    fn fits(&self, frame: &[u8]) -> bool {
+        self.end + FRAME_HEADER + frame.len() <= self.len
+    }
+
+    fn read(&self, at: usize, len: usize) -> &[u8] {
+        assert!(at + len <= self.len, "read past the end of a segment");
+        // SAFETY: within the map, which is only written through `&mut self`.
+        unsafe { slice::from_raw_parts(self.map.as_ptr().add(at), len) }
+    }
+
+    /// Append a frame, returning where it starts. Its length goes in last, so a frame cut short
+    /// by a crash reads as the end of the segment.
+    fn append(&mut self, frame: &[u8]) -> usize {
+        let start = self.end + FRAME_HEADER;
+        // SAFETY: the map is `len` bytes long and only written through `&mut self`.
+        let bytes = unsafe { slice::from_raw_parts_mut(self.map.as_ptr(), self.len) };
+        bytes[start..start + frame.len()].copy_from_slice(frame);
+        fence(Ordering::Release);
+        bytes[self.end..start].copy_from_slice(&(frame.len() as u32).to_be_bytes());
+        self.end = start + frame.len();
+        start
+    }
+
+    /// Write the mapped pages back to the file and wait for it.
+    fn sync(&self) -> io::Result<()> {
+        // SAFETY: the range is exactly the live mapping.
+        if unsafe { libc::msync(self.map.as_ptr().cast(), self.len, libc::MS_SYNC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        // SAFETY: the range is exactly the live mapping, and nothing reads it past this point.
+        unsafe { libc::munmap(self.map.as_ptr().cast(), self.len) };
+    }
+}
+
+/// Where the latest value of a record sits in its provider's segments.
+#[derive(Clone, Copy)]
+struct Location {
+    /// Position of the segment in `Segments::files`.
+    segment: u32,
+    offset: u32,
+    len: u16,
+}
+
+impl Location {
+    fn amount(self, files: &[Segment]) -> Option<f64> {
+        let value = files[self.segment as usize].read(self.offset as usize, self.len as usize);
+        StoredValue::decode(value).map(|value| value.amount())
+    }
+}
+
+/// Every key of a provider and where its latest value is, as of the last frame appended. The
+/// values themselves are only read from the mapped segments.
+#[derive(Default)]
+struct Index {
+    keys: BTreeMap<Vec<u8>, Location>,
+    total: Bucket,
+}
+
+impl Index {
+    /// Point the key at a value, false if the value can't be read and so isn't indexed.
+    fn insert(&mut self, files: &[Segment], key: &[u8], at: Location) -> bool {
+        let Some(amount) = at.amount(files) else {
+            return false;
+        };
+        if let Some(replaced) = self.keys.insert(key.to_vec(), at) {
+            // Only values that could be read were indexed.
+            if let Some(replaced) = replaced.amount(files) {
+                self.total.sub(Bucket::of(key, replaced));
+            }
+        }
+        self.total.add(Bucket::of(key, amount));
+        true
+    }
+}
+
+/// Frames hold records as the key length in one byte, the key, the value length as a
+/// big-endian u16, then the value as [`StoredValue`] encodes it.
+fn encode(entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(entries.len() * 64);
+    for (key, value) in entries {
+        frame.push(key.len() as u8);
+        frame.extend_from_slice(key);
+        frame.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        frame.extend_from_slice(value);
+    }
+    frame
+}
+
+/// The records of a frame, as their key and where their value is in the frame.
+fn entries(frame: &[u8]) -> impl Iterator<Item = (&[u8], usize, usize)> {
+    let mut at = 0;
+    std::iter::from_fn(move || {
+        let len = *frame.get(at)? as usize;
+        let key = frame.get(at + 1..at + 1 + len)?;
+        at += 1 + len;
+        let len = u16::from_be_bytes(frame.get(at..at + 2)?.try_into().ok()?) as usize;
+        let value = at + 2;
+        frame.get(value..value + len)?;
+        at = value + len;
+        Some((key, value, len))
+    })
+}
+
+/// A provider's segments, oldest first, and the index over them.
+#[derive(Default)]
+struct Segments {
+    files: Vec<Segment>,
+    /// Sequence number of the next segment.
+    next: u64,
+    index: Index,
+}
+
+impl Segments {
+    /// Index the frames of a segment, returning where the last complete one ends and how many
+    /// records in them couldn't be read.
+    fn replay(&mut self, segment: u32) -> (usize, usize) {
+        let Self { files, index, .. } = self;
+        let file = &files[segment as usize];
+        let bytes = file.read(0, file.len);
+        let (mut end, mut unreadable) = (0, 0);
+        while let Some(header) = bytes.get(end..end + FRAME_HEADER) {
+            let len = u32::from_be_bytes(header.try_into().unwrap()) as usize;
+            let start = end + FRAME_HEADER;
+            let Some(frame) = bytes.get(start..start + len) else {
+                break;
+            };
+            if len == 0 {
+                break;
+            }
+            for (key, offset, len) in entries(frame) {
+                let at = Location {
+                    segment,
+                    offset: (start + offset) as u32,
+                    len: len as u16,
+                };
+                if !index.insert(files, key, at) {
+                    unreadable += 1;
+                }
+            }
+            end = start + len;
+        }
+        (end, unreadable)
+    }
+
+    /// Append the records as one frame, so a crash keeps all of them or none.
+    fn append(&mut self, dir: &Path, entries: &[(Vec<u8>, Vec<u8>)]) -> io::Result<()> {
+        let frame = encode(entries);
+        if !self.files.last().is_some_and(|file| file.fits(&frame)) {
+            // The segment left behind isn't synced by later flushes, so it's synced now.
+            if let Some(full) = self.files.last() {
+                full.sync()?;
+            }
+            let path = dir.join(format!("{:010}.seg", self.next));
+            let len = SEGMENT_BYTES.max(FRAME_HEADER + frame.len());
+            self.files.push(Segment::create(&path, len)?);
+            self.next += 1;
+        }
+        let segment = self.files.len() - 1;
+        let start = self.files[segment].append(&frame);
+        for ((key, _), (_, offset, len)) in entries.iter().zip(self::entries(&frame)) {
+            let at = Location {
+                segment: segment as u32,
+                offset: (start + offset) as u32,
+                len: len as u16,
+            };
+            self.index.insert(&self.files, key, at);
+        }
+        Ok(())
+    }
+}
+
+/// One provider's directory of segments, named after their sequence number so they sort in the
+/// order they were written. Every segment stays mapped, since the index points into all of them.
+struct ProviderLog {
+    dir: PathBuf,
+    segments: RwLock<Segments>,
+}
+
+impl ProviderLog {
+    fn open(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let mut paths = segment_paths(&dir)?;
+        paths.sort();
+
+        let mut segments = Segments::default();
+        let mut unreadable = 0;
+        for path in paths {
+            let segment = segments.files.len();
+            segments.files.push(Segment::reopen(&path, 0)?);
+            let (end, skipped) = segments.replay(segment as u32);
+            segments.files[segment].end = end;
+            segments.next = sequence(&path).map_or(0, |sequence| sequence + 1);
+            unreadable += skipped;
+        }
+        if unreadable > 0 {
+            warn!(
+                "Skipped {unreadable} unreadable records in {}",
+                dir.display()
+            );
+        }
+        Ok(Self {
+            dir,
+            segments: RwLock::new(segments),
+        })
+    }
+
+    fn append(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> io::Result<()> {
+        self.segments.write().unwrap().append(&self.dir, entries)
+    }
+
+    fn summary(&self, range: &TimeRange) -> Summary {
+        let segments = self.segments.read().unwrap();
+        if range.covers_everything() {
+            return segments.index.total.into();
+        }
+        let (from, to) = range.millis();
+        let mut total = Bucket::default();
+        for (key, at) in segments.index.keys.range(keys::range(from, to)) {
+            if let Some(amount) = at.amount(&segments.files) {
+                total.add(Bucket::of(key, amount));
+            }
+        }
+        total.into()
+    }
+
+    fn purge(&self) -> io::Result<usize> {
+        let mut segments = self.segments.write().unwrap();
+        let removed = segments.index.keys.len();
+        // Unmapped before the files go.
+        *segments = Segments::default();
+        for path in segment_paths(&self.dir)? {
+            fs::remove_file(path)?;
+        }
+        Ok(removed)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        match self.segments.read().unwrap().files.last() {
+            Some(segment) => segment.sync(),
+            None => Ok(()),
+        }
+    }
+}
+
+fn segment_paths(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "seg") {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+fn sequence(path: &Path) -> Option<u64> {
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Payment records appended to memory-mapped segment files, one directory per provider under
+/// `SEGMENTS_DIR`, in the same value format as sled. Only an index of the keys is kept in memory,
+/// rebuilt from the segments on startup. Nothing is ever rewritten in place: an overwritten
+/// record is appended again and the index points at the latest copy.
+pub struct SegmentStore {
+    dir: PathBuf,
+    providers: Arc<Providers>,
+    metrics: Arc<Metrics>,
+    logs: RwLock<HashMap<SledTree, Arc<ProviderLog>>>,
+}
+
+impl SegmentStore {
+    /// Replay the segments of every provider registered so far.
+    pub fn open(
+        providers: Arc<Providers>,
+        metrics: Arc<Metrics>,
+        dir: PathBuf,
+    ) -> anyhow::Result<Self> {
+        let mut logs = HashMap::new();
+        let mut replayed = 0;
+        for (tree, trees) in providers.all() {
+            let log = ProviderLog::open(dir.join(&trees.name))?;
+            replayed += log.segments.read().unwrap().index.keys.len();
+            logs.insert(tree, Arc::new(log));
+        }
+        info!(
+            "Storing payments in segments under {}, replayed {replayed} records",
+            dir.display()
+        );
+        Ok(Self {
+            dir,
+            providers,
+            metrics,
+            logs: RwLock::new(logs),
+        })
+    }
+
+    /// The provider's log, registering the provider if it's new.
+    fn log(&self, tree: &SledTree) -> sled::Result<Arc<ProviderLog>> {
+        if let Some(log) = self.logs.read().unwrap().get(tree) {
+            return Ok(log.clone());
+        }
+        let trees = self.providers.get_or_open(tree)?;
+        let mut logs = self.logs.write().unwrap();
+        if let Some(log) = logs.get(tree) {
+            return Ok(log.clone());
+        }
+        let log = Arc::new(ProviderLog::open(self.dir.join(&trees.name))?);
+        logs.insert(tree.clone(), log.clone());
+        Ok(log)
+    }
+}
+
+impl PaymentStore for SegmentStore {
+    fn insert(&self, write: &DBWrite) -> sled::Result<()> {
+        let key = storage_key(write)?;
+        let value = StoredValue::new(write.value, &key, &write.tree).encode();
+        self.log(&write.tree)?.append(&[(key, value)])?;
+        self.metrics.record_inserts(1);
+        Ok(())
+    }
+
+    /// Every key is checked before anything is appended, then each provider's records go in a
+    /// single frame.
+    fn insert_batch(&self, writes: &[DBWrite]) -> sled::Result<()> {
+        let mut by_tree = BTreeMap::<&SledTree, Vec<(Vec<u8>, Vec<u8>)>>::new();
+        for write in writes {
+            let key = storage_key(write)?;
+            let value = StoredValue::new(write.value, &key, &write.tree).encode();
+            by_tree.entry(&write.tree).or_default().push((key, value));
+        }
+
+        for (tree, entries) in by_tree {
+            self.log(tree)?.append(&entries)?;
+            self.metrics.record_batch(entries.len());
+        }
+        Ok(())
+    }
+
+    fn summary(&self, tree: &SledTree, range: &TimeRange) -> Summary {
+        match self.logs.read().unwrap().get(tree) {
+            Some(log) => log.summary(range),
+            None => Summary::new(),
+        }
+    }
+
+    /// Deletes the provider's segment files.
+    fn purge(&self, tree: &SledTree) -> sled::Result<usize> {
+        let log = self.logs.read().unwrap().get(tree).cloned();
+        match log {
+            Some(log) => Ok(log.purge()?),
+            None => Ok(0),
+        }
+    }
+
+    /// Segments are only ever appended to, so a range can't be taken out of them.
+    fn purge_range(&self, _: &SledTree, _: (u64, u64)) -> sled::Result<usize> {
+        Err(sled::Error::Unsupported(
+            "DB_ENGINE=segments can only purge a whole provider".to_string(),
+        ))
+    }
+
+    fn purge_all(&self) -> sled::Result<Vec<(SledTree, usize)>> {
+        self.providers
+            .all()
+            .into_iter()
+            .map(|(tree, _)| Ok((tree.clone(), self.purge(&tree)?)))
+            .collect()
+    }
+
+    fn flush(&self) -> sled::Result<()> {
+        let logs = self
+            .logs
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        for log in logs {
+            log.sync()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ops::Range, process};
+
+    use chrono::DateTime;
+
+    use super::*;
+
+    const START: u64 = 1_752_580_800_000;
+
+    /// A payment requested `second`s after `START`.
+    fn key(second: u64, id: u128) -> Vec<u8> {
+        let at = DateTime::from_timestamp_millis((START + second * 1000) as i64).unwrap();
+        let key = format!("{}#{}", at.to_rfc3339(), uuid::Uuid::from_u128(id));
+        keys::from_write_key(&key).unwrap()
+    }
+
+    fn record(second: u64, id: u128, amount: f64) -> (Vec<u8>, Vec<u8>) {
+        let key = key(second, id);
+        let value = StoredValue::new(amount, &key, &SledTree::DEFAULT).encode();
+        (key, value)
+    }
+
+    fn sum(log: &ProviderLog, seconds: Range<u64>) -> (u64, f64) {
+        let at = |second: u64| DateTime::from_timestamp_millis((START + second * 1000) as i64);
+        let range = TimeRange {
+            from: at(seconds.start).unwrap(),
+            to: at(seconds.end).unwrap() - chrono::Duration::milliseconds(1),
+        };
+        let summary = log.summary(&range);
+        (summary.total_requests, summary.total_amount)
+    }
+
+    fn total(log: &ProviderLog) -> (u64, f64) {
+        let summary = log.summary(&TimeRange::parse(None, None).unwrap());
+        (summary.total_requests, summary.total_amount)
+    }
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rinha-segments-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn the_index_is_rebuilt_from_the_segments() {
+        let dir = dir("rebuilt");
+        let log = ProviderLog::open(dir.clone()).unwrap();
+        for second in 0..10 {
+            let batch: Vec<_> = (0..3).map(|id| record(second, id, 1.0)).collect();
+            log.append(&batch).unwrap();
+        }
+        // Overwrites only count their latest amount.
+        log.append(&[record(4, 0, 5.0), record(4, 0, 2.5)]).unwrap();
+        assert_eq!(total(&log), (30, 31.5));
+        assert_eq!(sum(&log, 3..5), (6, 7.5));
+        drop(log);
+
+        let log = ProviderLog::open(dir.clone()).unwrap();
+        assert_eq!(total(&log), (30, 31.5));
+        assert_eq!(sum(&log, 3..5), (6, 7.5));
+        // Appending goes on after the last frame.
+        log.append(&[record(11, 0, 1.0)]).unwrap();
+        drop(log);
+        assert_eq!(total(&ProviderLog::open(dir.clone()).unwrap()), (31, 32.5));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn unreadable_values_are_skipped() {
+        let dir = dir("unreadable");
+        let log = ProviderLog::open(dir.clone()).unwrap();
+        log.append(&[record(0, 0, 1.0), (key(1, 0), vec![0; 12])])
+            .unwrap();
+        assert_eq!(total(&log), (1, 1.0));
+        drop(log);
+
+        let log = ProviderLog::open(dir.clone()).unwrap();
+        assert_eq!(total(&log), (1, 1.0));
+        assert_eq!(log.purge().unwrap(), 1);
+        assert_eq!(total(&ProviderLog::open(dir.clone()).unwrap()), (0, 0.0));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
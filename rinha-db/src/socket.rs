@@ -0,0 +1,133 @@
+use shared_types::{DbRequest, DbResponse, codec, logging};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    sync::watch,
+    task::JoinSet,
+};
+use tracing::{Instrument, debug, debug_span, error};
+
+use crate::{AppState, READ_ONLY, range::TimeRange};
+
+/// Serve binary-framed requests from the api workers on a unix socket until `stopped` is set,
+/// then wait for the requests in flight to be answered.
+pub async fn serve(listener: UnixListener, state: AppState, stopped: watch::Receiver<bool>) {
+    let mut connections = JoinSet::new();
+    let mut stop = stopped.clone();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    let (state, stopped) = (state.clone(), stopped.clone());
+                    connections.spawn(async move {
+                        if let Err(e) = handle_connection(stream, state, stopped).await {
+                            error!("Socket connection error: {e}");
+                        }
+                    });
+                }
+                Err(e) => error!("Failed to accept socket connection: {e}"),
+            },
+            // Reap the connections that closed.
+            Some(_) = connections.join_next() => {}
+            _ = stop.wait_for(|stop| *stop) => break,
+        }
+    }
+
+    while connections.join_next().await.is_some() {}
+}
+
+async fn handle_connection(
+    mut stream: UnixStream,
+    state: AppState,
+    mut stopped: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    loop {
+        // A request already read is still answered, only waiting for the next one stops.
+        let request = tokio::select! {
+            request = codec::read_frame::<_, DbRequest>(&mut stream) => request?,
+            _ = stopped.wait_for(|stop| *stop) => None,
+        };
+        let Some(request) = request else {
+            break;
+        };
+        let response = match request {
+            DbRequest::Write(_) | DbRequest::WriteBatch(_) | DbRequest::Aggregate(_)
+                if state.read_only =>
+            {
+                DbResponse::Error(READ_ONLY.to_string())
+            }
+            DbRequest::Write(write) => match state.admit(1) {
+                Ok(admitted) => {
+                    let tree = write.tree.clone();
+                    let span = debug_span!("write", trace_id = write.trace_id.as_deref());
+                    logging::follow(&span, write.traceparent.as_deref());
+                    match state.write(write, admitted).instrument(span).await {
+                        Ok(()) => DbResponse::Ok,
+                        Err(e) => {
+                            error!("Error inserting into {:?} tree: {}", tree, e);
+                            DbResponse::Error(e)
+                        }
+                    }
+                }
+                Err(retry_after) => DbResponse::Busy(retry_after.as_millis() as u64),
+            },
+            DbRequest::WriteBatch(batch) => match state.admit(batch.writes.len()) {
+                Ok(admitted) => {
+                    debug!(
+                        trace_ids = ?batch
+                            .writes
+                            .iter()
+                            .filter_map(|write| write.trace_id.as_deref())
+                            .collect::<Vec<_>>(),
+                        "Writing a batch of {}",
+                        batch.writes.len()
+                    );
+                    // One span per traced write, closed once the whole batch is applied.
+                    let spans = batch
+                        .writes
+                        .iter()
+                        .filter_map(|write| write.traceparent.as_deref())
+                        .map(|traceparent| {
+                            let span = debug_span!("write");
+                            logging::follow(&span, Some(traceparent));
+                            span
+                        })
+                        .collect::<Vec<_>>();
+                    let written = state.write_batch(batch.writes, admitted);
+                    drop(spans);
+                    match written {
+                        Ok(()) => DbResponse::Ok,
+                        Err(e) => {
+                            error!("Error inserting batch: {}", e);
+                            DbResponse::Error(e.to_string())
+                        }
+                    }
+                }
+                Err(retry_after) => DbResponse::Busy(retry_after.as_millis() as u64),
+            },
+            DbRequest::Summary(read) => match TimeRange::parse(Some(&read.from), Some(&read.to)) {
+                Ok(range) => DbResponse::Summary(state.summary(&range).await),
+                Err(e) => DbResponse::Error(e),
+            },
+            DbRequest::Aggregate(push) => match state.merge_push(&push) {
+                Ok(_) => DbResponse::Ok,
+                Err(e) => {
+                    error!("Error merging aggregates: {}", e);
+                    DbResponse::Error(e.to_string())
+                }
+            },
+            DbRequest::Records(read) => match TimeRange::parse(Some(&read.from), Some(&read.to)) {
+                Ok(range) => match state
+                    .sled_only("reading records")
+                    .and_then(|()| state.records(&range).collect())
+                {
+                    Ok(records) => DbResponse::Records(records),
+                    Err(e) => DbResponse::Error(e.to_string()),
+                },
+                Err(e) => DbResponse::Error(e),
+            },
+        };
+        codec::write_frame(&mut stream, &response).await?;
+    }
+
+    Ok(())
+}
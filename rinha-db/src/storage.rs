@@ -0,0 +1,924 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    env, hint,
+    ops::{Bound, Range},
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicI64, AtomicU64, Ordering, fence},
+    },
+    thread,
+};
+
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use shared_types::{SledTree, Summary, decode_bucket};
+use sled::{IVec, Tree};
+use tracing::warn;
+
+use crate::{keys, providers::ProviderTrees, value::StoredValue};
+
+/// Most divergent seconds a verification reports.
+const MAX_REPORTED_SECONDS: usize = 100;
+
+/// How much time each in-memory bucket covers, from `SUMMARY_GRANULARITY`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    #[default]
+    Second,
+    /// 60 times fewer buckets over a long history, for up to a minute of records read from sled
+    /// at either end of a summary instead of a second.
+    Minute,
+}
+
+impl Granularity {
+    pub fn from_env() -> anyhow::Result<Self> {
+        match env::var("SUMMARY_GRANULARITY")
+            .unwrap_or("second".to_string())
+            .as_str()
+        {
+            "second" => Ok(Granularity::Second),
+            "minute" => Ok(Granularity::Minute),
+            other => {
+                anyhow::bail!("invalid SUMMARY_GRANULARITY {other:?}, expected second or minute")
+            }
+        }
+    }
+
+    /// Width of a bucket in milliseconds.
+    pub fn millis(self) -> u64 {
+        match self {
+            Granularity::Second => 1000,
+            Granularity::Minute => 60_000,
+        }
+    }
+
+    /// The second the bucket holding `millis` starts at.
+    fn bucket(self, millis: u64) -> u64 {
+        self.floor(millis) / 1000
+    }
+
+    /// `millis` rounded down to the start of its bucket.
+    pub fn floor(self, millis: u64) -> u64 {
+        millis - millis % self.millis()
+    }
+}
+
+/// Requests and amount stored in one bucket, voids already subtracted.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Bucket {
+    requests: i64,
+    amount: f64,
+}
+
+impl Bucket {
+    pub fn add(&mut self, other: Bucket) {
+        self.requests += other.requests;
+        self.amount += other.amount;
+    }
+
+    pub fn sub(&mut self, other: Bucket) {
+        self.requests -= other.requests;
+        self.amount -= other.amount;
+    }
+
+    /// Sum the records read from a payment tree.
+    pub fn sum(records: impl Iterator<Item = sled::Result<(IVec, IVec)>>) -> Self {
+        records
+            .filter_map(Result::ok)
+            .fold(Bucket::default(), |mut bucket, (key, value)| {
+                if let Some(amount) = decode_amount(&key, &value) {
+                    bucket.add(Bucket::of(&key, amount));
+                }
+                bucket
+            })
+    }
+
+    /// Whether both hold the same totals. Amounts only need to match to the cent, since they
+    /// may have been summed in a different order.
+    fn matches(&self, other: &Bucket) -> bool {
+        self.requests == other.requests && (self.amount - other.amount).abs() < 0.005
+    }
+
+    /// What the record stored under `key` adds to the totals.
+    pub fn of(key: &[u8], amount: f64) -> Self {
+        if keys::is_void(key) {
+            Bucket {
+                requests: -1,
+                amount: -amount,
+            }
+        } else {
+            Bucket {
+                requests: 1,
+                amount,
+            }
+        }
+    }
+}
+
+impl From<Bucket> for Summary {
+    fn from(bucket: Bucket) -> Self {
+        Summary {
+            total_requests: bucket.requests.max(0) as u64,
+            total_amount: bucket.amount,
+        }
+    }
+}
+
+/// A bucket updated in place through a shared reference, the amount kept as the bits of an
+/// f64. Both values are kept consistent by a seqlock: a writer makes the sequence odd while it
+/// updates them, and a reader retries until it sees the same even sequence on either side.
+#[derive(Default)]
+struct AtomicBucket {
+    sequence: AtomicU64,
+    requests: AtomicI64,
+    amount: AtomicU64,
+}
+
+impl AtomicBucket {
+    fn add(&self, bucket: Bucket) {
+        let mut sequence = self.sequence.load(Ordering::Relaxed);
+        let mut spins = 0;
+        loop {
+            if sequence % 2 == 1 {
+                backoff(&mut spins);
+                sequence = self.sequence.load(Ordering::Relaxed);
+                continue;
+            }
+            match self.sequence.compare_exchange_weak(
+                sequence,
+                sequence + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => sequence = current,
+            }
+        }
+        fence(Ordering::Release);
+        let requests = self.requests.load(Ordering::Relaxed);
+        self.requests
+            .store(requests + bucket.requests, Ordering::Relaxed);
+        let amount = f64::from_bits(self.amount.load(Ordering::Relaxed));
+        self.amount
+            .store((amount + bucket.amount).to_bits(), Ordering::Relaxed);
+        self.sequence.store(sequence + 2, Ordering::Release);
+    }
+
+    fn sub(&self, bucket: Bucket) {
+        self.add(Bucket {
+            requests: -bucket.requests,
+            amount: -bucket.amount,
+        });
+    }
+
+    fn load(&self) -> Bucket {
+        let mut spins = 0;
+        loop {
+            let before = self.sequence.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                backoff(&mut spins);
+                continue;
+            }
+            let bucket = Bucket {
+                requests: self.requests.load(Ordering::Relaxed),
+                amount: f64::from_bits(self.amount.load(Ordering::Relaxed)),
+            };
+            fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) == before {
+                return bucket;
+            }
+        }
+    }
+}
+
+/// Wait for a bucket's writer to finish, spinning at first, then yielding in case it was
+/// preempted.
+fn backoff(spins: &mut u32) {
+    if *spins < 100 {
+        *spins += 1;
+        hint::spin_loop();
+    } else {
+        thread::yield_now();
+    }
+}
+
+/// Buckets are spread over this many maps, each behind its own lock.
+const SHARDS: usize = 16;
+
+/// The shard of the bucket starting at `second`, hashed so consecutive buckets land in
+/// different shards whatever their width.
+fn shard(second: u64) -> usize {
+    (second.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (64 - SHARDS.trailing_zeros())) as usize
+}
+
+/// A provider's totals. Records and aggregates are added under a shard's read lock, so writers
+/// only wait on each other to open a bucket in the same shard, while compacting and purging
+/// take the write locks one shard at a time.
+#[derive(Default)]
+struct TreeTotals {
+    /// Keyed by the second each bucket starts at, spread over shards by [`shard`].
+    shards: [RwLock<BTreeMap<u64, AtomicBucket>>; SHARDS],
+    /// Every record, so a summary over the whole history doesn't add up the seconds.
+    records: AtomicBucket,
+    /// Every delta merged into the aggregate buckets by workers in aggregate mode.
+    aggregated: AtomicBucket,
+}
+
+impl TreeTotals {
+    /// Add up everything a provider's trees hold.
+    fn count(trees: &ProviderTrees, granularity: Granularity) -> sled::Result<Self> {
+        let totals = Self::default();
+        for entry in trees.records.iter() {
+            let (key, value) = entry?;
+            let (Some(millis), Some(amount)) = (keys::millis(&key), decode_amount(&key, &value))
+            else {
+                continue;
+            };
+            totals.add(granularity.bucket(millis), Bucket::of(&key, amount));
+        }
+        for entry in trees.buckets.iter() {
+            let (_, value) = entry?;
+            let (requests, amount) = decode_bucket(&value);
+            totals.aggregated.add(Bucket { requests, amount });
+        }
+        Ok(totals)
+    }
+
+    /// Add `delta` to the records and the bucket starting at `second`.
+    fn add(&self, second: u64, delta: Bucket) {
+        self.bucket(second, |bucket| bucket.add(delta));
+        self.records.add(delta);
+    }
+
+    /// Update the bucket starting at `second`. Only a bucket that doesn't exist yet takes its
+    /// shard's write lock.
+    fn bucket(&self, second: u64, update: impl FnOnce(&AtomicBucket)) {
+        let shard = &self.shards[shard(second)];
+        if let Some(bucket) = shard.read().unwrap().get(&second) {
+            return update(bucket);
+        }
+        update(shard.write().unwrap().entry(second).or_default());
+    }
+
+    /// Every bucket whose start is within `seconds`, by the second it starts at.
+    fn buckets(&self, seconds: (Bound<u64>, Bound<u64>)) -> BTreeMap<u64, Bucket> {
+        let mut buckets = BTreeMap::new();
+        for shard in &self.shards {
+            let shard = shard.read().unwrap();
+            for (second, bucket) in shard.range(seconds) {
+                buckets.insert(*second, bucket.load());
+            }
+        }
+        buckets
+    }
+
+    fn snapshot(&self) -> TotalsSnapshot {
+        TotalsSnapshot {
+            buckets: self.buckets((Bound::Unbounded, Bound::Unbounded)),
+            records: self.records.load(),
+            aggregated: self.aggregated.load(),
+        }
+    }
+
+    /// Totals of a snapshot, folding its buckets into ones `granularity` wide.
+    fn restore(snapshot: TotalsSnapshot, granularity: Granularity) -> Self {
+        let totals = TreeTotals::default();
+        for (second, bucket) in snapshot.buckets {
+            let second = granularity.bucket(second * 1000);
+            totals.bucket(second, |stored| stored.add(bucket));
+        }
+        totals.records.add(snapshot.records);
+        totals.aggregated.add(snapshot.aggregated);
+        totals
+    }
+
+    /// Add `other`'s totals to these, folding its buckets into ones `granularity` wide.
+    fn merge(&self, other: &TreeTotals, granularity: Granularity) {
+        let all = (Bound::Unbounded, Bound::Unbounded);
+        for (second, bucket) in other.buckets(all) {
+            let second = granularity.bucket(second * 1000);
+            self.bucket(second, |stored| stored.add(bucket));
+        }
+        self.records.add(other.records.load());
+        self.aggregated.add(other.aggregated.load());
+    }
+}
+
+/// Everything a [`SummaryStore`] holds, to be written to disk or sent over and restored as is.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    granularity: Granularity,
+    trees: Vec<(SledTree, TotalsSnapshot)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TotalsSnapshot {
+    buckets: BTreeMap<u64, Bucket>,
+    records: Bucket,
+    aggregated: Bucket,
+}
+
+/// Totals kept in memory next to the ones added up from sled.
+#[derive(Serialize)]
+pub struct Divergence {
+    stored: Bucket,
+    counted: Bucket,
+}
+
+impl Divergence {
+    fn of(stored: Bucket, counted: Bucket) -> Option<Self> {
+        (!stored.matches(&counted)).then_some(Self { stored, counted })
+    }
+}
+
+/// How a provider's in-memory totals compare to what its trees hold.
+#[derive(Serialize)]
+pub struct Verification {
+    pub consistent: bool,
+    /// Set when the totals of the raw records differ.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    records: Option<Divergence>,
+    /// Set when the totals of the aggregate buckets differ.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aggregated: Option<Divergence>,
+    /// The first buckets whose totals differ, by the second they start at.
+    seconds: BTreeMap<String, Divergence>,
+}
+
+/// Per-second, or per-minute, totals of every record in the payment trees, kept in memory so
+/// a summary adds up buckets instead of scanning every key. Sled stays the durable log: the
+/// buckets are rebuilt from it on startup, and the partial buckets at either end of a range are
+/// still read from it so summaries stay exact.
+#[derive(Default)]
+pub struct SummaryStore {
+    granularity: Granularity,
+    trees: RwLock<HashMap<SledTree, Arc<TreeTotals>>>,
+}
+
+impl SummaryStore {
+    pub fn new(granularity: Granularity) -> Self {
+        Self {
+            granularity,
+            ..Self::default()
+        }
+    }
+
+    /// Rebuild the totals from every provider's payment and aggregate bucket trees, each
+    /// provider counted on its own thread and merged in.
+    pub fn load(
+        providers: &[(SledTree, ProviderTrees)],
+        granularity: Granularity,
+    ) -> anyhow::Result<Self> {
+        let counted = thread::scope(|scope| {
+            let counting = providers
+                .iter()
+                .map(|(tree, trees)| {
+                    scope.spawn(move || {
+                        let mut store = SummaryStore::new(granularity);
+                        let counted = TreeTotals::count(trees, granularity)?;
+                        store
+                            .trees
+                            .get_mut()
+                            .unwrap()
+                            .insert(tree.clone(), Arc::new(counted));
+                        Ok(store)
+                    })
+                })
+                .collect::<Vec<_>>();
+            counting
+                .into_iter()
+                .map(|counting| counting.join().expect("Counting a provider panicked"))
+                .collect::<sled::Result<Vec<_>>>()
+        })?;
+
+        let mut store = SummaryStore::new(granularity);
+        for counted in counted {
+            store.merge(counted)?;
+        }
+        Ok(store)
+    }
+
+    pub fn granularity(&self) -> Granularity {
+        self.granularity
+    }
+
+    /// Add every total of `other` to these, as when stores filled separately, one per shard or
+    /// from a replication stream, are brought together. `other` must be as fine-grained as this
+    /// store or finer, its buckets are folded into this store's.
+    pub fn merge(&mut self, other: SummaryStore) -> anyhow::Result<()> {
+        if other.granularity.millis() > self.granularity.millis() {
+            anyhow::bail!(
+                "a store of {:?} buckets can't be merged into {:?} ones",
+                other.granularity,
+                self.granularity
+            );
+        }
+        let trees = self.trees.get_mut().unwrap();
+        for (tree, totals) in other.trees.into_inner().unwrap() {
+            trees
+                .entry(tree)
+                .or_default()
+                .merge(&totals, self.granularity);
+        }
+        Ok(())
+    }
+
+    /// The totals of every provider as they are now, though writes may land while they're
+    /// read.
+    pub fn snapshot(&self) -> Snapshot {
+        let trees = self.trees.read().unwrap();
+        Snapshot {
+            granularity: self.granularity,
+            trees: trees
+                .iter()
+                .map(|(tree, totals)| (tree.clone(), totals.snapshot()))
+                .collect(),
+        }
+    }
+
+    /// Replace every total with a snapshot's. Its buckets must be as fine-grained as this
+    /// store's or finer.
+    pub fn restore(&self, snapshot: Snapshot) -> anyhow::Result<()> {
+        if snapshot.granularity.millis() > self.granularity.millis() {
+            anyhow::bail!(
+                "a snapshot of {:?} buckets can't be restored into {:?} ones",
+                snapshot.granularity,
+                self.granularity
+            );
+        }
+        *self.trees.write().unwrap() = snapshot
+            .trees
+            .into_iter()
+            .map(|(tree, totals)| {
+                let totals = TreeTotals::restore(totals, self.granularity);
+                (tree, Arc::new(totals))
+            })
+            .collect();
+        Ok(())
+    }
+
+    /// Add up the provider's trees again and compare the result to the totals kept in memory.
+    /// Writes landing while it runs show up as divergences.
+    pub fn verify(&self, tree: &SledTree, trees: &ProviderTrees) -> sled::Result<Verification> {
+        let counted = TreeTotals::count(trees, self.granularity)?;
+        let stored = self.totals(tree);
+        let all = (Bound::Unbounded, Bound::Unbounded);
+        let (stored_buckets, counted_buckets) = (stored.buckets(all), counted.buckets(all));
+
+        let mut seconds = BTreeMap::new();
+        let all = stored_buckets.keys().chain(counted_buckets.keys());
+        for second in all.collect::<BTreeSet<_>>() {
+            let divergence = Divergence::of(
+                stored_buckets.get(second).copied().unwrap_or_default(),
+                counted_buckets.get(second).copied().unwrap_or_default(),
+            );
+            if let Some(divergence) = divergence {
+                let at = DateTime::from_timestamp(*second as i64, 0).unwrap_or_default();
+                seconds.insert(at.format("%Y-%m-%dT%H:%M:%SZ").to_string(), divergence);
+                if seconds.len() == MAX_REPORTED_SECONDS {
+                    break;
+                }
+            }
+        }
+        let records = Divergence::of(stored.records.load(), counted.records.load());
+        let aggregated = Divergence::of(stored.aggregated.load(), counted.aggregated.load());
+        Ok(Verification {
+            consistent: records.is_none() && aggregated.is_none() && seconds.is_empty(),
+            records,
+            aggregated,
+            seconds,
+        })
+    }
+
+    fn totals(&self, tree: &SledTree) -> Arc<TreeTotals> {
+        if let Some(totals) = self.trees.read().unwrap().get(tree) {
+            return totals.clone();
+        }
+        self.trees
+            .write()
+            .unwrap()
+            .entry(tree.clone())
+            .or_default()
+            .clone()
+    }
+
+    /// Account for a record just written under `key`, which may have replaced one holding
+    /// `replaced`.
+    pub fn record(&self, tree: &SledTree, key: &[u8], amount: f64, replaced: Option<f64>) {
+        let Some(millis) = keys::millis(key) else {
+            return;
+        };
+        let mut delta = Bucket::of(key, amount);
+        if let Some(replaced) = replaced {
+            let replaced = Bucket::of(key, replaced);
+            delta.requests -= replaced.requests;
+            delta.amount -= replaced.amount;
+        }
+
+        self.add(tree, self.granularity.bucket(millis), delta);
+    }
+
+    /// Add `delta` to the records and the bucket starting at `second`.
+    fn add(&self, tree: &SledTree, second: u64, delta: Bucket) {
+        self.totals(tree).add(second, delta);
+    }
+
+    /// Account for the record stored under `key`, holding `amount`, being removed.
+    pub fn removed(&self, tree: &SledTree, key: &[u8], amount: f64) {
+        let Some(millis) = keys::millis(key) else {
+            return;
+        };
+        let removed = Bucket::of(key, amount);
+        let delta = Bucket {
+            requests: -removed.requests,
+            amount: -removed.amount,
+        };
+
+        self.add(tree, self.granularity.bucket(millis), delta);
+    }
+
+    /// Account for a delta merged into the aggregate buckets.
+    pub fn aggregate(&self, tree: &SledTree, requests: i64, amount: f64) {
+        self.totals(tree)
+            .aggregated
+            .add(Bucket { requests, amount });
+    }
+
+    pub fn clear(&self, tree: &SledTree) {
+        self.trees
+            .write()
+            .unwrap()
+            .insert(tree.clone(), Arc::default());
+    }
+
+    /// Forget the totals of every provider.
+    pub fn purge_all(&self) {
+        self.trees.write().unwrap().clear();
+    }
+
+    /// Take the records in `tree` requested between `from` and `to` milliseconds, both
+    /// inclusive, off the totals, before they're removed. `partial` sums the stored records
+    /// within a key range, for the buckets the range only partly covers.
+    pub fn purge_range(
+        &self,
+        tree: &SledTree,
+        (from, to): (u64, u64),
+        partial: impl Fn(Range<Vec<u8>>) -> Bucket,
+    ) {
+        let (first, last) = (self.granularity.floor(from), self.granularity.floor(to));
+        let width = self.granularity.millis();
+        let edges = if first >= last {
+            vec![(first, keys::range(from, to))]
+        } else {
+            vec![
+                (first, keys::range(from, first + width - 1)),
+                (last, keys::range(last, to)),
+            ]
+        };
+        let edges = edges
+            .into_iter()
+            .map(|(start, keys)| (start / 1000, partial(keys)))
+            .collect::<Vec<_>>();
+
+        let totals = self.totals(tree);
+        let mut removed = Bucket::default();
+        if first < last {
+            let whole = (Bound::Excluded(first / 1000), Bound::Excluded(last / 1000));
+            for shard in &totals.shards {
+                let mut shard = shard.write().unwrap();
+                let seconds = shard.range(whole).map(|(second, _)| *second);
+                for second in seconds.collect::<Vec<_>>() {
+                    if let Some(bucket) = shard.remove(&second) {
+                        removed.add(bucket.load());
+                    }
+                }
+            }
+        }
+        for (second, bucket) in edges {
+            totals.bucket(second, |stored| stored.sub(bucket));
+            removed.add(bucket);
+        }
+        totals.records.sub(removed);
+    }
+
+    /// Move the records compacted into the aggregate buckets, every one requested before
+    /// `before` milliseconds, from the per-record totals to the aggregated ones. `before` must
+    /// start a bucket.
+    pub fn compacted(&self, tree: &SledTree, before: u64, removed: &[(IVec, f64)]) {
+        let mut folded = Bucket::default();
+        for (key, amount) in removed {
+            folded.add(Bucket::of(key, *amount));
+        }
+
+        let totals = self.totals(tree);
+        for shard in &totals.shards {
+            let mut shard = shard.write().unwrap();
+            *shard = shard.split_off(&(before / 1000));
+        }
+        totals.records.add(Bucket {
+            requests: -folded.requests,
+            amount: -folded.amount,
+        });
+        totals.aggregated.add(folded);
+    }
+
+    /// Totals of everything stored in `tree`, aggregates included, without reading sled.
+    pub fn total(&self, tree: &SledTree) -> Summary {
+        let totals = self.totals(tree);
+        let mut total = totals.records.load();
+        total.add(totals.aggregated.load());
+        total.into()
+    }
+
+    /// Totals of the records in `tree` requested between `from` and `to` milliseconds, both
+    /// inclusive. `partial` sums the stored records within a key range, for the buckets the
+    /// range only partly covers.
+    pub fn summary(
+        &self,
+        tree: &SledTree,
+        (from, to): (u64, u64),
+        partial: impl Fn(Range<Vec<u8>>) -> Bucket,
+    ) -> Summary {
+        let (first, last) = (self.granularity.floor(from), self.granularity.floor(to));
+        if first >= last {
+            return partial(keys::range(from, to)).into();
+        }
+
+        // The buckets `from` and `to` fall in are only partly covered.
+        let width = self.granularity.millis();
+        let mut total = partial(keys::range(from, first + width - 1));
+        total.add(partial(keys::range(last, to)));
+
+        let whole = (Bound::Excluded(first / 1000), Bound::Excluded(last / 1000));
+        for shard in &self.totals(tree).shards {
+            for (_, bucket) in shard.read().unwrap().range(whole) {
+                total.add(bucket.load());
+            }
+        }
+        total.into()
+    }
+
+    /// Totals of the records in `tree` requested between `from` and `to` milliseconds, plus
+    /// the aggregate buckets given, grouped by `interval` seconds. Groups are keyed by the
+    /// second they start at, and those without any payment are left out.
+    pub fn series(
+        &self,
+        tree: &SledTree,
+        records: &Tree,
+        aggregates: impl Iterator<Item = sled::Result<(IVec, IVec)>>,
+        (from, to): (u64, u64),
+        interval: u64,
+    ) -> BTreeMap<u64, Summary> {
+        let mut series = BTreeMap::<u64, Bucket>::new();
+        let mut add = |second: u64, bucket: Bucket| {
+            series
+                .entry(second - second % interval)
+                .or_default()
+                .add(bucket);
+        };
+
+        // Like `summary`, sled is only read for the buckets the range partly covers, unless
+        // the buckets are wider than the groups and it's read in full.
+        let width = self.granularity.millis();
+        let (first, last) = if (interval * 1000) % width == 0 {
+            (self.granularity.floor(from), self.granularity.floor(to))
+        } else {
+            (from, from)
+        };
+        let partial = if first >= last {
+            vec![keys::range(from, to)]
+        } else {
+            vec![keys::range(from, first + width - 1), keys::range(last, to)]
+        };
+        for (key, value) in partial
+            .into_iter()
+            .flat_map(|range| records.range(range))
+            .filter_map(Result::ok)
+        {
+            if let (Some(millis), Some(amount)) = (keys::millis(&key), decode_amount(&key, &value))
+            {
+                add(millis / 1000, Bucket::of(&key, amount));
+            }
+        }
+        if first < last {
+            let whole = (Bound::Excluded(first / 1000), Bound::Excluded(last / 1000));
+            for (second, bucket) in self.totals(tree).buckets(whole) {
+                add(second, bucket);
+            }
+        }
+
+        for (key, value) in aggregates.filter_map(Result::ok) {
+            let Some(at) = std::str::from_utf8(&key)
+                .ok()
+                .and_then(keys::parse_timestamp)
+            else {
+                continue;
+            };
+            let (requests, amount) = decode_bucket(&value);
+            add(at.timestamp().max(0) as u64, Bucket { requests, amount });
+        }
+
+        series
+            .into_iter()
+            .map(|(second, bucket)| (second, bucket.into()))
+            .collect()
+    }
+}
+
+/// Amount of the value stored under `key`, in either format. An unreadable value is logged and
+/// the record left out of whatever is being added up, as if it wasn't there.
+pub fn decode_amount(key: &[u8], value: &[u8]) -> Option<f64> {
+    let amount = StoredValue::decode(value).map(|value| value.amount());
+    if amount.is_none() {
+        let (requested_at, correlation_id) = keys::decode(key).unwrap_or_default();
+        warn!(
+            "Skipping the unreadable value of the record requested at {requested_at} ({})",
+            correlation_id.as_deref().unwrap_or("no correlation id")
+        );
+    }
+    amount
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::SecondsFormat;
+    use proptest::prelude::*;
+    use shared_types::{payment_key, void_key};
+    use uuid::Uuid;
+
+    use super::*;
+
+    /// 2025-07-15T12:00:00Z, where the records start.
+    const START: u64 = 1_752_580_800_000;
+    /// Records fall within five minutes, so minute buckets hold several seconds.
+    const WINDOW: u64 = 5 * 60_000;
+
+    /// A record written to one of two providers, with its key and amount.
+    #[derive(Clone, Debug)]
+    struct Record {
+        tree: SledTree,
+        key: Vec<u8>,
+        amount: f64,
+    }
+
+    fn record() -> impl Strategy<Value = Record> {
+        (any::<bool>(), 0..WINDOW, 1..100_000u32, any::<bool>()).prop_map(
+            |(fallback, millis, cents, void)| {
+                let requested_at = DateTime::from_timestamp_millis((START + millis) as i64)
+                    .unwrap()
+                    .to_rfc3339_opts(SecondsFormat::Millis, true);
+                let key = payment_key(&requested_at, &Uuid::new_v4());
+                let key = if void { void_key(&key) } else { key };
+                Record {
+                    tree: if fallback {
+                        SledTree::FALLBACK
+                    } else {
+                        SledTree::DEFAULT
+                    },
+                    key: keys::from_write_key(&key).unwrap(),
+                    amount: cents as f64 / 100.0,
+                }
+            },
+        )
+    }
+
+    /// Granularity of the single store, then of the parts merged into another.
+    fn granularities() -> impl Strategy<Value = (Granularity, Granularity)> {
+        prop_oneof![
+            Just((Granularity::Second, Granularity::Second)),
+            Just((Granularity::Minute, Granularity::Minute)),
+            Just((Granularity::Minute, Granularity::Second)),
+        ]
+    }
+
+    fn filled<'a>(
+        granularity: Granularity,
+        records: impl Iterator<Item = &'a Record>,
+    ) -> SummaryStore {
+        let store = SummaryStore::new(granularity);
+        for record in records {
+            store.record(&record.tree, &record.key, record.amount, None);
+        }
+        store
+    }
+
+    /// Summary of `tree` between `from` and `to` milliseconds, with the partly covered buckets
+    /// summed from `records` as sled would.
+    fn summary(
+        store: &SummaryStore,
+        records: &[Record],
+        tree: &SledTree,
+        range: (u64, u64),
+    ) -> Summary {
+        store.summary(tree, range, |keys| {
+            records
+                .iter()
+                .filter(|record| record.tree == *tree && keys.contains(&record.key))
+                .fold(Bucket::default(), |mut bucket, record| {
+                    bucket.add(Bucket::of(&record.key, record.amount));
+                    bucket
+                })
+        })
+    }
+
+    fn assert_same(merged: Summary, single: Summary) {
+        assert_eq!(merged.total_requests, single.total_requests);
+        assert!((merged.total_amount - single.total_amount).abs() < 0.005);
+    }
+
+    proptest! {
+        /// Records split across stores and merged add up to what one store holding them all
+        /// does, bucket by bucket and for any range.
+        #[test]
+        fn merged_stores_match_a_single_store(
+            records in prop::collection::vec(record(), 0..200),
+            parts in prop::collection::vec(0..4usize, 200),
+            (granularity, parts_granularity) in granularities(),
+            ranges in prop::collection::vec((0..WINDOW, 0..WINDOW), 1..10),
+        ) {
+            let single = filled(granularity, records.iter());
+            let mut merged = SummaryStore::new(granularity);
+            for part in 0..4 {
+                let records = records.iter().zip(&parts).filter(|(_, p)| **p == part);
+                merged.merge(filled(parts_granularity, records.map(|(record, _)| record))).unwrap();
+            }
+
+            let (single, merged_snapshot) = (single, merged.snapshot());
+            for (tree, totals) in &merged_snapshot.trees {
+                let expected = single.totals(tree).snapshot();
+                prop_assert!(totals.records.matches(&expected.records));
+                prop_assert_eq!(totals.buckets.len(), expected.buckets.len());
+                for (second, bucket) in &totals.buckets {
+                    prop_assert!(bucket.matches(&expected.buckets[second]));
+                }
+            }
+            for tree in [SledTree::DEFAULT, SledTree::FALLBACK] {
+                assert_same(merged.total(&tree), single.total(&tree));
+                for (a, b) in &ranges {
+                    let range = (START + a.min(b), START + a.max(b));
+                    assert_same(
+                        summary(&merged, &records, &tree, range),
+                        summary(&single, &records, &tree, range),
+                    );
+                }
+            }
+        }
+    }
+
+    /// A bucket read while others add to it always holds as many requests as it holds amount,
+    /// when every add is one request of 1.
+    #[test]
+    fn buckets_are_read_whole_while_written() {
+        let bucket = AtomicBucket::default();
+        let one = Bucket {
+            requests: 1,
+            amount: 1.0,
+        };
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| (0..100_000).for_each(|_| bucket.add(one)));
+            }
+            scope.spawn(|| {
+                loop {
+                    let read = bucket.load();
+                    assert_eq!(read.requests as f64, read.amount);
+                    if read.requests == 400_000 {
+                        break;
+                    }
+                }
+            });
+        });
+        assert_eq!(bucket.load().requests, 400_000);
+    }
+
+    #[test]
+    fn unreadable_values_are_left_out_of_sums() {
+        let tree = sled::Config::new()
+            .temporary(true)
+            .open()
+            .unwrap()
+            .open_tree("Default")
+            .unwrap();
+        for (id, amount) in [(1, 2.5), (2, 4.0)] {
+            let key = payment_key("2025-07-15T12:00:00.000Z", &Uuid::from_u128(id));
+            let key = keys::from_write_key(&key).unwrap();
+            let value = StoredValue::new(amount, &key, &SledTree::DEFAULT);
+            tree.insert(&key, value.encode()).unwrap();
+        }
+        let key = payment_key("2025-07-15T12:00:01.000Z", &Uuid::from_u128(3));
+        let key = keys::from_write_key(&key).unwrap();
+        tree.insert(&key, &[0; 12]).unwrap();
+
+        assert_eq!(decode_amount(&key, &[0; 12]), None);
+        let sum = Bucket::sum(tree.iter());
+        assert_eq!((sum.requests, sum.amount), (2, 6.5));
+    }
+
+    #[test]
+    fn merging_a_coarser_store_is_refused() {
+        let mut store = SummaryStore::new(Granularity::Second);
+        let minutes = SummaryStore::new(Granularity::Minute);
+        assert!(store.merge(minutes).is_err());
+    }
+}
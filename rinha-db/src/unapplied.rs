@@ -0,0 +1,92 @@
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::Notify;
+
+/// Writes accepted and not applied yet, whether queued for the group commit or acknowledged
+/// with `Ack::None` and still on the blocking pool. Each one holds a [`Ticket`] in the order it
+/// was accepted, so a summary can wait for the writes accepted before it without waiting on
+/// the ones accepted after.
+#[derive(Default)]
+pub struct Unapplied {
+    /// The next ticket, and the tickets not released yet.
+    tickets: Mutex<(u64, BTreeSet<u64>)>,
+    released: Notify,
+}
+
+impl Unapplied {
+    /// A ticket for a write just accepted, released once it's dropped.
+    pub fn accept(self: &Arc<Self>) -> Ticket {
+        let mut tickets = self.tickets.lock().unwrap();
+        let ticket = tickets.0;
+        tickets.0 += 1;
+        tickets.1.insert(ticket);
+        Ticket(self.clone(), ticket)
+    }
+
+    /// Wait until every write accepted so far was applied.
+    pub async fn caught_up(&self) {
+        let until = self.tickets.lock().unwrap().0;
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            let oldest = self.tickets.lock().unwrap().1.first().copied();
+            if oldest.is_none_or(|oldest| oldest >= until) {
+                return;
+            }
+            released.await;
+        }
+    }
+}
+
+/// A write accepted and not applied yet.
+pub struct Ticket(Arc<Unapplied>, u64);
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.0.tickets.lock().unwrap().1.remove(&self.1);
+        self.0.released.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    const WAIT: Duration = Duration::from_millis(50);
+
+    #[tokio::test]
+    async fn waits_for_the_writes_accepted_before() {
+        let unapplied = Arc::new(Unapplied::default());
+        let (first, second) = (unapplied.accept(), unapplied.accept());
+
+        let caught_up = tokio::spawn({
+            let unapplied = unapplied.clone();
+            async move { unapplied.caught_up().await }
+        });
+        tokio::time::sleep(WAIT).await;
+        let later = unapplied.accept();
+
+        drop(second);
+        tokio::time::sleep(WAIT).await;
+        assert!(!caught_up.is_finished());
+
+        drop(first);
+        timeout(WAIT * 10, caught_up).await.unwrap().unwrap();
+        drop(later);
+    }
+
+    #[tokio::test]
+    async fn nothing_pending_returns_at_once() {
+        let unapplied = Arc::new(Unapplied::default());
+        drop(unapplied.accept());
+        timeout(WAIT, unapplied.caught_up()).await.unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+use shared_types::SledTree;
+use uuid::Uuid;
+
+use crate::keys;
+
+/// Layout of the values written now. A version only appends fields to the one before it, so
+/// readers take the fields they know and skip the rest.
+const VERSION: u8 = 1;
+/// Values written before they were versioned: the bare amount as a big-endian f64.
+const LEGACY_LEN: usize = 8;
+
+/// What a payment tree holds for a record. Versioned values start with their version and are
+/// always longer than the 8 bytes of a legacy one, so both read back from the same tree.
+#[derive(Debug, PartialEq)]
+pub struct StoredValue {
+    /// The amount in cents, so adding them up doesn't drift.
+    pub cents: i64,
+    /// Unset for placeholder ids and legacy values.
+    pub correlation_id: Option<Uuid>,
+    /// The provider it was written for, empty for legacy values.
+    pub provider: String,
+}
+
+impl StoredValue {
+    pub fn new(amount: f64, key: &[u8], tree: &SledTree) -> Self {
+        Self {
+            cents: (amount * 100.0).round() as i64,
+            correlation_id: keys::correlation_id(key),
+            provider: tree.name().to_string(),
+        }
+    }
+
+    pub fn amount(&self) -> f64 {
+        self.cents as f64 / 100.0
+    }
+
+    /// Version, cents as a big-endian i64, the correlation id (zeroes when unset), then the
+    /// provider name prefixed by its length in one byte.
+    pub fn encode(&self) -> Vec<u8> {
+        let provider = &self.provider.as_bytes()[..self.provider.len().min(u8::MAX as usize)];
+        let mut value = Vec::with_capacity(1 + 8 + 16 + 1 + provider.len());
+        value.push(VERSION);
+        value.extend_from_slice(&self.cents.to_be_bytes());
+        value.extend_from_slice(self.correlation_id.unwrap_or_default().as_bytes());
+        value.push(provider.len() as u8);
+        value.extend_from_slice(provider);
+        value
+    }
+
+    /// `None` when the value is neither a legacy one nor starts with the fields of version 1.
+    pub fn decode(value: &[u8]) -> Option<Self> {
+        if value.len() == LEGACY_LEN {
+            return Some(Self {
+                cents: (f64::from_be_bytes(value.try_into().ok()?) * 100.0).round() as i64,
+                correlation_id: None,
+                provider: String::new(),
+            });
+        }
+
+        let (&version, value) = value.split_first()?;
+        if version == 0 {
+            return None;
+        }
+        let cents = i64::from_be_bytes(value.get(..8)?.try_into().ok()?);
+        let correlation_id = Uuid::from_slice(value.get(8..24)?).ok()?;
+        let len = *value.get(24)? as usize;
+        let provider = std::str::from_utf8(value.get(25..25 + len)?).ok()?;
+        Some(Self {
+            cents,
+            correlation_id: (!correlation_id.is_nil()).then_some(correlation_id),
+            provider: provider.to_string(),
+        })
+    }
+}
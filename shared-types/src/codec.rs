@@ -0,0 +1,55 @@
+//! Length-prefixed bincode frames used on the binary unix-socket protocols.
+//!
+//! Each frame is a big-endian `u32` payload length followed by the bincode payload.
+
+use anyhow::Result;
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Upper bound on a single frame, to reject garbage lengths before allocating.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Encode a value into a complete frame, length prefix included.
+pub fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    let mut buf = vec![0; 4];
+    bincode::serde::encode_into_std_write(value, &mut buf, bincode::config::standard())?;
+    let len = (buf.len() - 4) as u32;
+    buf[..4].copy_from_slice(&len.to_be_bytes());
+    Ok(buf)
+}
+
+/// Decode a frame payload (without the length prefix).
+pub fn decode<T: DeserializeOwned>(payload: &[u8]) -> Result<T> {
+    let (value, _) = bincode::serde::decode_from_slice(payload, bincode::config::standard())?;
+    Ok(value)
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin, T: Serialize>(
+    writer: &mut W,
+    value: &T,
+) -> Result<()> {
+    writer.write_all(&encode(value)?).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read the next frame, returning `None` when the peer closed the connection between frames.
+pub async fn read_frame<R: AsyncRead + Unpin, T: DeserializeOwned>(
+    reader: &mut R,
+) -> Result<Option<T>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        anyhow::bail!("frame of {len} bytes exceeds the {MAX_FRAME_LEN} byte limit");
+    }
+
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+    decode(&payload).map(Some)
+}
@@ -1,41 +1,48 @@
+pub mod codec;
+pub mod logging;
+
 use anyhow::Result;
 use crossbeam::queue::SegQueue;
 use std::{
-    ops::Deref,
+    borrow::Cow,
     path::{Path, PathBuf},
     sync::{
         Arc,
         atomic::{AtomicUsize, Ordering},
     },
 };
+use tracing::warn;
 
 use serde::{Deserialize, Serialize};
-use sled;
-use tokio::{io::BufReader, net::UnixStream};
+use tokio::net::UnixStream;
 use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub enum SledTree {
-    Fallback,
-    Default,
+/// Name of the provider a record is stored under. The workers route to `Default` and
+/// `Fallback`, and rinha-db opens trees for any other name the first time it's written to.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(transparent)]
+pub struct SledTree(Cow<'static, str>);
+
+impl SledTree {
+    pub const DEFAULT: SledTree = SledTree(Cow::Borrowed("Default"));
+    pub const FALLBACK: SledTree = SledTree(Cow::Borrowed("Fallback"));
+
+    pub fn new(name: impl Into<String>) -> Self {
+        SledTree(Cow::Owned(name.into()))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct GlobalSummary {
     pub default: Summary,
     pub fallback: Summary,
 }
 
-impl Default for GlobalSummary {
-    fn default() -> Self {
-        Self {
-            default: Summary::new(),
-            fallback: Summary::new(),
-        }
-    }
-}
-
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Summary {
     #[serde(rename = "totalRequests")]
     pub total_requests: u64,
@@ -52,18 +59,23 @@ impl Summary {
     }
 }
 
-impl FromIterator<sled::Result<(sled::IVec, sled::IVec)>> for Summary {
-    fn from_iter<I: IntoIterator<Item = sled::Result<(sled::IVec, sled::IVec)>>>(iter: I) -> Self {
-        iter.into_iter()
-            .filter_map(Result::ok)
-            .map(|(_, value)| {
-                f64::from_be_bytes(value.as_ref().try_into().expect("Expected 8 bytes"))
-            })
-            .fold(Summary::new(), |mut summary, amount| {
-                summary.total_amount += amount;
-                summary.total_requests += 1;
-                summary
-            })
+impl Summary {
+    /// Add aggregate buckets, stored as encoded by [`AggregateDelta::merge`].
+    pub fn add_buckets<I: IntoIterator<Item = sled::Result<(sled::IVec, sled::IVec)>>>(
+        &mut self,
+        buckets: I,
+    ) {
+        for (_, value) in buckets.into_iter().filter_map(Result::ok) {
+            let (requests, amount) = decode_bucket(&value);
+            self.total_requests = self.total_requests.saturating_add_signed(requests);
+            self.total_amount += amount;
+        }
+    }
+}
+
+impl Default for Summary {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -72,13 +84,354 @@ pub struct PaymentDTO {
     #[serde(rename = "correlationId")]
     pub correlation_id: Uuid,
     pub amount: f64,
+    /// Set by the gateway when it accepts the payment, so a backlog in the workers doesn't
+    /// shift the payment out of the summary range it was requested in.
+    #[serde(
+        rename = "requestedAt",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub requested_at: Option<String>,
+    /// Set by the gateway from the `X-Payment-Priority` header to queue the payment ahead of
+    /// the backlog.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub priority: bool,
+    /// Set by the gateway from the `X-Trace-Id` header, to follow the payment through the
+    /// worker's and rinha-db's logs.
+    #[serde(rename = "traceId", default, skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+    /// W3C trace context of the gateway's span, set when spans are exported so the worker's
+    /// spans join the same trace.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traceparent: Option<String>,
+}
+
+/// Newline-delimited frames sent from the gateway to an api worker.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum ApiFrame {
+    Payment(PaymentDTO),
+    /// Drop every payment still queued in the worker.
+    Purge,
+    /// Ask the worker how many payments are waiting in its channel.
+    Depth,
+    /// Ask the worker for its provider counters.
+    Metrics,
+    /// Ask the worker for its current queue, circuit and DB state.
+    Status,
+    /// Write the records batched for rinha-db right away, answered once they're written.
+    Flush,
+    /// Void a payment: drop it if still queued, or offset it in the summaries if it was
+    /// already processed.
+    Cancel {
+        #[serde(rename = "correlationId")]
+        correlation_id: Uuid,
+    },
+}
+
+/// Newline-delimited replies written back by an api worker.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum ApiReply {
+    Purged {
+        dropped: u64,
+    },
+    Depth {
+        queued: u64,
+    },
+    /// Records still not in rinha-db after a flush, zero unless it's unreachable.
+    Flushed {
+        unwritten: u64,
+    },
+    Metrics(WorkerMetrics),
+    Status(WorkerStatus),
+    Cancelled {
+        #[serde(rename = "correlationId")]
+        correlation_id: Uuid,
+        outcome: CancelOutcome,
+    },
+    /// The payment was queued for processing, or had already been received.
+    Ack {
+        #[serde(rename = "correlationId")]
+        correlation_id: Uuid,
+    },
+    /// The payment was not accepted and should be retried elsewhere.
+    Nack {
+        #[serde(rename = "correlationId")]
+        correlation_id: Uuid,
+        reason: NackReason,
+    },
+}
+
+/// Current state of an api worker, for routing decisions and orchestration health checks.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct WorkerStatus {
+    pub queued: u64,
+    pub default: ProviderStatus,
+    pub fallback: ProviderStatus,
+    /// Accounting records not yet written to rinha-db.
+    #[serde(rename = "dbBacklog")]
+    pub db_backlog: u64,
+    /// How long the DB flusher has gone without writing while records wait, zero when it's
+    /// caught up.
+    #[serde(rename = "dbLagMs")]
+    pub db_lag_ms: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitStatus {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ProviderStatus {
+    pub circuit: CircuitStatus,
+    /// As last reported by the processor's health endpoint.
+    pub failing: bool,
+    #[serde(rename = "minResponseTime")]
+    pub min_response_time: u64,
+    #[serde(rename = "lastLatencyMs")]
+    pub last_latency_ms: Option<u64>,
+    #[serde(rename = "p99LatencyMs")]
+    pub p99_latency_ms: Option<u64>,
+}
+
+/// Counters of an api worker since it started.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct WorkerMetrics {
+    pub default: ProviderMetrics,
+    pub fallback: ProviderMetrics,
+    /// Payments nacked or given up on.
+    pub dropped: u64,
+    /// Hedged payments processed by both providers, stored under each.
+    #[serde(rename = "hedgedTwice", default)]
+    pub hedged_twice: u64,
+    /// Latest reconciliation against the processors, when enabled.
+    pub drift: Option<SummaryDrift>,
+}
+
+/// Processor totals minus local totals over the last reconciled window.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default)]
+pub struct SummaryDrift {
+    pub default: ProviderDrift,
+    pub fallback: ProviderDrift,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default)]
+pub struct ProviderDrift {
+    pub requests: i64,
+    pub amount: f64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ProviderMetrics {
+    pub success: u64,
+    #[serde(rename = "retryableFailure")]
+    pub retryable_failure: u64,
+    #[serde(rename = "permanentFailure")]
+    pub permanent_failure: u64,
+    /// Cumulative latency histogram as `(upper bound in ms, calls)`; the last bucket is
+    /// unbounded and reported with `u64::MAX`.
+    #[serde(rename = "latencyBuckets")]
+    pub latency_buckets: Vec<(u64, u64)>,
+    #[serde(rename = "latencySumMs")]
+    pub latency_sum_ms: u64,
+    /// Hedged payments this provider processed first.
+    #[serde(rename = "hedgeWins", default)]
+    pub hedge_wins: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelOutcome {
+    /// The payment hadn't been processed yet and won't be charged. If a provider call was
+    /// already in flight and succeeds, it is voided instead.
+    Dropped,
+    /// The payment was already processed and a compensating record was written.
+    Voided,
+    /// The worker doesn't know the payment, or processed it too long ago to void it.
+    Unknown,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NackReason {
+    /// The worker channel is at capacity.
+    QueueFull,
+    /// The workers have stopped.
+    Unavailable,
+}
+
+/// When rinha-db acknowledges a write, trading latency for durability.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Ack {
+    /// Once it's queued, before it's applied.
+    None,
+    /// Once it's applied, flushed or not.
+    #[default]
+    Received,
+    /// Once it's on disk.
+    Flushed,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct DBWrite {
+    /// Composite key built by [`payment_key`].
     pub key: String,
     pub value: f64,
     pub tree: SledTree,
+    #[serde(default)]
+    pub ack: Ack,
+    /// Trace id of the payment the record was written for, only logged.
+    #[serde(default)]
+    pub trace_id: Option<String>,
+    /// W3C trace context of the worker's span, so the write is exported in the payment's
+    /// trace.
+    #[serde(default)]
+    pub traceparent: Option<String>,
+}
+
+const KEY_SEPARATOR: char = '#';
+const VOID_SUFFIX: &str = "#void";
+
+/// Key a stored payment by `requested_at` first, so range scans stay ordered by time, and
+/// by correlation id second, so payments requested in the same instant don't overwrite
+/// each other.
+pub fn payment_key(requested_at: &str, correlation_id: &Uuid) -> String {
+    format!("{requested_at}{KEY_SEPARATOR}{correlation_id}")
+}
+
+/// Key of the compensating record that offsets the payment stored under `key`. It sorts right
+/// after the payment, so any range holding one holds the other.
+pub fn void_key(key: &str) -> String {
+    format!("{key}{VOID_SUFFIX}")
+}
+
+pub fn is_void_key(key: &[u8]) -> bool {
+    key.ends_with(VOID_SUFFIX.as_bytes())
+}
+
+/// Split a key built by [`payment_key`] or [`void_key`] back into its timestamp and
+/// correlation id.
+pub fn split_payment_key(key: &[u8]) -> (String, Option<String>) {
+    let key = String::from_utf8_lossy(key);
+    let key = key.strip_suffix(VOID_SUFFIX).unwrap_or(&key);
+    match key.split_once(KEY_SEPARATOR) {
+        Some((requested_at, correlation_id)) => {
+            (requested_at.to_string(), Some(correlation_id.to_string()))
+        }
+        None => (key.to_string(), None),
+    }
+}
+
+/// Length of the `YYYY-MM-DDTHH:MM:SS` prefix aggregates are bucketed by.
+const BUCKET_LEN: usize = 19;
+
+/// The second an RFC 3339 timestamp falls in, which keys its aggregate bucket.
+pub fn aggregate_bucket(requested_at: &str) -> &str {
+    requested_at.get(..BUCKET_LEN).unwrap_or(requested_at)
+}
+
+/// Byte bounds covering every aggregate bucket touched by a `from`..`to` range. Buckets are
+/// whole seconds, so payments in the partial seconds at either end are counted too.
+pub fn bucket_range(from: &str, to: &str) -> std::ops::RangeInclusive<Vec<u8>> {
+    aggregate_bucket(from).as_bytes().to_vec()..=aggregate_bucket(to).as_bytes().to_vec()
+}
+
+/// Payments a worker accounted for in one bucket since its last push.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AggregateDelta {
+    pub tree: SledTree,
+    /// Built by [`aggregate_bucket`].
+    pub bucket: String,
+    /// Negative when voided payments outnumber new ones.
+    pub requests: i64,
+    pub amount: f64,
+}
+
+impl AggregateDelta {
+    /// Add the delta to a stored bucket, encoded as a big-endian count followed by the
+    /// amount.
+    pub fn merge(&self, stored: Option<&[u8]>) -> Vec<u8> {
+        let (requests, amount) = stored.map(decode_bucket).unwrap_or((0, 0.0));
+        let mut value = Vec::with_capacity(16);
+        value.extend_from_slice(&(requests + self.requests).to_be_bytes());
+        value.extend_from_slice(&(amount + self.amount).to_be_bytes());
+        value
+    }
+}
+
+/// Tags an aggregate push so rinha-db merges it once, however many times it's sent.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PushToken {
+    /// Picked at random by the worker when it starts.
+    pub worker: Uuid,
+    /// The worker's pushes counted from 1. A worker only moves on to its next push once the
+    /// last one was merged, so a sequence rinha-db is already past was merged before.
+    pub sequence: u64,
+}
+
+/// Deltas a worker in aggregate mode pushes at once.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AggregatePush {
+    /// Unset for deltas pushed by hand, which are merged every time they're sent.
+    #[serde(default)]
+    pub token: Option<PushToken>,
+    pub deltas: Vec<AggregateDelta>,
+}
+
+/// Requests and amount held by an aggregate bucket.
+pub fn decode_bucket(value: &[u8]) -> (i64, f64) {
+    let (requests, amount) = value.split_at(8);
+    (
+        i64::from_be_bytes(requests.try_into().expect("Expected 16 bytes")),
+        f64::from_be_bytes(amount.try_into().expect("Expected 16 bytes")),
+    )
+}
+
+/// A single stored payment, as streamed by rinha-db's `/export`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PaymentRecord {
+    pub tree: SledTree,
+    #[serde(rename = "requestedAt")]
+    pub requested_at: String,
+    /// Missing for payments stored before keys carried the correlation id.
+    #[serde(rename = "correlationId", skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    pub amount: f64,
+    /// A compensating record for a cancelled payment.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub voided: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct DBWriteBatch {
+    pub writes: Vec<DBWrite>,
+}
+
+/// Requests sent to rinha-db over its unix socket, encoded with [`codec`].
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub enum DbRequest {
+    Write(DBWrite),
+    WriteBatch(DBWriteBatch),
+    /// Totals per tree for a `requestedAt` range, answered with [`DbResponse::Summary`].
+    Summary(DBRead),
+    /// Every record in a `requestedAt` range, answered with [`DbResponse::Records`].
+    Records(DBRead),
+    /// Per-second totals to add to the aggregate buckets.
+    Aggregate(AggregatePush),
+}
+
+/// Responses from rinha-db's unix socket, one per [`DbRequest`].
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub enum DbResponse {
+    Ok,
+    Error(String),
+    /// Too many writes in flight, retry after this many milliseconds.
+    Busy(u64),
+    Summary(GlobalSummary),
+    Records(Vec<PaymentRecord>),
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -144,10 +497,7 @@ impl UnixConnectionPool {
         }
 
         if !errors.is_empty() {
-            eprintln!(
-                "Warning: Some connections failed to initialize: {:?}",
-                errors
-            );
+            warn!("Some connections failed to initialize: {:?}", errors);
         }
 
         Ok(())
@@ -158,6 +508,11 @@ impl UnixConnectionPool {
         UnixStream::connect(&self.path).await.map_err(Into::into)
     }
 
+    /// Open a dedicated connection that is never shared with or returned to the pool
+    pub async fn connect(&self) -> Result<UnixStream> {
+        self.create_connection().await
+    }
+
     /// Get a connection from the pool (non-blocking, lockfree)
     pub fn try_get_connection(&self) -> Option<UnixStream> {
         match self.connections.pop() {
@@ -203,6 +558,11 @@ impl UnixConnectionPool {
         self.pool_size
     }
 
+    /// Get the approximate number of idle connections in the pool
+    pub fn idle(&self) -> usize {
+        self.current_size.load(Ordering::Relaxed)
+    }
+
     /// Check if pool is approximately empty
     pub fn is_empty(&self) -> bool {
         self.current_size.load(Ordering::Relaxed) == 0
@@ -0,0 +1,120 @@
+//! Log output shared by every binary in the workspace, and with the `otel` feature, spans
+//! exported over OTLP.
+
+use std::{env, io::IsTerminal};
+
+use tracing::Span;
+use tracing_subscriber::{EnvFilter, Layer, layer::SubscriberExt, util::SubscriberInitExt};
+
+/// Log to stdout, filtered per crate by `RUST_LOG` (default `info`), as text or, with
+/// `LOG_FORMAT=json`, as one JSON object per line. Built with `otel` and given
+/// `OTEL_EXPORTER_OTLP_ENDPOINT`, spans are also exported as `service`.
+pub fn init(service: &'static str) -> anyhow::Result<()> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let logs = tracing_subscriber::fmt::layer().with_ansi(std::io::stdout().is_terminal());
+    let logs = match env::var("LOG_FORMAT")
+        .unwrap_or("text".to_string())
+        .as_str()
+    {
+        "text" => logs.boxed(),
+        "json" => logs.json().boxed(),
+        other => anyhow::bail!("invalid LOG_FORMAT {other:?}, expected text or json"),
+    };
+
+    let registry = tracing_subscriber::registry().with(logs.with_filter(filter));
+    #[cfg(feature = "otel")]
+    let registry = registry.with(otel::layer(service)?);
+    #[cfg(not(feature = "otel"))]
+    let _ = service;
+    registry.try_init()?;
+    Ok(())
+}
+
+/// The W3C `traceparent` of `span`, for the next service to continue its trace. `None`
+/// unless spans are exported.
+pub fn traceparent(span: &Span) -> Option<String> {
+    #[cfg(feature = "otel")]
+    return otel::traceparent(span);
+    #[cfg(not(feature = "otel"))]
+    {
+        let _ = span;
+        None
+    }
+}
+
+/// Make `span` a child of the span `traceparent` was taken from, so it's exported in the same
+/// trace. Does nothing unless spans are exported.
+pub fn follow(span: &Span, traceparent: Option<&str>) {
+    #[cfg(feature = "otel")]
+    if let Some(traceparent) = traceparent {
+        otel::follow(span, traceparent);
+    }
+    #[cfg(not(feature = "otel"))]
+    let _ = (span, traceparent);
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use std::{collections::HashMap, env};
+
+    use opentelemetry::{propagation::TextMapPropagator, trace::TracerProvider};
+    use opentelemetry_sdk::{
+        Resource,
+        propagation::TraceContextPropagator,
+        trace::{Sampler, SdkTracerProvider},
+    };
+    use tracing::{Span, Subscriber};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::{EnvFilter, Layer, registry::LookupSpan};
+
+    /// Exports spans to `OTEL_EXPORTER_OTLP_ENDPOINT` over HTTP, or `None` when it's unset.
+    /// Spans are filtered by `TRACE_FILTER`, apart from `RUST_LOG`, so they can be exported
+    /// at `debug` without logging at it. `TRACE_SAMPLE_RATIO` (default 1) is the share of
+    /// payments traced, decided once at the gateway and followed by the services after it.
+    pub fn layer<S>(service: &'static str) -> anyhow::Result<Option<impl Layer<S>>>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        if env::var("OTEL_EXPORTER_OTLP_ENDPOINT").is_err() {
+            return Ok(None);
+        }
+        let ratio: f64 = env::var("TRACE_SAMPLE_RATIO")
+            .unwrap_or("1".to_string())
+            .parse()?;
+        if !(0.0..=1.0).contains(&ratio) {
+            anyhow::bail!("invalid TRACE_SAMPLE_RATIO {ratio}, expected between 0 and 1");
+        }
+        let filter = EnvFilter::new(env::var("TRACE_FILTER").unwrap_or(
+            "info,gateway=debug,api=debug,payment_core=debug,rinha_db=debug".to_string(),
+        ));
+
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .build()?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                ratio,
+            ))))
+            .with_resource(Resource::builder().with_service_name(service).build())
+            .build();
+        let tracer = provider.tracer(service);
+        opentelemetry::global::set_tracer_provider(provider);
+        Ok(Some(
+            tracing_opentelemetry::layer()
+                .with_tracer(tracer)
+                .with_filter(filter),
+        ))
+    }
+
+    pub fn traceparent(span: &Span) -> Option<String> {
+        let mut carrier = HashMap::new();
+        TraceContextPropagator::new().inject_context(&span.context(), &mut carrier);
+        carrier.remove("traceparent")
+    }
+
+    pub fn follow(span: &Span, traceparent: &str) {
+        let carrier = HashMap::from([("traceparent".to_string(), traceparent.to_string())]);
+        let _ = span.set_parent(TraceContextPropagator::new().extract(&carrier));
+    }
+}
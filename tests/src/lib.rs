@@ -0,0 +1,254 @@
+//! End-to-end harness: rinha-db, two api workers and the gateway run as child processes, wired
+//! the way docker compose wires them, against two `mock-processor`s.
+
+use std::{
+    env, fs,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use serde_json::Value;
+use shared_types::{GlobalSummary, Summary};
+
+/// How long a service gets to come up.
+const STARTUP: Duration = Duration::from_secs(30);
+
+/// A running stack, stopped when dropped. Its sockets, database and logs live in a directory
+/// of its own, kept when the test panicked so the logs can be read.
+pub struct Stack {
+    /// Base URL of the gateway.
+    pub url: String,
+    /// Base URL of rinha-db's HTTP server.
+    pub db: String,
+    pub default: Processor,
+    pub fallback: Processor,
+    dir: PathBuf,
+    children: Vec<Child>,
+}
+
+impl Stack {
+    /// Start with each processor configured by its env vars, e.g. `FAIL_RATE`.
+    pub async fn start(
+        default: &[(&str, &str)],
+        fallback: &[(&str, &str)],
+    ) -> anyhow::Result<Self> {
+        let bin = binaries()?;
+        let dir =
+            env::temp_dir().join(format!("rinha-e2e-{}-{}", std::process::id(), free_port()?));
+        fs::create_dir_all(dir.join("db"))?;
+        let db_socket = dir.join("db.sock");
+        let api_sockets = [dir.join("api-1.sock"), dir.join("api-2.sock")];
+        let db_addr = format!("127.0.0.1:{}", free_port()?);
+        let gateway_addr = format!("127.0.0.1:{}", free_port()?);
+        let mut stack = Self {
+            url: format!("http://{gateway_addr}"),
+            db: format!("http://{db_addr}"),
+            default: Processor::new(free_port()?),
+            fallback: Processor::new(free_port()?),
+            dir,
+            children: Vec::new(),
+        };
+
+        let processors = [
+            ("default", default, stack.default.addr.clone()),
+            ("fallback", fallback, stack.fallback.addr.clone()),
+        ];
+        for (name, vars, addr) in processors {
+            let mut vars = vars
+                .iter()
+                .map(|(key, value)| (*key, value.to_string()))
+                .collect::<Vec<_>>();
+            vars.push(("LISTEN", addr));
+            stack.spawn(&bin.join("mock-processor"), name, &vars)?;
+        }
+        for processor in [&stack.default, &stack.fallback] {
+            let started = Instant::now();
+            while processor.summary().await.is_err() {
+                if started.elapsed() > STARTUP {
+                    anyhow::bail!("{} didn't come up", processor.addr);
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }
+
+        stack.spawn(
+            &bin.join("rinha_db"),
+            "rinha-db",
+            &[
+                ("DB_SOCKET_PATH", path(&db_socket)),
+                ("DB_HTTP_ADDR", db_addr.clone()),
+            ],
+        )?;
+        wait_for(&db_socket).await?;
+        for (i, socket) in api_sockets.iter().enumerate() {
+            stack.spawn(
+                &bin.join("api"),
+                &format!("api-{}", i + 1),
+                &[
+                    ("API_PATH", path(socket)),
+                    ("DB_SOCKET_PATH", path(&db_socket)),
+                    ("PAYMENT_PROCESSOR_URL_DEFAULT", stack.default.url()),
+                    ("PAYMENT_PROCESSOR_URL_FALLBACK", stack.fallback.url()),
+                ],
+            )?;
+            wait_for(socket).await?;
+        }
+        stack.spawn(
+            &bin.join("gateway"),
+            "gateway",
+            &[
+                ("LISTEN", gateway_addr),
+                ("DB_URLS", format!("http://{db_addr}")),
+                (
+                    "API_PATHS",
+                    format!("{},{}", path(&api_sockets[0]), path(&api_sockets[1])),
+                ),
+            ],
+        )?;
+
+        let started = Instant::now();
+        while stack.summary().await.is_err() {
+            if started.elapsed() > STARTUP {
+                anyhow::bail!("gateway didn't come up, see {}", stack.dir.display());
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        Ok(stack)
+    }
+
+    /// Start a service in the stack's directory, logging to `<name>.log` there.
+    fn spawn(&mut self, bin: &Path, name: &str, vars: &[(&str, String)]) -> anyhow::Result<()> {
+        let log = fs::File::create(self.dir.join(format!("{name}.log")))?;
+        let child = Command::new(bin)
+            .current_dir(self.dir.join("db"))
+            .envs(vars.iter().map(|(key, value)| (key, value)))
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .stdin(Stdio::null())
+            .spawn()?;
+        self.children.push(child);
+        Ok(())
+    }
+
+    /// `/payments-summary` over every payment.
+    pub async fn summary(&self) -> anyhow::Result<GlobalSummary> {
+        Ok(reqwest::get(format!("{}/payments-summary", self.url))
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+}
+
+/// A `mock-processor` of the stack.
+pub struct Processor {
+    addr: String,
+    client: reqwest::Client,
+}
+
+impl Processor {
+    fn new(port: u16) -> Self {
+        Self {
+            addr: format!("127.0.0.1:{port}"),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Every payment the processor accepted.
+    pub async fn summary(&self) -> anyhow::Result<Summary> {
+        Ok(self
+            .client
+            .get(format!("{}/admin/payments-summary", self.url()))
+            .header("X-Rinha-Token", "123")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Play a script of failure and latency phases from now on, see `mock-processor`.
+    pub async fn play(&self, script: &Value) -> anyhow::Result<()> {
+        self.client
+            .put(format!("{}/admin/script", self.url()))
+            .header("X-Rinha-Token", "123")
+            .json(script)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        for child in &mut self.children {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        if std::thread::panicking() {
+            eprintln!("Logs kept in {}", self.dir.display());
+        } else {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+/// Build the gateway, api, rinha-db and mock-processor with the cargo running the tests, in the profile the
+/// tests were built with, returning the directory they're in. Built once per test binary.
+fn binaries() -> anyhow::Result<PathBuf> {
+    static BUILT: OnceLock<Result<PathBuf, String>> = OnceLock::new();
+    BUILT
+        .get_or_init(|| {
+            let build = || -> anyhow::Result<PathBuf> {
+                // Test binaries sit in `<target>/<profile>/deps`.
+                let exe = env::current_exe()?;
+                let Some(dir) = exe.parent().and_then(Path::parent) else {
+                    anyhow::bail!("no target directory above {}", exe.display());
+                };
+                let profile = match dir.file_name().and_then(|name| name.to_str()) {
+                    Some("debug") => "dev",
+                    Some(profile) => profile,
+                    None => anyhow::bail!("no profile in {}", dir.display()),
+                };
+                let status = Command::new(env!("CARGO"))
+                    .args(["build", "--quiet", "--profile", profile])
+                    .args(["-p", "gateway", "-p", "api", "-p", "rinha_db"])
+                    .args(["-p", "mock-processor"])
+                    .status()?;
+                if !status.success() {
+                    anyhow::bail!("building the services failed with {status}");
+                }
+                Ok(dir.to_path_buf())
+            };
+            build().map_err(|e| e.to_string())
+        })
+        .clone()
+        .map_err(anyhow::Error::msg)
+}
+
+fn free_port() -> anyhow::Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+fn path(path: &Path) -> String {
+    path.display().to_string()
+}
+
+async fn wait_for(socket: &Path) -> anyhow::Result<()> {
+    let started = Instant::now();
+    while !socket.exists() {
+        if started.elapsed() > STARTUP {
+            anyhow::bail!("nothing listening on {}", socket.display());
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    Ok(())
+}
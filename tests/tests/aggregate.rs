@@ -0,0 +1,41 @@
+use shared_types::{AggregateDelta, AggregatePush, GlobalSummary, PushToken, SledTree};
+use tests::Stack;
+use uuid::Uuid;
+
+/// A worker's push sent again, as after a lost reply, is merged once, while its next push
+/// and pushes without a token are merged every time.
+#[tokio::test(flavor = "multi_thread")]
+async fn pushes_are_merged_once_per_token() -> anyhow::Result<()> {
+    let stack = Stack::start(&[], &[]).await?;
+    let client = reqwest::Client::new();
+    let worker = Uuid::new_v4();
+    let pushes = [Some(1), Some(1), Some(2), Some(1), None, None];
+    for sequence in pushes {
+        let push = AggregatePush {
+            token: sequence.map(|sequence| PushToken { worker, sequence }),
+            deltas: vec![AggregateDelta {
+                tree: SledTree::DEFAULT,
+                bucket: "2025-07-15T12:00:00".to_string(),
+                requests: 1,
+                amount: 2.5,
+            }],
+        };
+        client
+            .post(format!("{}/aggregate", stack.db))
+            .json(&push)
+            .send()
+            .await?
+            .error_for_status()?;
+    }
+
+    let summary: GlobalSummary = client
+        .get(format!("{}/summary", stack.db))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    assert_eq!(summary.default.total_requests, 4);
+    assert_eq!(summary.default.total_amount, 10.0);
+    Ok(())
+}
@@ -0,0 +1,135 @@
+use std::time::{Duration, Instant};
+
+use reqwest::StatusCode;
+use serde_json::json;
+use shared_types::{GlobalSummary, Summary, payment_key};
+use tests::Stack;
+use tokio::task::JoinSet;
+use uuid::Uuid;
+
+const CLIENTS: usize = 64;
+
+/// Every payment the gateway acknowledges ends up on a processor, and the summary counts
+/// exactly what the processors accepted, with failed calls retried on either one.
+#[tokio::test(flavor = "multi_thread")]
+async fn summary_matches_what_processors_accepted() -> anyhow::Result<()> {
+    let stack = Stack::start(&[("FAIL_RATE", "0.15")], &[("FAIL_RATE", "0.1")]).await?;
+    let sent = pay(&stack, 3000).await?;
+    let accepted = settle(&stack, 3000).await?;
+    assert_eq!(
+        cents(accepted[0].total_amount + accepted[1].total_amount),
+        cents(sent)
+    );
+    assert_matches(&stack, &accepted).await
+}
+
+/// Payments sent while the default processor is down are taken by the fallback.
+#[tokio::test(flavor = "multi_thread")]
+async fn outage_moves_payments_to_fallback() -> anyhow::Result<()> {
+    let stack = Stack::start(&[], &[]).await?;
+    let outage = json!({ "phases": [
+        { "durationMs": 3000, "failing": true },
+        { "durationMs": 0 },
+    ] });
+    stack.default.play(&outage).await?;
+    pay(&stack, 1000).await?;
+    let accepted = settle(&stack, 1000).await?;
+    assert!(accepted[1].total_requests > 0, "fallback took nothing");
+    assert_matches(&stack, &accepted).await
+}
+
+/// A summary read right after writes acknowledged before they're applied still counts them.
+#[tokio::test(flavor = "multi_thread")]
+async fn summary_counts_unacknowledged_writes() -> anyhow::Result<()> {
+    let stack = Stack::start(&[], &[]).await?;
+    let client = reqwest::Client::new();
+    for _ in 0..500 {
+        let write = json!({
+            "key": payment_key("2025-07-15T12:00:00.000Z", &Uuid::new_v4()),
+            "value": 1.0,
+            "tree": "Default",
+            "ack": "none",
+        });
+        client
+            .post(format!("{}/payment", stack.db))
+            .json(&write)
+            .send()
+            .await?
+            .error_for_status()?;
+    }
+
+    let summary: GlobalSummary = client
+        .get(format!("{}/summary", stack.db))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    assert_eq!(summary.default.total_requests, 500);
+    assert_eq!(summary.default.total_amount, 500.0);
+    Ok(())
+}
+
+/// Send `payments` through the gateway from a few concurrent clients, returning their total.
+async fn pay(stack: &Stack, payments: usize) -> anyhow::Result<f64> {
+    let client = reqwest::Client::new();
+    let mut clients = JoinSet::new();
+    for c in 0..CLIENTS {
+        let client = client.clone();
+        let url = format!("{}/payments", stack.url);
+        clients.spawn(async move {
+            let mut amount = 0.0;
+            for i in (c..payments).step_by(CLIENTS) {
+                let payment = json!({
+                    "correlationId": Uuid::new_v4(),
+                    "amount": (i % 100 + 1) as f64 / 10.0,
+                });
+                let status = client.post(&url).json(&payment).send().await?.status();
+                anyhow::ensure!(status == StatusCode::OK, "payment {i} answered {status}");
+                amount += payment["amount"].as_f64().unwrap();
+            }
+            anyhow::Ok(amount)
+        });
+    }
+    let mut sent = 0.0;
+    while let Some(amount) = clients.join_next().await {
+        sent += amount??;
+    }
+    Ok(sent)
+}
+
+/// Wait for the processors to accept `payments` between them, since workers keep retrying in
+/// the background after the gateway acknowledged.
+async fn settle(stack: &Stack, payments: u64) -> anyhow::Result<[Summary; 2]> {
+    let started = Instant::now();
+    loop {
+        let accepted = [
+            stack.default.summary().await?,
+            stack.fallback.summary().await?,
+        ];
+        if accepted.iter().map(|s| s.total_requests).sum::<u64>() == payments {
+            return Ok(accepted);
+        }
+        anyhow::ensure!(
+            started.elapsed() < Duration::from_secs(60),
+            "processors only accepted {accepted:?}"
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+async fn assert_matches(stack: &Stack, accepted: &[Summary; 2]) -> anyhow::Result<()> {
+    let summary = stack.summary().await?;
+    for (summary, accepted) in [
+        (&summary.default, &accepted[0]),
+        (&summary.fallback, &accepted[1]),
+    ] {
+        assert_eq!(summary.total_requests, accepted.total_requests);
+        assert_eq!(cents(summary.total_amount), cents(accepted.total_amount));
+    }
+    Ok(())
+}
+
+fn cents(amount: f64) -> i64 {
+    (amount * 100.0).round() as i64
+}